@@ -0,0 +1,25 @@
+import Toast, { Root } from '~/components/Toast'
+import { useRoomContext } from '../hooks/useRoomContext'
+import { Icon } from './Icon/Icon'
+
+export function E2eeErrorToast() {
+	const { e2eeError } = useRoomContext()
+
+	if (!e2eeError) {
+		return null
+	}
+
+	return (
+		<Root duration={Infinity}>
+			<div className="space-y-2 text-sm">
+				<div className="font-bold">
+					<Toast.Title className="flex items-center gap-2">
+						<Icon type="ExclamationCircleIcon" />
+						End-to-end encryption failed
+					</Toast.Title>
+				</div>
+				<Toast.Description>{e2eeError}</Toast.Description>
+			</div>
+		</Root>
+	)
+}
@@ -1,8 +1,10 @@
 import { useFetcher, useParams } from '@remix-run/react'
 import type { FC } from 'react'
+import { useEffect, useState } from 'react'
 import useCopyToClipboard from '~/hooks/useCopyToClipboard'
 import { useRoomContext } from '~/hooks/useRoomContext'
 import type { BugReportInfo } from '~/routes/api.bugReport'
+import type { Diagnostic } from '~/types/E2eeProtocol'
 import { Button } from './Button'
 import {
 	Description,
@@ -34,15 +36,24 @@ export const ReportBugDialog: FC<ReportBugDialogProps> = ({ onOpenChange }) => {
 
 const ReportBugForm: FC<{}> = () => {
 	const { Form, data, state } = useFetcher()
-	const { room, roomHistory } = useRoomContext()
+	const { room, roomHistory, e2eeDumpDiagnostics } = useRoomContext()
 	const { roomName } = useParams()
 	const { identity } = room
 
+	// A snapshot of the E2EE worker's recent events, taken when the dialog opens
+	const [e2eeDiagnostics, setE2eeDiagnostics] = useState<Diagnostic[]>()
+	useEffect(() => {
+		e2eeDumpDiagnostics?.()
+			.then(setE2eeDiagnostics)
+			.catch((e) => console.warn('Failed to dump E2EE diagnostics', e))
+	}, [e2eeDumpDiagnostics])
+
 	const info: BugReportInfo = {
 		roomName,
 		identity,
 		roomHistory,
 		url: typeof location !== 'undefined' ? location.href : undefined,
+		e2eeDiagnostics,
 	}
 
 	const [copied, copy] = useCopyToClipboard()
@@ -75,7 +86,7 @@ const ReportBugForm: FC<{}> = () => {
 					>
 						{copied ? 'Copied!' : 'Copy'}
 					</Button>
-					<TextArea rows={10} readOnly defaultValue={infoString}></TextArea>
+					<TextArea rows={10} readOnly value={infoString}></TextArea>
 				</div>
 			</details>
 
@@ -2,6 +2,7 @@ import { useOutletContext } from '@remix-run/react'
 import type { PartyTracks } from 'partytracks/client'
 import type { Dispatch, SetStateAction } from 'react'
 import type { UserMedia } from '~/hooks/useUserMedia'
+import type { Diagnostic } from '~/types/E2eeProtocol'
 import type useRoom from './useRoom'
 import type { useRoomHistory } from './useRoomHistory'
 
@@ -26,7 +27,10 @@ export type RoomContextType = {
 	roomHistory: ReturnType<typeof useRoomHistory>
 	simulcastEnabled: boolean
 	e2eeSafetyNumber?: string
+	// The last error the E2EE worker reported
+	e2eeError?: string
 	e2eeOnJoin: (firstUser: boolean) => void
+	e2eeDumpDiagnostics?: () => Promise<Diagnostic[]>
 	pushedTracks: {
 		video?: string
 		audio?: string
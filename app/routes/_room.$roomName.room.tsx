@@ -12,6 +12,7 @@ import { AiButton } from '~/components/AiButton'
 import { ButtonLink } from '~/components/Button'
 import { CameraButton } from '~/components/CameraButton'
 import { CopyButton } from '~/components/CopyButton'
+import { E2eeErrorToast } from '~/components/E2eeErrorToast'
 import { HighPacketLossWarningsToast } from '~/components/HighPacketLossWarningsToast'
 import { IceDisconnectedToast } from '~/components/IceDisconnectedToast'
 import { LeaveRoomButton } from '~/components/LeaveRoomButton'
@@ -238,6 +239,7 @@ function JoinedRoom({ bugReportsEnabled }: { bugReportsEnabled: boolean }) {
 			</div>
 			<HighPacketLossWarningsToast />
 			<IceDisconnectedToast />
+			<E2eeErrorToast />
 		</PullAudioTracks>
 	)
 }
@@ -222,7 +222,7 @@ function Room({ room, userMedia }: RoomProps) {
 	const [pinnedTileIds, setPinnedTileIds] = useState<string[]>([])
 	const [showDebugInfo, setShowDebugInfo] = useState(mode !== 'production')
 
-	const { e2eeSafetyNumber, onJoin } = useE2EE({
+	const { e2eeSafetyNumber, e2eeError, e2eeDumpDiagnostics, onJoin } = useE2EE({
 		enabled: e2eeEnabled,
 		room,
 		partyTracks,
@@ -246,7 +246,9 @@ function Room({ room, userMedia }: RoomProps) {
 		partyTracks,
 		roomHistory,
 		e2eeSafetyNumber,
+		e2eeError,
 		e2eeOnJoin: onJoin,
+		e2eeDumpDiagnostics,
 		iceConnectionState,
 		room,
 		simulcastEnabled,
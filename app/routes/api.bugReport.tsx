@@ -2,6 +2,7 @@ import type { ActionFunctionArgs } from '@remix-run/cloudflare'
 import { json } from '@remix-run/cloudflare'
 import invariant from 'tiny-invariant'
 import type { RoomHistory } from '~/hooks/useRoomHistory'
+import type { Diagnostic } from '~/types/E2eeProtocol'
 import type { ChatCard } from '~/types/GoogleChatApi'
 import type { User } from '~/types/Messages'
 import { RELEASE } from '~/utils/constants'
@@ -11,6 +12,8 @@ export type BugReportInfo = {
 	identity?: User
 	roomHistory?: RoomHistory
 	url?: string
+	// The E2EE worker's recent group events, if E2EE is on
+	e2eeDiagnostics?: Diagnostic[]
 }
 
 export const action = async ({ request, context }: ActionFunctionArgs) => {
@@ -24,7 +27,7 @@ export const action = async ({ request, context }: ActionFunctionArgs) => {
 	const requestUrl = new URL(request.url)
 	const formData = await request.formData()
 	const info: BugReportInfo = JSON.parse(String(formData.get('info')))
-	const { identity, roomName, roomHistory, url } = info
+	const { identity, roomName, roomHistory, url, e2eeDiagnostics } = info
 	const description = formData.get('description')
 	invariant(typeof description === 'string')
 	const userAgent = request.headers.get('User-Agent')
@@ -36,7 +39,7 @@ export const action = async ({ request, context }: ActionFunctionArgs) => {
 	if (context.env.FEEDBACK_STORAGE) {
 		await context.env.FEEDBACK_STORAGE.put(
 			debugInfoId,
-			JSON.stringify(roomHistory, null, 2)
+			JSON.stringify({ roomHistory, e2eeDiagnostics }, null, 2)
 		)
 	}
 
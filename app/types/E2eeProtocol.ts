@@ -0,0 +1,229 @@
+// This file was generated by [ts-rs](https://github.com/Aleph-Alpha/ts-rs). Do not edit this file manually.
+
+/**
+ * What to leave in the clear in an audio frame. Everyone in a room must use the same policy
+ */
+export type AudioPolicy = "keepToc" | "encryptAll";
+
+/**
+ * The ciphersuites the worker can run a group in, by their names in RFC 9420
+ */
+export type CiphersuiteName = "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519" | "MLS_128_DHKEMP256_AES128GCM_SHA256_P256" | "MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519" | "MLS_256_XWING_CHACHA20POLY1305_SHA256_Ed25519";
+
+/**
+ * How many frames had each outcome of decryption since the worker was initialized
+ */
+export type DecryptCounts = { decrypted: number, dropped: number, passedThrough: number, concealed: number, 
+/**
+ * Markers from senders who weren't in the group yet
+ */
+notKeyed: number, };
+
+/**
+ * What to do with a frame that can't be decrypted
+ */
+export type DecryptFailurePolicy = "drop" | "passthrough" | "conceal";
+
+/**
+ * A diagnostic event, along with when it happened
+ */
+export type Diagnostic = { 
+/**
+ * Milliseconds since the session was created
+ */
+elapsedMs: number, } & ({ "kind": "epochChanged", epoch: number, members: number, } | { "kind": "committed", added: number, removed: number, } | { "kind": "deferred", pendingAdds: number, pendingRemoves: number, } | { "kind": "joinerDropped" } | { "kind": "staleCommit" } | { "kind": "error", code: ErrorCode, });
+
+/**
+ * A group event worth knowing about when a call goes wrong
+ */
+export type DiagnosticEvent = { "kind": "epochChanged", epoch: number, members: number, } | { "kind": "committed", added: number, removed: number, } | { "kind": "deferred", pendingAdds: number, pendingRemoves: number, } | { "kind": "joinerDropped" } | { "kind": "staleCommit" } | { "kind": "error", code: ErrorCode, };
+
+/**
+ * The kind of error a worker error is
+ */
+export type ErrorCode = "decrypt" | "unknownEvent" | "malformedEvent" | "unsupportedProtocolVersion" | "sessionExists" | "unknownSession" | "invalidGroupConfig" | "groupConfigMismatch" | "malformedMessage" | "invalidKeyPackage" | "incompatibleKeyPackage" | "wrongMessageType" | "mls" | "removeSelf" | "notInitialized" | "stateUnavailable" | "stream";
+
+/**
+ * The parameters of the MLS group. Everything is optional, and defaults to what the worker's group
+ * config defaults to. The creator of the group pins them in the group, and a worker that joins a
+ * group with different parameters takes on the group's. It refuses to join a group whose
+ * ciphersuite it doesn't accept
+ */
+export type GroupOptions = { 
+/**
+ * The ciphersuites this user accepts, in order of preference. A group this user creates is in
+ * the first. By default, every ciphersuite but the post-quantum one is accepted
+ */
+ciphersuites?: Array<CiphersuiteName>, 
+/**
+ * How many frames older than the newest one from the same sender still decrypt. In the SFrame
+ * format, each sender's replay window covers this many frames
+ */
+outOfOrderTolerance?: number, 
+/**
+ * How many frames ahead of the newest one from the same sender still decrypt
+ */
+maxMessageSeqJump?: number, 
+/**
+ * How many past epochs the worker keeps keys for, so that frames in flight during a membership
+ * change still decrypt
+ */
+maxPastEpochs?: number, padding?: PaddingPolicy, };
+
+/**
+ * An event posted by the main thread
+ */
+export type InboundEvent = { "type": "encryptStream" } & StreamEvent | { "type": "decryptStream" } & StreamEvent | { "type": "initialize" } & InitializeEvent | { "type": "initializeAndCreateGroup" } & InitializeEvent | { "type": "destroySession" } & SessionEvent | { "type": "userJoined" } & UserJoinedEvent | { "type": "userLeft" } & UserLeftEvent | { "type": "recvMlsWelcome" } & RecvMlsWelcomeEvent | { "type": "recvMlsMessage" } & RecvMlsMessageEvent | { "type": "getDecryptStats" } & SessionEvent | { "type": "dumpDiagnostics" } & SessionEvent;
+
+/**
+ * This user's ID, the protocol version, and how media frames are encrypted. Everything but the ID
+ * and the protocol version is optional, and defaults to what the worker's media config defaults to
+ */
+export type InitializeEvent = { 
+/**
+ * The session to create. There must be no session with this ID yet
+ */
+sessionId: string, id: string, 
+/**
+ * The protocol version the main thread speaks, which must be one of the ones the worker
+ * announced in `workerReady`
+ */
+protocolVersion: number, audioPolicy?: AudioPolicy, 
+/**
+ * How the encrypted parts of frames are protected. Defaults to `sframe`, whose frames never
+ * wait on handshake processing. Frames in the `mls` format wait on it every time
+ */
+mediaFormat?: MediaFormat, 
+/**
+ * The parameters of the MLS group, which must be the same for everyone in the room
+ */
+group?: GroupOptions, 
+/**
+ * How long the worker keeps sending in the previous epoch after a membership change. Only used
+ * with the `sframe` format, and only if past epochs are kept
+ */
+senderDelayMs?: number, decryptFailurePolicy?: DecryptFailurePolicy, preGroupPolicy?: PreGroupPolicy, 
+/**
+ * How much the worker logs to the console. Unlike the rest of this event, this isn't specific
+ * to the session: logging is worker-wide, and the last session initialized with this set
+ * decides it
+ */
+logging?: LogOptions, };
+
+/**
+ * The least severe kind of log message that's logged. `off` logs nothing
+ */
+export type LogLevel = "off" | "error" | "warn" | "info" | "debug" | "trace";
+
+/**
+ * How much the worker logs to the console. Everything is optional. By default, everything at the
+ * `info` level and above is logged
+ */
+export type LogOptions = { 
+/**
+ * The level of the modules that aren't in `modules`
+ */
+level?: LogLevel, 
+/**
+ * The levels of individual modules, and of the modules in them, by path, e.g.,
+ * `orange_mls_core::mls_ops`. A module's level is that of the longest path it's in
+ */
+modules?: { [key in string]: LogLevel }, };
+
+/**
+ * How the encrypted parts of media frames are protected. Everyone in a room must use the same
+ * format
+ */
+export type MediaFormat = "mls" | "sframe";
+
+/**
+ * An event posted to the main thread. Byte strings are posted as `ArrayBuffer`s of their own,
+ * which are transferred rather than copied. Every event but `workerReady` says which session it's
+ * from
+ */
+export type OutboundEvent = { "type": "workerReady", protocolVersion: number, minProtocolVersion: number, } | { "type": "shareKeyPackage", sessionId: string, keyPkgs: ArrayBuffer[], } | { "type": "newSafetyNumber", sessionId: string, hash: ArrayBuffer, } | { "type": "sendMlsWelcome", sessionId: string, senderId: string, welcome: ArrayBuffer, rtree: ArrayBuffer, } | { "type": "sendMlsMessage", sessionId: string, senderId: string, msg: ArrayBuffer, } | { "type": "decryptStats", sessionId: string, decrypted: number, dropped: number, passedThrough: number, concealed: number, 
+/**
+ * Markers from senders who weren't in the group yet
+ */
+notKeyed: number, } | { "type": "diagnostics", sessionId: string, events: Array<Diagnostic>, } | { "type": "keyFrameRequest", sessionId: string, operation: StreamOperation, trackId: string, } | { "type": "encryptionStarted", sessionId: string, trackId: string, } | { "type": "error", code: ErrorCode, message: string, 
+/**
+ * The session the event was addressed to, if it said
+ */
+sessionId?: string, 
+/**
+ * The `type` of the event, if it had one
+ */
+eventType?: string, };
+
+/**
+ * How to pad plaintexts. In events from the main thread, this is `"none"`, `"padme"`, or a bucket
+ * size of at most 64 KiB
+ */
+export type Padding = 'none' | 'padme' | number;
+
+/**
+ * How to pad audio and video frames. In events from the main thread, this is either one padding
+ * for both kinds of frames, or an object with an optional padding per kind. Everyone in a room must
+ * use the same policy
+ */
+export type PaddingPolicy = Padding | { audio?: Padding; video?: Padding };
+
+/**
+ * What to send in place of frames before this user is in a group, when there are no keys to
+ * encrypt them with
+ */
+export type PreGroupPolicy = "hold" | "empty" | "marked";
+
+/**
+ * An MLS message relayed from a peer
+ */
+export type RecvMlsMessageEvent = { sessionId: string, senderId: string, msg: ArrayBuffer | Uint8Array, };
+
+/**
+ * A Welcome and ratchet tree relayed from the peer who added this user
+ */
+export type RecvMlsWelcomeEvent = { sessionId: string, 
+/**
+ * The peer who sent the Welcome. The worker doesn't use this
+ */
+senderId: string, welcome: ArrayBuffer | Uint8Array, rtree: ArrayBuffer | Uint8Array, };
+
+/**
+ * An event with nothing but the session it's about
+ */
+export type SessionEvent = { sessionId: string, };
+
+/**
+ * The streams of frames to encrypt or decrypt
+ */
+export type StreamEvent = { 
+/**
+ * The session whose keys protect the frames. Until the session is created, and after it's
+ * destroyed, no frames are passed on
+ */
+sessionId: string, in: ReadableStream, out: WritableStream, 
+/**
+ * The MIME type of the video codec, e.g., `video/H264`. This is only used for frames whose
+ * metadata doesn't say what codec they're in. If it's not given, VP8 is assumed
+ */
+codec?: string, 
+/**
+ * Identifies the track, e.g., by its track name. Frames are bound to the track they're
+ * encrypted for, so both sides of a track must use the same ID
+ */
+trackId?: string, };
+
+/**
+ * Which kind of stream an event is about
+ */
+export type StreamOperation = "encryptStream" | "decryptStream";
+
+/**
+ * The key packages shared by a user who wants to join, one per ciphersuite it accepts
+ */
+export type UserJoinedEvent = { sessionId: string, keyPkgs: Array<ArrayBuffer | Uint8Array>, };
+
+/**
+ * The ID of a user who left
+ */
+export type UserLeftEvent = { sessionId: string, id: string, };
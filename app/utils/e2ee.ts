@@ -2,53 +2,59 @@ import type { PartyTracks } from 'partytracks/client'
 import { useCallback, useEffect, useMemo, useState } from 'react'
 import invariant from 'tiny-invariant'
 import type useRoom from '~/hooks/useRoom'
+import type {
+	Diagnostic,
+	InboundEvent,
+	InitializeEvent,
+	OutboundEvent,
+	RecvMlsMessageEvent,
+	RecvMlsWelcomeEvent,
+	UserJoinedEvent,
+} from '~/types/E2eeProtocol'
 import type { ServerMessage } from '~/types/Messages'
 
-type MessagesToE2eeWorker =
-	| {
-			type: 'userJoined'
-			id: string
-	  }
-	| {
-			type: 'userLeft'
-			id: string
-	  }
-	| { type: 'recvMlsMessage'; msg: Uint8Array }
-	| { type: 'encryptStream'; in: ReadableStream; out: WritableStream }
-	| { type: 'decryptStream'; in: ReadableStream; out: WritableStream }
-	| { type: 'initializeAndCreateGroup'; id: string }
-
-type MessagesFromE2eeWorker =
-	| {
-			type: 'workerReady'
-	  }
-	| {
-			type: 'sendMlsMessage'
-			msg: Uint8Array
-	  }
-	| {
-			type: 'newSafetyNumber'
-			msg: Uint8Array
-	  }
+// The version of the worker protocol this code speaks. The worker says which versions it speaks in
+// its 'workerReady' event
+const E2EE_PROTOCOL_VERSION = 1
+
+// The session an EncryptionWorker uses if it isn't given one. A worker can be in several sessions,
+// e.g., one per room, and every event to or from it says which session it's about
+const DEFAULT_SESSION_ID = 'main'
+
+// Resolves once the worker is loaded, or rejects if it doesn't speak E2EE_PROTOCOL_VERSION
+function workerReady(worker: Worker) {
+	return new Promise<void>((resolve, reject) => {
+		const handler = (event: MessageEvent<OutboundEvent>) => {
+			if (event.data.type !== 'workerReady') return
+			worker.removeEventListener('message', handler)
+			const { protocolVersion, minProtocolVersion } = event.data
+			if (
+				E2EE_PROTOCOL_VERSION < minProtocolVersion ||
+				E2EE_PROTOCOL_VERSION > protocolVersion
+			) {
+				reject(
+					new Error(
+						`E2EE worker speaks protocol versions ${minProtocolVersion} to ${protocolVersion}, not ${E2EE_PROTOCOL_VERSION}`
+					)
+				)
+			} else {
+				resolve()
+			}
+		}
+		worker.addEventListener('message', handler)
+	})
+}
 
 export async function loadWorker(
-	handleEvents: (message: MessagesFromE2eeWorker) => void
+	handleEvents: (message: OutboundEvent) => void
 ) {
 	// Create a new worker
 	const worker = new Worker('/e2ee/worker.js')
 
-	const ready = new Promise<void>((res) => {
-		const handler = (event: MessageEvent) => {
-			if (event.data.type === 'workerReady') {
-				res()
-				worker.removeEventListener('message', handler)
-			}
-		}
-		worker.addEventListener('message', handler)
-	})
+	const ready = workerReady(worker)
 
 	// Listen for messages from the worker
-	worker.onmessage = function (event: MessageEvent<MessagesFromE2eeWorker>) {
+	worker.onmessage = function (event: MessageEvent<OutboundEvent>) {
 		console.log('Received message from worker:', event.data)
 		handleEvents(event.data)
 	}
@@ -60,13 +66,13 @@ export async function loadWorker(
 
 	await ready
 
-	async function safePostMessage(message: MessagesToE2eeWorker): Promise<void>
+	async function safePostMessage(message: InboundEvent): Promise<void>
 	async function safePostMessage(
-		message: MessagesToE2eeWorker,
+		message: InboundEvent,
 		transfer: Transferable[]
 	): Promise<void>
 	async function safePostMessage(
-		message: MessagesToE2eeWorker,
+		message: InboundEvent,
 		transfer?: Transferable[]
 	): Promise<void> {
 		if (transfer) {
@@ -81,16 +87,35 @@ export async function loadWorker(
 	})
 }
 
-type MessagesFromWorker =
-	| { type: 'shareKeyPackage'; keyPkg: Uint8Array }
-	| { type: 'sendMlsMessage'; msg: Uint8Array; senderId: string }
-	| {
-			type: 'sendMlsWelcome'
-			senderId: string
-			welcome: Uint8Array
-			rtree: Uint8Array
-	  }
-	| { type: 'newSafetyNumber'; hash: Uint8Array }
+// The events the worker posts that are relayed to other users' workers
+type MessagesFromWorker = Extract<
+	OutboundEvent,
+	{ type: 'shareKeyPackage' | 'sendMlsWelcome' | 'sendMlsMessage' }
+>
+
+// See `OutboundEvent` for what these mean
+type KeyFrameRequest = Extract<OutboundEvent, { type: 'keyFrameRequest' }>
+type EncryptionStarted = Extract<OutboundEvent, { type: 'encryptionStarted' }>
+type WorkerError = Extract<OutboundEvent, { type: 'error' }>
+type DecryptStats = Extract<OutboundEvent, { type: 'decryptStats' }>
+
+// How the worker encrypts media, i.e., everything the 'initialize' events carry but the session and
+// the protocol version. See `InitializeEvent` for what each option means
+type EncryptionConfig = Omit<InitializeEvent, 'sessionId' | 'protocolVersion'>
+
+// How a sender or receiver transform is set up. See `EncryptionWorker.setupSenderTransform`
+type StreamOptions = { codec?: string; trackId?: string }
+
+// The video codecs the worker knows how to encrypt. The worker reads each frame's codec from its
+// metadata, so any of these can be negotiated
+const E2EE_VIDEO_CODECS = [
+	'video/VP8',
+	'video/VP9',
+	'video/H264',
+	'video/H265',
+	'video/AV1',
+	'video/rtx',
+]
 
 export class EncryptionWorker {
 	get worker(): Worker {
@@ -103,59 +128,102 @@ export class EncryptionWorker {
 
 	_worker: Worker | null = null
 	safetyNumber: number = -1
-	id: string
-
-	constructor(config: { id: string }) {
-		this.id = config.id
+	sessionId: string
+	config: EncryptionConfig
+	// Resolves once the worker is loaded and speaks our protocol version. The worker is only
+	// initialized after that
+	ready: Promise<void>
+
+	constructor({
+		sessionId = DEFAULT_SESSION_ID,
+		...config
+	}: EncryptionConfig & { sessionId?: string }) {
+		this.sessionId = sessionId
+		this.config = config
 		this._worker = new Worker('/e2ee/worker.js')
+		this.ready = workerReady(this._worker)
 	}
 
 	dispose() {
 		this.worker.terminate()
 	}
 
-	initialize() {
-		this.worker.postMessage({ type: 'initialize', id: this.id })
+	postEvent(event: InboundEvent, transfer: Transferable[] = []) {
+		this.worker.postMessage(event, transfer)
 	}
 
-	initializeAndCreateGroup() {
-		this.worker.postMessage({ type: 'initializeAndCreateGroup', id: this.id })
+	async initialize() {
+		await this.ready
+		this.postEvent({
+			type: 'initialize',
+			sessionId: this.sessionId,
+			protocolVersion: E2EE_PROTOCOL_VERSION,
+			...this.config,
+		})
+	}
+
+	async initializeAndCreateGroup() {
+		await this.ready
+		this.postEvent({
+			type: 'initializeAndCreateGroup',
+			sessionId: this.sessionId,
+			protocolVersion: E2EE_PROTOCOL_VERSION,
+			...this.config,
+		})
 	}
 
-	userJoined(keyPkg: Uint8Array) {
-		this.worker.postMessage({ type: 'userJoined', keyPkg })
+	userJoined(keyPkgs: UserJoinedEvent['keyPkgs']) {
+		this.postEvent({ type: 'userJoined', sessionId: this.sessionId, keyPkgs })
 	}
 
 	userLeft(id: string) {
-		this.worker.postMessage({ type: 'userLeft', id })
+		this.postEvent({ type: 'userLeft', sessionId: this.sessionId, id })
 	}
 
-	receiveMlsWelcome(senderId: string, welcome: Uint8Array, rtree: Uint8Array) {
-		this.worker.postMessage({
+	receiveMlsWelcome(
+		senderId: string,
+		welcome: RecvMlsWelcomeEvent['welcome'],
+		rtree: RecvMlsWelcomeEvent['rtree']
+	) {
+		this.postEvent({
 			type: 'recvMlsWelcome',
+			sessionId: this.sessionId,
 			welcome,
 			rtree,
 			senderId,
 		})
 	}
 
-	receiveMlsMessage(msg: Uint8Array, senderId: string) {
-		const message = {
+	receiveMlsMessage(msg: RecvMlsMessageEvent['msg'], senderId: string) {
+		const message: InboundEvent = {
+			sessionId: this.sessionId,
 			msg,
 			senderId,
 			type: 'recvMlsMessage',
 		}
 		console.log('passing receiveMlsMessage into worker', message)
-		this.worker.postMessage(message)
+		this.postEvent(message)
 	}
 
-	async setupSenderTransform(sender: RTCRtpSender) {
+	/**
+	 * `codec` is the MIME type of the codec the sender will use, e.g., `video/H264`. The worker
+	 * assumes VP8 if it's not given. `trackId` identifies the track, e.g., by its track name. Every
+	 * frame is bound to it, and receivers reject frames that weren't sent with the `trackId` their
+	 * own transform was set up with.
+	 */
+	async setupSenderTransform(
+		sender: RTCRtpSender,
+		{ codec, trackId }: StreamOptions = {}
+	) {
 		console.log('Setting up sender transform')
 
 		// If this is Firefox, we will have to use RTCRtpScriptTransform
 		if (window.RTCRtpScriptTransform) {
 			sender.transform = new RTCRtpScriptTransform(this.worker, {
 				operation: 'encryptStream',
+				sessionId: this.sessionId,
+				codec,
+				trackId,
 			})
 			return
 		}
@@ -167,11 +235,14 @@ export class EncryptionWorker {
 		) {
 			const senderStreams = sender.createEncodedStreams()
 			const { readable, writable } = senderStreams
-			this.worker.postMessage(
+			this.postEvent(
 				{
 					type: 'encryptStream',
+					sessionId: this.sessionId,
 					in: readable,
 					out: writable,
+					codec,
+					trackId,
 				},
 				[readable, writable]
 			)
@@ -184,13 +255,20 @@ export class EncryptionWorker {
 		)
 	}
 
-	async setupReceiverTransform(receiver: RTCRtpReceiver) {
+	/** See {@link setupSenderTransform} for the meaning of `codec` and `trackId` */
+	async setupReceiverTransform(
+		receiver: RTCRtpReceiver,
+		{ codec, trackId }: StreamOptions = {}
+	) {
 		console.log('Setting up receiver transform')
 
 		// If this is Firefox, we will have to use RTCRtpScriptTransform
 		if (window.RTCRtpScriptTransform) {
 			receiver.transform = new RTCRtpScriptTransform(this.worker, {
 				operation: 'decryptStream',
+				sessionId: this.sessionId,
+				codec,
+				trackId,
 			})
 
 			return
@@ -203,11 +281,14 @@ export class EncryptionWorker {
 		) {
 			const senderStreams = receiver.createEncodedStreams()
 			const { readable, writable } = senderStreams
-			this.worker.postMessage(
+			this.postEvent(
 				{
 					type: 'decryptStream',
+					sessionId: this.sessionId,
 					in: readable,
 					out: writable,
+					codec,
+					trackId,
 				},
 				[readable, writable]
 			)
@@ -221,8 +302,9 @@ export class EncryptionWorker {
 	}
 
 	decryptStream(inStream: ReadableStream, outStream: WritableStream) {
-		this.worker.postMessage({
+		this.postEvent({
 			type: 'decryptStream',
+			sessionId: this.sessionId,
 			in: inStream,
 			out: outStream,
 		})
@@ -230,8 +312,19 @@ export class EncryptionWorker {
 
 	handleOutgoingEvents(onMessage: (data: string) => void) {
 		this.worker.addEventListener('message', (event) => {
-			const excludedEvents = ['workerReady', 'newSafetyNumber']
-			if (!excludedEvents.includes(event.data.type)) {
+			const excludedEvents = [
+				'workerReady',
+				'newSafetyNumber',
+				'keyFrameRequest',
+				'decryptStats',
+				'diagnostics',
+				'encryptionStarted',
+				'error',
+			]
+			if (
+				event.data.sessionId === this.sessionId &&
+				!excludedEvents.includes(event.data.type)
+			) {
 				console.log('Message from worker in handleOutgoingEvents', event.data)
 				onMessage(JSON.stringify(event.data, replacer))
 			}
@@ -244,7 +337,7 @@ export class EncryptionWorker {
 		console.log('Incoming event: ', message.type, { message })
 		switch (message.type) {
 			case 'shareKeyPackage': {
-				this.userJoined(message.keyPkg)
+				this.userJoined(message.keyPkgs)
 				break
 			}
 			case 'sendMlsWelcome': {
@@ -258,13 +351,97 @@ export class EncryptionWorker {
 		}
 	}
 
-	onNewSafetyNumber(handler: (safetyNumber: Uint8Array) => void) {
+	onNewSafetyNumber(handler: (safetyNumber: ArrayBuffer) => void) {
 		this.worker.addEventListener('message', (event) => {
-			if (event.data.type === 'newSafetyNumber') {
+			if (
+				event.data.type === 'newSafetyNumber' &&
+				event.data.sessionId === this.sessionId
+			) {
 				handler(event.data.hash)
 			}
 		})
 	}
+
+	/** The stats are delivered to the handlers registered with {@link onDecryptStats} */
+	requestDecryptStats() {
+		this.postEvent({ type: 'getDecryptStats', sessionId: this.sessionId })
+	}
+
+	onDecryptStats(handler: (stats: DecryptStats) => void) {
+		this.worker.addEventListener('message', (event) => {
+			if (
+				event.data.type === 'decryptStats' &&
+				event.data.sessionId === this.sessionId
+			) {
+				handler(event.data)
+			}
+		})
+	}
+
+	/**
+	 * Resolves with the session's recent group events, oldest first. They're redacted, so they can
+	 * be attached to bug reports as they are. Rejects if the session doesn't exist, e.g., before
+	 * joining
+	 */
+	dumpDiagnostics() {
+		return new Promise<Diagnostic[]>((resolve, reject) => {
+			const handler = (event: MessageEvent<OutboundEvent>) => {
+				const { data } = event
+				if (data.type === 'diagnostics' && data.sessionId === this.sessionId) {
+					resolve(data.events)
+				} else if (
+					data.type === 'error' &&
+					data.eventType === 'dumpDiagnostics' &&
+					data.sessionId === this.sessionId
+				) {
+					reject(new Error(data.message))
+				} else {
+					return
+				}
+				this.worker.removeEventListener('message', handler)
+			}
+			this.worker.addEventListener('message', handler)
+			this.postEvent({ type: 'dumpDiagnostics', sessionId: this.sessionId })
+		})
+	}
+
+	onError(handler: (error: WorkerError) => void) {
+		this.worker.addEventListener('message', (event) => {
+			// Errors for events that didn't say which session they're about go to every session
+			if (
+				event.data.type === 'error' &&
+				(event.data.sessionId ?? this.sessionId) === this.sessionId
+			) {
+				handler(event.data)
+			}
+		})
+	}
+
+	onEncryptionStarted(handler: (event: EncryptionStarted) => void) {
+		this.worker.addEventListener('message', (event) => {
+			if (
+				event.data.type === 'encryptionStarted' &&
+				event.data.sessionId === this.sessionId
+			) {
+				handler(event.data)
+			}
+		})
+	}
+
+	/**
+	 * Only called on the createEncodedStreams path. With RTCRtpScriptTransform, the worker makes
+	 * and asks for keyframes itself
+	 */
+	onKeyFrameRequest(handler: (request: KeyFrameRequest) => void) {
+		this.worker.addEventListener('message', (event) => {
+			if (
+				event.data.type === 'keyFrameRequest' &&
+				event.data.sessionId === this.sessionId
+			) {
+				handler(event.data)
+			}
+		})
+	}
 }
 
 const FLAG_TYPED_ARRAY = 'FLAG_TYPED_ARRAY'
@@ -303,6 +480,7 @@ export function useE2EE({
 	room: ReturnType<typeof useRoom>
 }) {
 	const [safetyNumber, setSafetyNumber] = useState<string>()
+	const [error, setError] = useState<string>()
 
 	const encryptionWorker = useMemo(
 		() =>
@@ -326,10 +504,10 @@ export function useE2EE({
 				if (transceiver.sender.track?.kind === 'video') {
 					const capability = RTCRtpSender.getCapabilities('video')
 					const codecs = capability ? capability.codecs : []
-					const vp8codec = codecs.filter(
-						(a) => a.mimeType === 'video/VP8' || a.mimeType === 'video/rtx'
+					const supportedCodecs = codecs.filter((a) =>
+						E2EE_VIDEO_CODECS.includes(a.mimeType)
 					)
-					transceiver.setCodecPreferences(vp8codec)
+					transceiver.setCodecPreferences(supportedCodecs)
 				}
 				encryptionWorker.setupSenderTransform(transceiver.sender)
 			}
@@ -370,6 +548,11 @@ export function useE2EE({
 		encryptionWorker.onNewSafetyNumber((buffer) =>
 			setSafetyNumber(arrayBufferToDecimal(buffer))
 		)
+		encryptionWorker.onError((error) => {
+			console.error('E2EE worker error:', error)
+			// A failed diagnostics dump is reported to whoever asked for it
+			if (error.eventType !== 'dumpDiagnostics') setError(error.message)
+		})
 		encryptionWorker.handleOutgoingEvents((data) => {
 			console.log('📬 sending e2eeMlsMessage to peers', data)
 			room.websocket.send(
@@ -403,8 +586,15 @@ export function useE2EE({
 		}
 	}, [encryptionWorker, firstUser, joined, room.websocket])
 
+	const dumpDiagnostics = useCallback(
+		() => encryptionWorker.dumpDiagnostics(),
+		[encryptionWorker]
+	)
+
 	return {
 		e2eeSafetyNumber: enabled ? safetyNumber : undefined,
+		e2eeError: enabled ? error : undefined,
+		e2eeDumpDiagnostics: enabled && joined ? dumpDiagnostics : undefined,
 		onJoin,
 	}
 }
@@ -4,12 +4,14 @@
 importScripts('/e2ee/wasm-pkg/orange_mls_worker.js')
 
 // Use the `processEvent` top-level function defined in Rust
-const { initLogging, processEvent } = wasm_bindgen
+const { initLogging, processEvent, readyEvent } = wasm_bindgen
 
-// Load the Wasm file by awaiting the Promise returned by `wasm_bindgen`.
+// Load the Wasm file by awaiting the Promise returned by `wasm_bindgen`. Once it's loaded, tell the
+// main thread which protocol versions the worker speaks
 async function initWasmInWorker() {
 	await wasm_bindgen('/e2ee/wasm-pkg/orange_mls_worker_bg.wasm')
 	initLogging()
+	postMessage(readyEvent())
 }
 const wasmIsReady = initWasmInWorker()
 
@@ -39,10 +41,13 @@ self.onrtctransform = async (event /* RTCTransformEvent */) => {
 			type: transformer.options.operation,
 			in: transformer.readable,
 			out: transformer.writable,
+			codec: transformer.options.codec,
+			trackId: transformer.options.trackId,
+			sessionId: transformer.options.sessionId,
+			// Lets the worker make and ask for keyframes
+			transformer,
 		},
 	}
 	// Pass it to handler we defined above
 	await self.onmessage(repackagedEvent)
 }
-
-postMessage({ type: 'workerReady' })
@@ -2,11 +2,12 @@
 
 set -e
 
-# cd to the directory this script is in
-cd "$(dirname "$0")"
+# cd to the WASM crate in the directory this script is in
+cd "$(dirname "$0")/wasm"
 
-OUTDIR="../public/e2ee/wasm-pkg"
+OUTDIR="../../public/e2ee/wasm-pkg"
 
 # This example requires to *not* create ES modules, therefore we pass the flag
-# `--target no-modules`
-wasm-pack build --target no-modules --out-dir "$OUTDIR"
+# `--target no-modules`. The worker script loads the files by the name they had before the crate
+# was split up, so keep it
+wasm-pack build --target no-modules --out-dir "$OUTDIR" --out-name orange_mls_worker
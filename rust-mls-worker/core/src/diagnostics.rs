@@ -0,0 +1,127 @@
+//! A ring buffer of a session's recent group events, for attaching to bug reports. Events are
+//! redacted: they say what happened to the group, and how many users it happened to, but never who
+//! the users are, and never anything derived from key material, e.g., safety numbers. Errors are
+//! kept by code, since their messages can name users.
+
+use std::{collections::VecDeque, sync::Mutex};
+
+use serde::Serialize;
+use ts_rs::TS;
+use web_time::Instant;
+
+use crate::error::ErrorCode;
+
+/// A group event worth knowing about when a call goes wrong
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, TS)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum DiagnosticEvent {
+    /// The group moved to a new epoch, by being created, joined, or committed to
+    #[serde(rename_all = "camelCase")]
+    EpochChanged {
+        #[ts(type = "number")]
+        epoch: u64,
+        members: usize,
+    },
+    /// This user, as the designated committer, committed the given numbers of adds and removes
+    #[serde(rename_all = "camelCase")]
+    Committed { added: usize, removed: usize },
+    /// This user isn't the designated committer, so it left the given numbers of users pending for
+    /// whoever is
+    #[serde(rename_all = "camelCase")]
+    Deferred {
+        pending_adds: usize,
+        pending_removes: usize,
+    },
+    /// A user was never added, because its add couldn't be committed, or because it joined the room
+    /// before this user was in the group and had no key package fitting the group
+    JoinerDropped,
+    /// A Commit from a past epoch was ignored
+    StaleCommit,
+    /// Handling an event failed
+    #[serde(rename_all = "camelCase")]
+    Error { code: ErrorCode },
+}
+
+/// A diagnostic event, along with when it happened
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub struct Diagnostic {
+    /// Milliseconds since the session was created
+    #[ts(type = "number")]
+    pub elapsed_ms: u64,
+    #[serde(flatten)]
+    pub event: DiagnosticEvent,
+}
+
+/// The most recent diagnostic events of a session. Once it's full, recording an event drops the
+/// oldest one
+#[derive(Debug)]
+pub struct Diagnostics {
+    start: Instant,
+    events: Mutex<VecDeque<Diagnostic>>,
+}
+
+impl Default for Diagnostics {
+    fn default() -> Diagnostics {
+        Diagnostics::new()
+    }
+}
+
+impl Diagnostics {
+    /// How many events are kept
+    pub const CAPACITY: usize = 256;
+
+    pub fn new() -> Diagnostics {
+        Diagnostics {
+            start: Instant::now(),
+            events: Mutex::new(VecDeque::with_capacity(Self::CAPACITY)),
+        }
+    }
+
+    /// Records the given event as happening now
+    pub fn record(&self, event: DiagnosticEvent) {
+        let diagnostic = Diagnostic {
+            elapsed_ms: self.start.elapsed().as_millis() as u64,
+            event,
+        };
+        // A poisoned buffer is still a buffer
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() == Self::CAPACITY {
+            events.pop_front();
+        }
+        events.push_back(diagnostic);
+    }
+
+    /// Returns the recorded events, oldest first
+    pub fn dump(&self) -> Vec<Diagnostic> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        events.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn oldest_events_are_dropped() {
+        let diagnostics = Diagnostics::new();
+        for added in 0..Diagnostics::CAPACITY + 2 {
+            diagnostics.record(DiagnosticEvent::Committed { added, removed: 0 });
+        }
+        let events = diagnostics.dump();
+        assert_eq!(events.len(), Diagnostics::CAPACITY);
+        assert_eq!(
+            events[0].event,
+            DiagnosticEvent::Committed {
+                added: 2,
+                removed: 0
+            }
+        );
+        assert!(events
+            .windows(2)
+            .all(|w| w[0].elapsed_ms <= w[1].elapsed_ms));
+    }
+}
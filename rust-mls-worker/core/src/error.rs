@@ -0,0 +1,147 @@
+//! The error type of the worker API. Anything that goes wrong while handling an event from the main
+//! thread, whether the event itself is malformed or a peer's MLS message is, is returned as a
+//! [`WorkerError`] and reported to the main thread, rather than taking the worker down with it.
+
+use openmls::prelude::KeyPackageVerifyError;
+use serde::Serialize;
+use thiserror::Error;
+use ts_rs::TS;
+
+use crate::mls_ops::DecryptAppMsgError;
+
+/// Error incurred when handling an event from the main thread. The state is left as it was before
+/// the event
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum WorkerError {
+    #[error(transparent)]
+    Decrypt(#[from] DecryptAppMsgError),
+
+    #[error("unknown event type {0}")]
+    UnknownEvent(String),
+
+    #[error("malformed event: {0}")]
+    MalformedEvent(String),
+
+    #[error("unsupported protocol version {0}")]
+    UnsupportedProtocolVersion(u32),
+
+    #[error("session {0} already exists")]
+    SessionExists(String),
+
+    #[error("unknown session {0}")]
+    UnknownSession(String),
+
+    #[error("invalid group config: {0}")]
+    InvalidGroupConfig(String),
+
+    #[error("group config mismatch: {0}")]
+    GroupConfigMismatch(String),
+
+    #[error("malformed {what}: {source}")]
+    Deserialization {
+        what: &'static str,
+        source: openmls::prelude::Error,
+    },
+
+    #[error("invalid key package: {0}")]
+    InvalidKeyPackage(#[from] KeyPackageVerifyError),
+
+    #[error("incompatible key package: {0}")]
+    IncompatibleKeyPackage(String),
+
+    #[error("wrong message type: expected {0}")]
+    WrongMsgType(&'static str),
+
+    #[error("couldn't {op}: {reason}")]
+    Mls { op: &'static str, reason: String },
+
+    #[error("cannot remove self")]
+    RemoveSelf,
+
+    #[error("not initialized")]
+    NotInitialized,
+
+    #[error("worker state is unavailable")]
+    StateUnavailable,
+
+    #[error("stream failed: {0}")]
+    Stream(String),
+}
+
+impl WorkerError {
+    /// Returns a closure that wraps an error from the MLS operation `op`, for use with `map_err`
+    pub(crate) fn mls<E: std::fmt::Display>(op: &'static str) -> impl FnOnce(E) -> WorkerError {
+        move |e| WorkerError::Mls {
+            op,
+            reason: e.to_string(),
+        }
+    }
+
+    /// Returns a closure that wraps an error from deserializing `what`, for use with `map_err`
+    pub(crate) fn deserialization(
+        what: &'static str,
+    ) -> impl FnOnce(openmls::prelude::Error) -> WorkerError {
+        move |source| WorkerError::Deserialization { what, source }
+    }
+
+    /// Returns the code of this error, as it appears in `error` events posted to the main thread
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            WorkerError::Decrypt(_) => ErrorCode::Decrypt,
+            WorkerError::UnknownEvent(_) => ErrorCode::UnknownEvent,
+            WorkerError::MalformedEvent(_) => ErrorCode::MalformedEvent,
+            WorkerError::UnsupportedProtocolVersion(_) => ErrorCode::UnsupportedProtocolVersion,
+            WorkerError::SessionExists(_) => ErrorCode::SessionExists,
+            WorkerError::UnknownSession(_) => ErrorCode::UnknownSession,
+            WorkerError::InvalidGroupConfig(_) => ErrorCode::InvalidGroupConfig,
+            WorkerError::GroupConfigMismatch(_) => ErrorCode::GroupConfigMismatch,
+            WorkerError::Deserialization { .. } => ErrorCode::MalformedMessage,
+            WorkerError::InvalidKeyPackage(_) => ErrorCode::InvalidKeyPackage,
+            WorkerError::IncompatibleKeyPackage(_) => ErrorCode::IncompatibleKeyPackage,
+            WorkerError::WrongMsgType(_) => ErrorCode::WrongMessageType,
+            WorkerError::Mls { .. } => ErrorCode::Mls,
+            WorkerError::RemoveSelf => ErrorCode::RemoveSelf,
+            WorkerError::NotInitialized => ErrorCode::NotInitialized,
+            WorkerError::StateUnavailable => ErrorCode::StateUnavailable,
+            WorkerError::Stream(_) => ErrorCode::Stream,
+        }
+    }
+}
+
+/// The kind of error a worker error is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum ErrorCode {
+    Decrypt,
+    /// The event's `type` isn't one the worker knows
+    UnknownEvent,
+    /// The event is missing a field, or a field has the wrong type or value
+    MalformedEvent,
+    /// The main thread asked for a protocol version outside of the ones in `workerReady`
+    UnsupportedProtocolVersion,
+    /// The main thread tried to initialize a session that's already initialized. Sessions must be
+    /// destroyed before their ID can be reused
+    SessionExists,
+    /// The event is addressed to a session that doesn't exist
+    UnknownSession,
+    /// The group config the main thread asked for is out of range
+    InvalidGroupConfig,
+    /// This user was welcomed into a group in a ciphersuite it doesn't accept, or whose parameters
+    /// are missing or out of range, so it didn't join
+    GroupConfigMismatch,
+    /// An MLS message, Welcome, ratchet tree, or key package relayed from a peer couldn't be parsed
+    MalformedMessage,
+    InvalidKeyPackage,
+    /// A user who wants to join has no key package for the group's ciphersuite, so the designated
+    /// committer can't add it
+    IncompatibleKeyPackage,
+    WrongMessageType,
+    /// An MLS operation failed, e.g., a Commit didn't apply to the group
+    Mls,
+    RemoveSelf,
+    NotInitialized,
+    StateUnavailable,
+    /// A stream of frames failed to read or write
+    Stream,
+}
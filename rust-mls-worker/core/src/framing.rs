@@ -0,0 +1,173 @@
+//! Codec-aware layout of encrypted media frames. The browser's packetizer and depacketizer look
+//! inside encoded frames, so we can't just encrypt a frame wholesale. For every codec, this module
+//! decides which part of a frame is left in the clear, which part is encrypted, and how the
+//! ciphertext is placed back into the frame so that the result is still well-formed.
+
+use thiserror::Error;
+
+mod av1;
+mod nal;
+mod opus;
+mod red;
+mod vp8;
+mod vp9;
+
+pub use opus::AudioPolicy;
+
+/// Error incurred when an encrypted frame doesn't have the layout we expect for its codec
+#[derive(Error, Debug, PartialEq, Clone)]
+#[error("malformed {0} frame")]
+pub struct MalformedFrame(pub &'static str);
+
+/// Whether an encoded frame is audio or video
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Audio,
+    Video,
+}
+
+/// The codec of a stream of encoded frames
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Codec {
+    #[default]
+    Vp8,
+    Vp9,
+    H264,
+    H265,
+    Av1,
+    /// Opus audio, with the policy for what to leave in the clear
+    Opus(AudioPolicy),
+    /// Opus audio with redundancy (RFC 2198). Every block is framed according to the policy
+    Red(AudioPolicy),
+}
+
+impl Codec {
+    /// Returns the codec with the given MIME type, e.g., `video/H264`. Returns `None` if we don't
+    /// know how to frame this codec.
+    pub fn from_mime_type(mime_type: &str) -> Option<Codec> {
+        // MIME types are case-insensitive, and browsers aren't consistent about it
+        match mime_type.to_ascii_lowercase().as_str() {
+            "video/vp8" => Some(Codec::Vp8),
+            "video/vp9" => Some(Codec::Vp9),
+            "video/h264" => Some(Codec::H264),
+            "video/h265" => Some(Codec::H265),
+            "video/av1" => Some(Codec::Av1),
+            _ => None,
+        }
+    }
+
+    /// Returns whether frames of this codec are audio or video
+    pub fn kind(self) -> FrameKind {
+        match self {
+            Codec::Opus(_) | Codec::Red(_) => FrameKind::Audio,
+            _ => FrameKind::Video,
+        }
+    }
+
+    /// Encrypts the given frame and appends the result to `out`. `encrypt` is called on every part
+    /// of the frame that must be encrypted, and appends the ciphertext to the buffer it's given.
+    /// Where the layout allows, that buffer is `out` itself, so the ciphertext is written in place.
+    pub fn encrypt_frame(
+        self,
+        frame: &[u8],
+        out: &mut Vec<u8>,
+        encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+    ) {
+        match self {
+            Codec::Vp8 => vp8::encrypt_frame(frame, out, encrypt),
+            Codec::Vp9 => vp9::encrypt_frame(frame, out, encrypt),
+            Codec::H264 => nal::H264.encrypt_frame(frame, out, encrypt),
+            Codec::H265 => nal::H265.encrypt_frame(frame, out, encrypt),
+            Codec::Av1 => av1::encrypt_frame(frame, out, encrypt),
+            Codec::Opus(policy) => opus::encrypt_frame(policy, frame, out, encrypt),
+            Codec::Red(policy) => red::encrypt_frame(policy, frame, out, encrypt),
+        }
+    }
+
+    /// Appends a frame that a decoder can take in place of the given encrypted frame, which
+    /// couldn't be decrypted, to `out`. For audio, this is silence. Nothing can stand in for a video
+    /// frame without knowing the decoder's state, so for video, this appends nothing and returns
+    /// `false`.
+    pub fn write_concealment_frame(self, frame: &[u8], out: &mut Vec<u8>) -> bool {
+        match self {
+            Codec::Opus(_) => {
+                opus::write_silence(out);
+                true
+            }
+            Codec::Red(_) => red::write_silence(frame, out),
+            _ => false,
+        }
+    }
+
+    /// Decrypts a frame produced by [`Codec::encrypt_frame`] and appends the result to `out`.
+    /// `decrypt` is called on every ciphertext found in the frame, and appends the plaintext to the
+    /// buffer it's given. On error, `out` may contain part of the frame.
+    pub fn decrypt_frame<E: From<MalformedFrame>>(
+        self,
+        frame: &[u8],
+        out: &mut Vec<u8>,
+        decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        match self {
+            Codec::Vp8 => vp8::decrypt_frame(frame, out, decrypt),
+            Codec::Vp9 => vp9::decrypt_frame(frame, out, decrypt),
+            Codec::H264 => nal::H264.decrypt_frame(frame, out, decrypt),
+            Codec::H265 => nal::H265.decrypt_frame(frame, out, decrypt),
+            Codec::Av1 => av1::decrypt_frame(frame, out, decrypt),
+            Codec::Opus(policy) => opus::decrypt_frame(policy, frame, out, decrypt),
+            Codec::Red(policy) => red::decrypt_frame(policy, frame, out, decrypt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_util {
+    use super::MalformedFrame;
+
+    /// A stand-in for MLS encryption. The "ciphertext" is the plaintext with every byte flipped,
+    /// followed by some bytes that are awkward for framing (zeros and start code lookalikes)
+    pub(super) fn fake_encrypt(pt: &[u8], out: &mut Vec<u8>) {
+        out.extend(pt.iter().map(|b| !b));
+        out.extend_from_slice(&[0, 0, 1, 0, 0, 0, 0, 3, 0]);
+    }
+
+    /// Inverts [`fake_encrypt`]
+    pub(super) fn fake_decrypt(ct: &[u8], out: &mut Vec<u8>) -> Result<(), MalformedFrame> {
+        let pt = ct
+            .strip_suffix(&[0, 0, 1, 0, 0, 0, 0, 3, 0])
+            .ok_or(MalformedFrame("fake ciphertext"))?;
+        out.extend(pt.iter().map(|b| !b));
+        Ok(())
+    }
+
+    /// Returns everything `f` appends to an empty buffer
+    pub(super) fn collect(f: impl FnOnce(&mut Vec<u8>)) -> Vec<u8> {
+        let mut out = Vec::new();
+        f(&mut out);
+        out
+    }
+
+    /// Returns everything `f` appends to an empty buffer, or its error
+    pub(super) fn try_collect<E>(
+        f: impl FnOnce(&mut Vec<u8>) -> Result<(), E>,
+    ) -> Result<Vec<u8>, E> {
+        let mut out = Vec::new();
+        f(&mut out)?;
+        Ok(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mime_types() {
+        assert_eq!(Codec::from_mime_type("video/VP8"), Some(Codec::Vp8));
+        assert_eq!(Codec::from_mime_type("video/VP9"), Some(Codec::Vp9));
+        assert_eq!(Codec::from_mime_type("video/h264"), Some(Codec::H264));
+        assert_eq!(Codec::from_mime_type("video/H265"), Some(Codec::H265));
+        assert_eq!(Codec::from_mime_type("video/AV1"), Some(Codec::Av1));
+        assert_eq!(Codec::from_mime_type("video/rtx"), None);
+    }
+}
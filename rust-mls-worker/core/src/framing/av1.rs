@@ -0,0 +1,305 @@
+//! AV1 framing. A frame is a sequence of OBUs, each of which is a 1–2 byte header, an optional
+//! leb128-encoded size, and a payload. The packetizer walks the OBUs and copies their headers into
+//! the RTP aggregation header, so every OBU header stays in the clear. Temporal delimiters and
+//! sequence headers are left entirely in the clear, since the depacketizer uses them to recognize
+//! keyframes and they don't say anything about the picture. Every other OBU payload is encrypted on
+//! its own, and its size field is rewritten to hold the size of the ciphertext. A frame that isn't
+//! a sequence of OBUs is encrypted whole, and sent as the payload of a single OBU of a reserved
+//! type, which decoders ignore. That way every encrypted frame is a sequence of OBUs, and the
+//! receiver knows which of the two it got.
+
+use std::iter;
+
+use super::MalformedFrame;
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+const OBU_EXTENSION_FLAG: u8 = 0b0000_0100;
+const OBU_HAS_SIZE_FIELD: u8 = 0b0000_0010;
+
+/// The header of the OBU holding a frame that was encrypted whole: reserved OBU type 0, no
+/// extension, and a size field
+const WHOLE_FRAME_HEADER: u8 = OBU_HAS_SIZE_FIELD;
+
+/// The largest size a leb128 value in an AV1 bitstream can have
+const MAX_LEB128_LEN: usize = 8;
+
+/// A single OBU in a frame
+struct Obu<'a> {
+    /// The OBU header, including the extension header if there is one
+    header: &'a [u8],
+    payload: &'a [u8],
+}
+
+impl Obu<'_> {
+    fn obu_type(&self) -> u8 {
+        (self.header[0] >> 3) & 0b1111
+    }
+
+    fn has_size_field(&self) -> bool {
+        self.header[0] & OBU_HAS_SIZE_FIELD != 0
+    }
+
+    /// Returns whether this OBU's payload is left in the clear
+    fn is_clear(&self) -> bool {
+        matches!(
+            self.obu_type(),
+            OBU_SEQUENCE_HEADER | OBU_TEMPORAL_DELIMITER
+        )
+    }
+
+    /// Returns how many bytes to leave for the size field in front of the new payload. That's as
+    /// many as the old payload's size takes, which is usually as many as the new one's takes too.
+    fn reserved_size_len(&self) -> usize {
+        if self.has_size_field() {
+            leb128_len(self.payload.len())
+        } else {
+            0
+        }
+    }
+
+    /// Appends this OBU's header and room for its size field to `out`, and returns where the
+    /// payload starts. The new payload must then be appended, and [`Obu::finish`] called with the
+    /// returned position.
+    fn start(&self, out: &mut Vec<u8>) -> usize {
+        out.extend_from_slice(self.header);
+        out.resize(out.len() + self.reserved_size_len(), 0);
+        out.len()
+    }
+
+    /// Writes the size field in front of the payload that starts at `payload_start`, if this OBU
+    /// has one. The size is that of the new payload, i.e., everything after `payload_start`. The
+    /// payload only has to move if its size doesn't fit in the room left by [`Obu::start`].
+    fn finish(&self, out: &mut Vec<u8>, payload_start: usize) {
+        if self.has_size_field() {
+            let (encoded, len) = encode_leb128(out.len() - payload_start);
+            let field = payload_start - self.reserved_size_len()..payload_start;
+            if field.len() == len {
+                out[field].copy_from_slice(&encoded[..len]);
+            } else {
+                out.splice(field, encoded[..len].iter().copied());
+            }
+        }
+    }
+}
+
+/// Reads a leb128-encoded integer from the start of `data`. Returns the integer and the number of
+/// bytes it took up.
+fn read_leb128(data: &[u8]) -> Option<(usize, usize)> {
+    let mut value: u64 = 0;
+    for (i, &b) in data.iter().take(MAX_LEB128_LEN).enumerate() {
+        value |= u64::from(b & 0x7f) << (7 * i);
+        if b & 0x80 == 0 {
+            return Some((usize::try_from(value).ok()?, i + 1));
+        }
+    }
+    None
+}
+
+/// Returns the number of bytes the leb128 encoding of `value` takes up
+fn leb128_len(value: usize) -> usize {
+    let bits = usize::BITS - value.leading_zeros();
+    (bits as usize).div_ceil(7).max(1)
+}
+
+/// Returns the leb128 encoding of `value`, and its length
+fn encode_leb128(mut value: usize) -> ([u8; MAX_LEB128_LEN], usize) {
+    let mut encoded = [0; MAX_LEB128_LEN];
+    let mut len = 0;
+    loop {
+        encoded[len] = (value & 0x7f) as u8;
+        value >>= 7;
+        len += 1;
+        if value == 0 {
+            break;
+        }
+        encoded[len - 1] |= 0x80;
+    }
+    (encoded, len)
+}
+
+/// Splits the first OBU off the given data. Returns `None` if the data doesn't start with a
+/// well-formed OBU.
+fn split_obu(data: &[u8]) -> Option<(Obu<'_>, &[u8])> {
+    let &first_byte = data.first()?;
+    let header_len = if first_byte & OBU_EXTENSION_FLAG != 0 {
+        2
+    } else {
+        1
+    };
+    let (header, rest) = data.split_at_checked(header_len)?;
+
+    // An OBU without a size field takes up the rest of the frame
+    let (payload, rest) = if first_byte & OBU_HAS_SIZE_FIELD != 0 {
+        let (size, size_len) = read_leb128(rest)?;
+        rest[size_len..].split_at_checked(size)?
+    } else {
+        (rest, &[][..])
+    };
+
+    Some((Obu { header, payload }, rest))
+}
+
+/// Iterates over the OBUs of the given frame. A frame that isn't a well-formed sequence of OBUs
+/// ends with a `None`.
+fn obus(mut data: &[u8]) -> impl Iterator<Item = Option<Obu<'_>>> {
+    iter::from_fn(move || {
+        if data.is_empty() {
+            return None;
+        }
+        let Some((obu, rest)) = split_obu(data) else {
+            data = &[];
+            return Some(None);
+        };
+        data = rest;
+        Some(Some(obu))
+    })
+}
+
+pub(super) fn encrypt_frame(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+) {
+    // If this isn't a sequence of OBUs, there's nothing we know to be safe to leave in the clear.
+    // Neither is there if it starts like a frame that was encrypted whole, or the receiver would
+    // take it for one
+    if frame.first() == Some(&WHOLE_FRAME_HEADER) || obus(frame).any(|obu| obu.is_none()) {
+        let obu = Obu {
+            header: &[WHOLE_FRAME_HEADER],
+            payload: frame,
+        };
+        let payload_start = obu.start(out);
+        encrypt(frame, out);
+        obu.finish(out, payload_start);
+        return;
+    }
+
+    for obu in obus(frame).flatten() {
+        let payload_start = obu.start(out);
+        if obu.is_clear() {
+            out.extend_from_slice(obu.payload);
+        } else {
+            encrypt(obu.payload, out);
+        }
+        obu.finish(out, payload_start);
+    }
+}
+
+pub(super) fn decrypt_frame<E: From<MalformedFrame>>(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<(), E> {
+    if frame.first() == Some(&WHOLE_FRAME_HEADER) {
+        return match split_obu(frame) {
+            Some((obu, [])) => decrypt(obu.payload, out),
+            _ => Err(MalformedFrame("AV1").into()),
+        };
+    }
+
+    for obu in obus(frame) {
+        let obu = obu.ok_or(MalformedFrame("AV1"))?;
+        let payload_start = obu.start(out);
+        if obu.is_clear() {
+            out.extend_from_slice(obu.payload);
+        } else {
+            decrypt(obu.payload, out)?;
+        }
+        obu.finish(out, payload_start);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::test_util::{collect, fake_decrypt, fake_encrypt, try_collect},
+        *,
+    };
+
+    fn parse_obus(data: &[u8]) -> Option<Vec<Obu<'_>>> {
+        obus(data).collect()
+    }
+
+    #[test]
+    fn leb128() {
+        for value in [0, 1, 127, 128, 300, 1 << 20] {
+            let (encoded, len) = encode_leb128(value);
+            assert_eq!(leb128_len(value), len);
+            assert_eq!(read_leb128(&encoded[..len]), Some((value, len)));
+        }
+        // Unterminated
+        assert_eq!(read_leb128(&[0x80, 0x80]), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let frame = [
+            &[0x12, 0x00][..],                     // Temporal delimiter
+            &[0x0a, 0x03, 0x00, 0x00, 0x00],       // Sequence header
+            &[0x36, 0x10, 0x03, 0x01, 0x02, 0x03], // Frame, with extension header
+            &[0x30, 0x05, 0x06],                   // Frame, without size field
+        ]
+        .concat();
+        let ct = collect(|out| encrypt_frame(&frame, out, fake_encrypt));
+
+        // The temporal delimiter and sequence header are untouched, and every OBU has its header
+        let obus = parse_obus(&ct).unwrap();
+        assert_eq!(obus.len(), 4);
+        assert_eq!(ct[..7], frame[..7]);
+        assert_eq!(obus[2].header, [0x36, 0x10]);
+        assert_eq!(
+            obus[2].payload,
+            collect(|out| fake_encrypt(&[1, 2, 3], out))
+        );
+        assert_eq!(obus[3].header, [0x30]);
+
+        assert_eq!(
+            try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn size_field_growth() {
+        // The ciphertext is too long for the payload's 1-byte size field, so the field grows
+        let frame = [&[0x32, 0x7f][..], &[0x55; 0x7f]].concat();
+        let ct = collect(|out| encrypt_frame(&frame, out, fake_encrypt));
+        let obus = parse_obus(&ct).unwrap();
+        assert_eq!(obus.len(), 1);
+        assert_eq!(obus[0].payload.len(), 0x7f + 9);
+        assert_eq!(
+            try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn unparsable_roundtrip() {
+        // The size field runs past the end of the frame, and the second frame starts like one that
+        // was encrypted whole. Both are encrypted whole, in an OBU of their own
+        for frame in [&[0x32, 0x05, 0x00][..], &[WHOLE_FRAME_HEADER, 0x01, 0x00]] {
+            let ct = collect(|out| encrypt_frame(frame, out, fake_encrypt));
+            let obus = parse_obus(&ct).unwrap();
+            assert_eq!(obus.len(), 1);
+            assert_eq!(obus[0].header, [WHOLE_FRAME_HEADER]);
+            assert_eq!(obus[0].payload, collect(|out| fake_encrypt(frame, out)));
+            assert_eq!(
+                try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn malformed() {
+        // The size field runs past the end of the frame
+        assert!(decrypt_frame(&[0x32, 0x05, 0x00], &mut Vec::new(), fake_decrypt).is_err());
+        // Missing extension header
+        assert!(decrypt_frame(&[0x36], &mut Vec::new(), fake_decrypt).is_err());
+        // A frame that was encrypted whole, followed by another OBU
+        assert!(decrypt_frame(&[0x02, 0x00, 0x30], &mut Vec::new(), fake_decrypt).is_err());
+    }
+}
@@ -0,0 +1,327 @@
+//! Framing for codecs whose frames are Annex B byte streams of NAL units, i.e., H.264 and H.265.
+//! The packetizer splits frames on start codes and reads the NAL header of every NAL unit it finds,
+//! and the depacketizer needs to see parameter sets and the type of the first slice to recognize
+//! keyframes.
+//!
+//! So we walk every NAL unit in the frame. Start codes and NAL headers stay in the clear, and so do
+//! NAL units that aren't slices (parameter sets, SEI, access unit delimiters), wherever they are in
+//! the frame. The payload of every slice is encrypted on its own, and the ciphertext becomes the
+//! slice's body, so it's escaped with emulation prevention bytes to make sure it can't be mistaken
+//! for a start code. A frame without any NAL units is encrypted whole.
+
+use std::{borrow::Cow, convert::Infallible};
+
+use super::MalformedFrame;
+
+/// The byte that terminates an escaped ciphertext. This is what `rbsp_trailing_bits` looks like,
+/// and being nonzero it guarantees the escaped ciphertext can't end in a partial start code.
+const STOP_BYTE: u8 = 0x80;
+
+/// The emulation prevention byte
+const EPB: u8 = 0x03;
+
+/// Describes the NAL unit syntax of a codec
+pub(super) struct NalSyntax {
+    /// The name of the codec, used in errors
+    name: &'static str,
+    /// The length of a NAL header in bytes
+    header_len: usize,
+    /// Given a NAL header, returns whether the NAL unit is a slice, i.e., carries picture data
+    is_vcl: fn(&[u8]) -> bool,
+}
+
+pub(super) const H264: NalSyntax = NalSyntax {
+    name: "H.264",
+    header_len: 1,
+    // Types 1–5 are coded slices (ITU-T H.264 Table 7-1)
+    is_vcl: |header| matches!(header[0] & 0x1f, 1..=5),
+};
+
+pub(super) const H265: NalSyntax = NalSyntax {
+    name: "H.265",
+    header_len: 2,
+    // Types 0–31 are VCL NAL units, and 32–34 are the VPS, SPS, and PPS (ITU-T H.265 Table 7-1)
+    is_vcl: |header| (header[0] >> 1) & 0x3f < 32,
+};
+
+impl NalSyntax {
+    /// Splits the frame into the parts that are left in the clear and the parts that are
+    /// encrypted, and calls `f` on each in order, along with whether it's encrypted. The encrypted
+    /// parts are the slice payloads, i.e., everything between a slice's NAL header and the next
+    /// start code. If there are no NAL units at all, the whole frame is one encrypted part.
+    ///
+    /// This splits a plaintext frame and its encrypted counterpart the same way, because the
+    /// escaped ciphertexts contain no start codes and leave the NAL headers as they were.
+    fn for_each_part<E>(
+        &self,
+        frame: &[u8],
+        mut f: impl FnMut(&[u8], bool) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut starts = nal_unit_starts(frame).peekable();
+        if starts.peek().is_none() {
+            return f(frame, true);
+        }
+
+        // Everything that isn't a slice payload goes into the clear part before the next one
+        let mut clear_start = 0;
+        while let Some(start) = starts.next() {
+            // A NAL unit ends where the next start code begins
+            let end = starts.peek().map_or(frame.len(), |&next| next - 3);
+            let header_end = start + self.header_len;
+            if header_end <= end && (self.is_vcl)(&frame[start..header_end]) {
+                f(&frame[clear_start..header_end], false)?;
+                f(&frame[header_end..end], true)?;
+                clear_start = end;
+            }
+        }
+        f(&frame[clear_start..], false)
+    }
+
+    pub(super) fn encrypt_frame(
+        &self,
+        frame: &[u8],
+        out: &mut Vec<u8>,
+        mut encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+    ) {
+        let res = self.for_each_part(frame, |part, encrypted| {
+            if encrypted {
+                let ct_start = out.len();
+                encrypt(part, out);
+                escape_in_place(out, ct_start);
+            } else {
+                out.extend_from_slice(part);
+            }
+            Ok::<_, Infallible>(())
+        });
+        let Ok(()) = res;
+    }
+
+    pub(super) fn decrypt_frame<E: From<MalformedFrame>>(
+        &self,
+        frame: &[u8],
+        out: &mut Vec<u8>,
+        mut decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.for_each_part(frame, |part, encrypted| {
+            if encrypted {
+                // What's in `out` so far ends with the same bytes that preceded the ciphertext
+                let msg_to_decrypt = unescape(out, part).ok_or(MalformedFrame(self.name))?;
+                decrypt(&msg_to_decrypt, out)
+            } else {
+                out.extend_from_slice(part);
+                Ok(())
+            }
+        })
+    }
+}
+
+/// Returns the index of the first byte of every NAL unit in the given Annex B byte stream, i.e.,
+/// the index right after every 3-byte start code. A 4-byte start code is just a 3-byte one preceded
+/// by a zero.
+fn nal_unit_starts(stream: &[u8]) -> impl Iterator<Item = usize> + '_ {
+    stream
+        .windows(3)
+        .enumerate()
+        .filter(|(_, w)| *w == [0, 0, 1])
+        .map(|(i, _)| i + 3)
+}
+
+/// Returns the number of consecutive zeros at the end of `bytes`
+fn trailing_zeros(bytes: &[u8]) -> usize {
+    bytes.iter().rev().take_while(|&&b| b == 0).count()
+}
+
+/// Escapes everything in `out` from index `start` on, by inserting emulation prevention bytes
+/// wherever two zeros are followed by a byte that's at most 3, then appends [`STOP_BYTE`]. Zeros
+/// right before `start` are taken into account.
+fn escape_in_place(out: &mut Vec<u8>, start: usize) {
+    // Count the bytes that need an EPB in front of them. There are usually none
+    let needs_epb = |zeros: &mut usize, b: u8| {
+        let needed = *zeros >= 2 && b <= EPB;
+        if needed {
+            *zeros = 0;
+        }
+        *zeros = if b == 0 { *zeros + 1 } else { 0 };
+        needed
+    };
+    let preceding_zeros = trailing_zeros(&out[..start]);
+    let mut zeros = preceding_zeros;
+    let epbs = out[start..]
+        .iter()
+        .filter(|&&b| needs_epb(&mut zeros, b))
+        .count();
+
+    // Move the data to the back of the room made for the EPBs, then move it forward into place,
+    // EPBs included. The write position never passes the read position
+    if epbs > 0 {
+        let end = out.len();
+        out.resize(end + epbs, 0);
+        out.copy_within(start..end, start + epbs);
+        let mut zeros = preceding_zeros;
+        let mut write = start;
+        for read in start + epbs..end + epbs {
+            let b = out[read];
+            if needs_epb(&mut zeros, b) {
+                out[write] = EPB;
+                write += 1;
+            }
+            out[write] = b;
+            write += 1;
+        }
+    }
+    out.push(STOP_BYTE);
+}
+
+/// Inverts [`escape_in_place`], where `preceding` is the data that came before `escaped` in the
+/// stream. This only copies if there are EPBs to remove. Returns `None` if `escaped` isn't
+/// terminated by [`STOP_BYTE`].
+fn unescape<'a>(preceding: &[u8], escaped: &'a [u8]) -> Option<Cow<'a, [u8]>> {
+    let escaped = escaped.strip_suffix(&[STOP_BYTE])?;
+    let mut zeros = trailing_zeros(preceding);
+    let mut out = Cow::Borrowed(escaped);
+    for (i, &b) in escaped.iter().enumerate() {
+        if zeros >= 2 && b == EPB {
+            zeros = 0;
+            if let Cow::Borrowed(_) = out {
+                out = Cow::Owned(escaped[..i].to_vec());
+            }
+            continue;
+        }
+        if let Cow::Owned(out) = &mut out {
+            out.push(b);
+        }
+        zeros = if b == 0 { zeros + 1 } else { 0 };
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::test_util::{collect, fake_decrypt, fake_encrypt, try_collect},
+        *,
+    };
+
+    /// An H.264 keyframe: SPS, PPS, and two IDR slices, with a mix of 3- and 4-byte start codes
+    const H264_KEYFRAME: &[u8] = &[
+        0, 0, 0, 1, 0x67, 0x42, 0xc0, 0x1f, // SPS
+        0, 0, 0, 1, 0x68, 0xce, 0x3c, 0x80, // PPS
+        0, 0, 1, 0x65, 0x88, 0x84, 0x00, 0x00, 0x03, 0x01, // IDR slice
+        0, 0, 1, 0x65, 0x00, 0x11, 0x22, // IDR slice
+    ];
+
+    #[test]
+    fn escaping() {
+        let data = [0, 0, 0, 0, 1, 0, 0, 2, 0, 0, 3, 0, 0, 4, 0, 0];
+        for preceding in [&[][..], &[0], &[0, 0], &[0x65]] {
+            let mut out = [preceding, &data].concat();
+            escape_in_place(&mut out, preceding.len());
+            // Nothing in the output looks like a start code
+            assert!(out
+                .windows(3)
+                .all(|w| !(w[0] == 0 && w[1] == 0 && w[2] < EPB)));
+            let escaped = &out[preceding.len()..];
+            assert_eq!(*unescape(preceding, escaped).unwrap(), data);
+        }
+
+        // Data that needs no escaping isn't copied
+        assert!(matches!(
+            unescape(&[], &[0, 0, 4, STOP_BYTE]),
+            Some(Cow::Borrowed(_))
+        ));
+
+        // A missing stop byte is an error
+        assert!(unescape(&[], &[1, 2, 3]).is_none());
+    }
+
+    /// Returns the NAL headers of the given H.264 stream
+    fn h264_headers(stream: &[u8]) -> Vec<u8> {
+        nal_unit_starts(stream).map(|start| stream[start]).collect()
+    }
+
+    #[test]
+    fn h264_roundtrip() {
+        let ct = collect(|out| H264.encrypt_frame(H264_KEYFRAME, out, fake_encrypt));
+
+        // Parameter sets and the first slice header are in the clear, and so is every other NAL
+        // header
+        let clear_len = 20;
+        assert_eq!(ct[..clear_len], H264_KEYFRAME[..clear_len]);
+        assert_eq!(h264_headers(&ct), h264_headers(H264_KEYFRAME));
+
+        assert_eq!(
+            try_collect(|out| H264.decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            H264_KEYFRAME
+        );
+    }
+
+    #[test]
+    fn h265_roundtrip() {
+        // VPS, SPS, PPS, and an IDR slice, whose two-byte header is 0x26 0x01
+        let frame = [
+            0, 0, 0, 1, 0x40, 0x01, 0x0c, // VPS
+            0, 0, 0, 1, 0x42, 0x01, 0x01, // SPS
+            0, 0, 0, 1, 0x44, 0x01, 0xc1, // PPS
+            0, 0, 0, 1, 0x26, 0x01, 0xaf, 0x00, 0x00, 0x03, 0x00, // IDR slice
+        ];
+        let ct = collect(|out| H265.encrypt_frame(&frame, out, fake_encrypt));
+
+        let clear_len = 27;
+        assert_eq!(ct[..clear_len], frame[..clear_len]);
+        assert_eq!(nal_unit_starts(&ct).last(), Some(clear_len - 2));
+        assert_eq!(
+            try_collect(|out| H265.decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn h264_multi_slice() {
+        // Two slices with an SEI and a PPS in between them, like some hardware encoders make
+        let frame = [
+            0, 0, 0, 1, 0x41, 0x9a, 0x00, 0x00, 0x03, 0x02, // Slice
+            0, 0, 1, 0x06, 0x05, 0x01, 0xaa, // SEI
+            0, 0, 1, 0x68, 0xce, 0x3c, 0x80, // PPS
+            0, 0, 1, 0x41, 0x11, 0x22, // Slice
+        ];
+        let ct = collect(|out| H264.encrypt_frame(&frame, out, fake_encrypt));
+
+        // Every NAL header is in the clear, and so are the SEI and PPS after the first slice
+        assert_eq!(h264_headers(&ct), h264_headers(&frame));
+        let sei_and_pps = &frame[10..24];
+        assert!(ct.windows(sei_and_pps.len()).any(|w| w == sei_and_pps));
+
+        // Each slice payload is encrypted on its own
+        let mut payloads = Vec::new();
+        let pt = try_collect(|out| {
+            H264.decrypt_frame(&ct, out, |ct, out| {
+                let start = out.len();
+                fake_decrypt(ct, out)?;
+                payloads.push(out[start..].to_vec());
+                Ok::<_, MalformedFrame>(())
+            })
+        });
+        assert_eq!(pt.unwrap(), frame);
+        assert_eq!(payloads, [&frame[5..10], &frame[28..]]);
+    }
+
+    #[test]
+    fn h264_no_slices() {
+        // Without a slice, everything is in the clear
+        let frame = [0, 0, 1, 0x67, 0x42, 0, 0, 1, 0x68, 0xce];
+        let ct = collect(|out| H264.encrypt_frame(&frame, out, fake_encrypt));
+        assert_eq!(ct, frame);
+        assert_eq!(
+            try_collect(|out| H264.decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+
+        // Without any NAL units, everything is encrypted
+        let frame = [1, 2, 3];
+        let ct = collect(|out| H264.encrypt_frame(&frame, out, fake_encrypt));
+        assert_eq!(
+            try_collect(|out| H264.decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+    }
+}
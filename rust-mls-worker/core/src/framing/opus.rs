@@ -0,0 +1,119 @@
+//! Opus framing. Nothing in an Opus frame is needed by the packetizer or depacketizer, so how much
+//! to leave in the clear is a matter of policy. The TOC byte says which mode, bandwidth, and frame
+//! duration the encoder picked, which some middleboxes like to see, but which also leaks a little
+//! about the audio (e.g., speech vs music). Both sides must use the same policy.
+
+use serde::Deserialize;
+use ts_rs::TS;
+
+use super::MalformedFrame;
+
+/// What to leave in the clear in an audio frame. Everyone in a room must use the same policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum AudioPolicy {
+    /// Leave the Opus TOC byte in the clear and encrypt the rest
+    KeepToc,
+    /// Encrypt the whole frame
+    #[default]
+    EncryptAll,
+}
+
+impl AudioPolicy {
+    /// Returns how many bytes at the start of a frame this policy leaves in the clear
+    fn clear_len(self) -> usize {
+        match self {
+            AudioPolicy::KeepToc => 1,
+            AudioPolicy::EncryptAll => 0,
+        }
+    }
+}
+
+/// A 20 ms fullband CELT frame of silence, which any Opus decoder plays regardless of what the
+/// stream was encoded with
+const SILENCE: [u8; 3] = [0xf8, 0xff, 0xfe];
+
+/// Appends a frame of silence to `out`
+pub(super) fn write_silence(out: &mut Vec<u8>) {
+    out.extend_from_slice(&SILENCE);
+}
+
+pub(super) fn encrypt_frame(
+    policy: AudioPolicy,
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+) {
+    // An empty frame has no TOC byte to keep, and with the TOC byte kept, nothing to encrypt either.
+    // It's sent as is, like an empty AV1 frame
+    let Some((toc, msg_to_encrypt)) = frame.split_at_checked(policy.clear_len()) else {
+        return;
+    };
+    out.extend_from_slice(toc);
+    encrypt(msg_to_encrypt, out);
+}
+
+pub(super) fn decrypt_frame<E: From<MalformedFrame>>(
+    policy: AudioPolicy,
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<(), E> {
+    // An empty frame was sent as is
+    let Some((toc, msg_to_decrypt)) = frame.split_at_checked(policy.clear_len()) else {
+        return Ok(());
+    };
+    out.extend_from_slice(toc);
+    decrypt(msg_to_decrypt, out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::test_util::{collect, fake_decrypt, fake_encrypt, try_collect},
+        *,
+    };
+
+    #[test]
+    fn policies() {
+        let frame = [0x78, 0x01, 0x02, 0x03];
+
+        let ct = collect(|out| encrypt_frame(AudioPolicy::KeepToc, &frame, out, fake_encrypt));
+        assert_eq!(ct[0], frame[0]);
+        assert_eq!(ct[1..], collect(|out| fake_encrypt(&frame[1..], out)));
+        assert_eq!(
+            try_collect(|out| decrypt_frame(AudioPolicy::KeepToc, &ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+
+        let ct = collect(|out| encrypt_frame(AudioPolicy::EncryptAll, &frame, out, fake_encrypt));
+        assert_eq!(ct, collect(|out| fake_encrypt(&frame, out)));
+        assert_eq!(
+            try_collect(|out| decrypt_frame(AudioPolicy::EncryptAll, &ct, out, fake_decrypt))
+                .unwrap(),
+            frame
+        );
+    }
+
+    #[test]
+    fn empty_frames() {
+        // Keeping the TOC byte leaves nothing to encrypt, so the frame stays empty
+        let ct = collect(|out| encrypt_frame(AudioPolicy::KeepToc, &[], out, fake_encrypt));
+        assert!(ct.is_empty());
+        assert!(
+            try_collect(|out| decrypt_frame(AudioPolicy::KeepToc, &ct, out, fake_decrypt))
+                .unwrap()
+                .is_empty()
+        );
+
+        // Otherwise, the empty frame is encrypted
+        let ct = collect(|out| encrypt_frame(AudioPolicy::EncryptAll, &[], out, fake_encrypt));
+        assert_eq!(ct, collect(|out| fake_encrypt(&[], out)));
+        assert!(
+            try_collect(|out| decrypt_frame(AudioPolicy::EncryptAll, &ct, out, fake_decrypt))
+                .unwrap()
+                .is_empty()
+        );
+    }
+}
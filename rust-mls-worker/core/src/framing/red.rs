@@ -0,0 +1,285 @@
+//! RED (RFC 2198) framing. When Opus is negotiated with RED, a frame is a list of block headers
+//! followed by some redundant blocks (copies of earlier frames) and the primary block. The block
+//! headers stay in the clear so the SFU and receiver can still make use of the redundancy, and
+//! every block is encrypted on its own as an Opus frame. Block lengths are rewritten to hold the
+//! length of the encrypted blocks. Since the lengths come before the blocks, the headers are copied
+//! first and their lengths filled in as the blocks are written. A frame that isn't a RED payload is
+//! encrypted whole, and sent as the primary block of a RED payload with [`WHOLE_FRAME_HEADER`] as
+//! its only header. That way every encrypted frame is a RED payload, and the receiver knows which
+//! of the two it got.
+
+use std::convert::Infallible;
+
+use super::{opus, AudioPolicy, MalformedFrame};
+
+/// The F bit of a block header, which is set for every block but the last (primary) one
+const F_BIT: u8 = 0x80;
+
+/// The length of a redundant block's header
+const REDUNDANT_HEADER_LEN: usize = 4;
+
+/// Block lengths are 10 bits
+const MAX_BLOCK_LEN: usize = (1 << 10) - 1;
+
+/// The only header of a RED payload holding a frame that was encrypted whole: the header of a
+/// primary block with payload type 1, which is reserved (RFC 3551 §6)
+const WHOLE_FRAME_HEADER: u8 = 1;
+
+/// Returns the block length in the given redundant block header
+fn block_len(header: &[u8]) -> usize {
+    (usize::from(header[2] & 0b11) << 8) | usize::from(header[3])
+}
+
+/// A parsed RED payload
+struct RedPayload<'a> {
+    /// The headers of the redundant blocks, one after the other
+    redundant_headers: &'a [u8],
+    /// The header of the primary block, i.e., the F bit (unset) and the payload type
+    primary_header: u8,
+    /// The redundant blocks, one after the other
+    redundant_blocks: &'a [u8],
+    primary_block: &'a [u8],
+}
+
+impl<'a> RedPayload<'a> {
+    /// Parses the given frame as a RED payload. Returns `None` if the headers are truncated or the
+    /// blocks are longer than the frame.
+    fn parse(frame: &'a [u8]) -> Option<RedPayload<'a>> {
+        // Read the headers until we reach the one for the primary block
+        let mut headers_len = 0;
+        let mut blocks_len = 0;
+        let primary_header = loop {
+            let &first_byte = frame.get(headers_len)?;
+            if first_byte & F_BIT == 0 {
+                break first_byte;
+            }
+
+            let header = frame.get(headers_len..headers_len + REDUNDANT_HEADER_LEN)?;
+            blocks_len += block_len(header);
+            headers_len += REDUNDANT_HEADER_LEN;
+        };
+
+        // Whatever's left after the redundant blocks is the primary block
+        let (redundant_headers, rest) = frame.split_at(headers_len);
+        let (redundant_blocks, primary_block) = rest[1..].split_at_checked(blocks_len)?;
+        Some(RedPayload {
+            redundant_headers,
+            primary_header,
+            redundant_blocks,
+            primary_block,
+        })
+    }
+
+    /// Returns the header and contents of every redundant block
+    fn redundant_blocks(&self) -> impl Iterator<Item = (&'a [u8], &'a [u8])> {
+        let mut rest = self.redundant_blocks;
+        self.redundant_headers
+            .chunks_exact(REDUNDANT_HEADER_LEN)
+            .map(move |header| {
+                // The lengths were checked when parsing
+                let (block, new_rest) = rest.split_at(block_len(header));
+                rest = new_rest;
+                (header, block)
+            })
+    }
+}
+
+/// Appends the given RED payload to `out`, with every block replaced by what `f` appends to the
+/// buffer it's given. Redundant blocks that come out too long to be described by a block header
+/// are dropped.
+fn write_red_payload<E>(
+    red: &RedPayload,
+    out: &mut Vec<u8>,
+    mut f: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<(), E> {
+    let mut header_start = out.len();
+    out.extend_from_slice(red.redundant_headers);
+    out.push(red.primary_header);
+
+    for (_, block) in red.redundant_blocks() {
+        let block_start = out.len();
+        f(block, out)?;
+        let len = out.len() - block_start;
+        if len <= MAX_BLOCK_LEN {
+            let header = &mut out[header_start..header_start + REDUNDANT_HEADER_LEN];
+            header[2] = (header[2] & !0b11) | (len >> 8) as u8;
+            header[3] = len as u8;
+            header_start += REDUNDANT_HEADER_LEN;
+        } else {
+            out.truncate(block_start);
+            out.drain(header_start..header_start + REDUNDANT_HEADER_LEN);
+        }
+    }
+    f(red.primary_block, out)
+}
+
+/// Appends a RED payload with no redundant blocks and a primary block of silence to `out`. The
+/// primary block's header is taken from the given encrypted frame, whose headers are in the clear.
+/// Returns `false` and appends nothing if the frame was encrypted whole, since then there's no
+/// payload type to give the silence.
+pub(super) fn write_silence(frame: &[u8], out: &mut Vec<u8>) -> bool {
+    if frame.first() == Some(&WHOLE_FRAME_HEADER) {
+        return false;
+    }
+    let Some(red) = RedPayload::parse(frame) else {
+        return false;
+    };
+
+    out.push(red.primary_header);
+    opus::write_silence(out);
+    true
+}
+
+pub(super) fn encrypt_frame(
+    policy: AudioPolicy,
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+) {
+    // If this isn't a RED payload, there's nothing we know to be safe to leave in the clear.
+    // Neither is there if it starts like a frame that was encrypted whole, or the receiver would
+    // take it for one
+    let red = match RedPayload::parse(frame) {
+        Some(red) if frame.first() != Some(&WHOLE_FRAME_HEADER) => red,
+        _ => {
+            out.push(WHOLE_FRAME_HEADER);
+            return encrypt(frame, out);
+        }
+    };
+
+    let res = write_red_payload(&red, out, |block, out| {
+        opus::encrypt_frame(policy, block, out, &mut encrypt);
+        Ok::<_, Infallible>(())
+    });
+    let Ok(()) = res;
+}
+
+pub(super) fn decrypt_frame<E: From<MalformedFrame>>(
+    policy: AudioPolicy,
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<(), E> {
+    if let Some(msg_to_decrypt) = frame.strip_prefix(&[WHOLE_FRAME_HEADER]) {
+        return decrypt(msg_to_decrypt, out);
+    }
+
+    let red = RedPayload::parse(frame).ok_or(MalformedFrame("RED"))?;
+    write_red_payload(&red, out, |block, out| {
+        opus::decrypt_frame(policy, block, out, &mut decrypt)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::test_util::{collect, fake_decrypt, fake_encrypt, try_collect},
+        *,
+    };
+
+    /// Two redundant blocks of lengths 3 and 2, then a primary block, all with payload type 111
+    const RED_FRAME: &[u8] = &[
+        0xef, 0x03, 0xc0, 0x03, // Redundant block header, timestamp offset 240
+        0xef, 0x01, 0xe0, 0x02, // Redundant block header, timestamp offset 120
+        0x6f, // Primary block header
+        0x78, 0x01, 0x02, // Redundant block
+        0x78, 0x03, // Redundant block
+        0x78, 0x04, 0x05, 0x06, // Primary block
+    ];
+
+    #[test]
+    fn roundtrip() {
+        for policy in [AudioPolicy::KeepToc, AudioPolicy::EncryptAll] {
+            let ct = collect(|out| encrypt_frame(policy, RED_FRAME, out, fake_encrypt));
+
+            // The headers are the same, except for the lengths
+            let red = RedPayload::parse(&ct).unwrap();
+            let blocks: Vec<_> = red.redundant_blocks().collect();
+            assert_eq!(blocks.len(), 2);
+            assert_eq!(blocks[0].0[..3], [0xef, 0x03, 0xc0]);
+            assert_eq!(blocks[1].0[..3], [0xef, 0x01, 0xe0]);
+            assert_eq!(red.primary_header, 0x6f);
+            assert_eq!(
+                blocks[1].1,
+                collect(|out| opus::encrypt_frame(policy, &[0x78, 0x03], out, fake_encrypt))
+            );
+
+            assert_eq!(
+                try_collect(|out| decrypt_frame(policy, &ct, out, fake_decrypt)).unwrap(),
+                RED_FRAME
+            );
+        }
+    }
+
+    #[test]
+    fn non_red_roundtrip() {
+        // The block header is truncated, and the second frame starts like one that was encrypted
+        // whole. Both are encrypted whole, behind a header of their own
+        for frame in [&[0xef, 0x03][..], &[WHOLE_FRAME_HEADER, 0x78]] {
+            let ct =
+                collect(|out| encrypt_frame(AudioPolicy::EncryptAll, frame, out, fake_encrypt));
+            assert_eq!(ct[0], WHOLE_FRAME_HEADER);
+            assert_eq!(ct[1..], collect(|out| fake_encrypt(frame, out)));
+            assert_eq!(
+                try_collect(|out| decrypt_frame(AudioPolicy::EncryptAll, &ct, out, fake_decrypt))
+                    .unwrap(),
+                frame
+            );
+            assert!(!write_silence(&ct, &mut Vec::new()));
+        }
+    }
+
+    #[test]
+    fn oversized_blocks_are_dropped() {
+        // Make the ciphertext for the first redundant block too big to fit in a block header
+        let ct = collect(|out| {
+            encrypt_frame(AudioPolicy::EncryptAll, RED_FRAME, out, |pt, out| {
+                if pt == [0x78, 0x01, 0x02] {
+                    out.resize(out.len() + MAX_BLOCK_LEN + 1, 0);
+                } else {
+                    fake_encrypt(pt, out)
+                }
+            })
+        });
+
+        let red = RedPayload::parse(&ct).unwrap();
+        let blocks: Vec<_> = red.redundant_blocks().collect();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].0[..3], [0xef, 0x01, 0xe0]);
+        let pt = try_collect(|out| decrypt_frame(AudioPolicy::EncryptAll, &ct, out, fake_decrypt))
+            .unwrap();
+        assert_eq!(pt, [&RED_FRAME[4..9], &RED_FRAME[12..]].concat());
+    }
+
+    #[test]
+    fn silence() {
+        let ct =
+            collect(|out| encrypt_frame(AudioPolicy::EncryptAll, RED_FRAME, out, fake_encrypt));
+        let silence = collect(|out| assert!(write_silence(&ct, out)));
+
+        let red = RedPayload::parse(&silence).unwrap();
+        assert!(red.redundant_headers.is_empty());
+        assert_eq!(red.primary_header, 0x6f);
+        assert_eq!(red.primary_block, collect(opus::write_silence));
+    }
+
+    #[test]
+    fn malformed() {
+        // Truncated block header
+        assert!(decrypt_frame(
+            AudioPolicy::EncryptAll,
+            &[0xef, 0x03],
+            &mut Vec::new(),
+            fake_decrypt
+        )
+        .is_err());
+        // Block longer than the frame
+        assert!(decrypt_frame(
+            AudioPolicy::EncryptAll,
+            &[0xef, 0x03, 0xc0, 0x10, 0x6f, 0x00],
+            &mut Vec::new(),
+            fake_decrypt
+        )
+        .is_err());
+    }
+}
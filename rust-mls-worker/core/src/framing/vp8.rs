@@ -0,0 +1,112 @@
+//! VP8 framing. The part that's left plain is all or part of the VP8 payload header (1–10 bytes).
+//! The header must be intact in order for the browser's depacketizer to not freak out. A frame
+//! without a header is encrypted whole, behind a [`PLACEHOLDER_HEADER`] that no real header looks
+//! like, so the receiver knows which it got.
+
+use std::ops::Range;
+
+use super::MalformedFrame;
+
+/// The length of a keyframe's header: the frame tag, the start code, and the frame size
+const KEYFRAME_HEADER_LEN: usize = 10;
+/// Where the start code is in a keyframe's header
+const START_CODE_RANGE: Range<usize> = 3..6;
+const START_CODE: [u8; 3] = [0x9d, 0x01, 0x2a];
+
+/// Precedes a frame that was encrypted whole. It's a keyframe's header without a start code
+const PLACEHOLDER_HEADER: [u8; KEYFRAME_HEADER_LEN] = [0; KEYFRAME_HEADER_LEN];
+
+/// Splits the given VP8 frame ("uncompressed data chunk") into a part to leave plain and a part to
+/// encrypt. Returns `None` if the frame doesn't start with a header.
+fn split_header(frame: &[u8]) -> Option<(&[u8], &[u8])> {
+    // We follow https://github.com/discord/dave-protocol/blob/29c431b67a1366223f555a3f66a1f385b17215b9/protocol.md#vp8
+    // If this is a keyframe, keep 10 bytes unencrypted. Otherwise, 1 is enough. The frame type is
+    // the lowest bit of the frame tag, and 0 means keyframe
+    let is_keyframe = frame.first()? & 1 == 0;
+    if !is_keyframe {
+        return Some(frame.split_at(1));
+    }
+    let (header, rest) = frame.split_at_checked(KEYFRAME_HEADER_LEN)?;
+    (header[START_CODE_RANGE] == START_CODE).then_some((header, rest))
+}
+
+pub(super) fn encrypt_frame(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+) {
+    // A frame without a header isn't something the depacketizer can use anyway, so just encrypt
+    // all of it
+    let (header, msg_to_encrypt) = split_header(frame).unwrap_or((&PLACEHOLDER_HEADER, frame));
+    out.extend_from_slice(header);
+    encrypt(msg_to_encrypt, out);
+}
+
+pub(super) fn decrypt_frame<E: From<MalformedFrame>>(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<(), E> {
+    match split_header(frame) {
+        Some((header, msg_to_decrypt)) => {
+            out.extend_from_slice(header);
+            decrypt(msg_to_decrypt, out)
+        }
+        None => {
+            let msg_to_decrypt = frame
+                .strip_prefix(&PLACEHOLDER_HEADER)
+                .ok_or(MalformedFrame("VP8"))?;
+            decrypt(msg_to_decrypt, out)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::test_util::{collect, fake_decrypt, fake_encrypt, try_collect},
+        *,
+    };
+
+    #[test]
+    fn header_sizes() {
+        // Keyframes keep 10 bytes in the clear
+        let keyframe = [0x50, 1, 2, 0x9d, 0x01, 0x2a, 6, 7, 8, 9, 10, 11];
+        let ct = collect(|out| encrypt_frame(&keyframe, out, fake_encrypt));
+        assert_eq!(ct[..10], keyframe[..10]);
+        assert_eq!(
+            try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            keyframe
+        );
+
+        // Delta frames keep 1 byte in the clear
+        let delta_frame = [0x51, 1, 2, 3, 4];
+        let ct = collect(|out| encrypt_frame(&delta_frame, out, fake_encrypt));
+        assert_eq!(ct[..1], delta_frame[..1]);
+        assert_ne!(ct[1..5], delta_frame[1..]);
+        assert_eq!(
+            try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            delta_frame
+        );
+
+        // Empty frames can't be decrypted
+        assert!(decrypt_frame(&[], &mut Vec::new(), fake_decrypt).is_err());
+    }
+
+    #[test]
+    fn headerless_roundtrip() {
+        // A keyframe too short for a header, one without a start code, and an empty frame are all
+        // encrypted whole, behind the placeholder
+        let keyframe = [0x50, 1, 2, 0x9d, 0x01, 0x2a, 6, 7, 8, 9, 10, 11];
+        let mut no_start_code = keyframe;
+        no_start_code[3] = 0;
+        for frame in [&keyframe[..4], &no_start_code, &[]] {
+            let ct = collect(|out| encrypt_frame(frame, out, fake_encrypt));
+            assert_eq!(ct[..KEYFRAME_HEADER_LEN], PLACEHOLDER_HEADER);
+            assert_eq!(
+                try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+                frame
+            );
+        }
+    }
+}
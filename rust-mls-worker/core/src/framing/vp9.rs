@@ -0,0 +1,421 @@
+//! VP9 framing. Like with VP8, we leave the start of the uncompressed header in the clear. For
+//! keyframes and intra-only frames that's everything up to and including the frame size, and for
+//! other frames it's just the first byte or two (frame marker, profile, and frame type).
+//!
+//! A frame might also be a superframe, i.e., several frames followed by an index of their sizes.
+//! Each frame in a superframe is encrypted on its own, and the index is rewritten to hold the
+//! sizes of the encrypted frames. A frame whose header doesn't parse is encrypted whole, behind a
+//! [`WHOLE_FRAME_MARKER`] that no valid header starts with, so the receiver knows which it got.
+
+use super::MalformedFrame;
+
+const FRAME_MARKER: u32 = 0b10;
+const SYNC_CODE: u32 = 0x49_83_42;
+const KEY_FRAME: u32 = 0;
+const CS_RGB: u32 = 7;
+
+/// Precedes a frame that was encrypted whole. Its frame marker is 0, so it can't start a header
+const WHOLE_FRAME_MARKER: u8 = 0;
+
+/// A superframe marker byte looks like `0b110mmfff`, where `mm + 1` is the number of bytes used
+/// for each frame size and `fff + 1` is the number of frames
+const SUPERFRAME_MARKER_MASK: u8 = 0b1110_0000;
+const SUPERFRAME_MARKER: u8 = 0b1100_0000;
+/// `fff` is three bits, so a superframe has at most 8 frames
+const MAX_SUPERFRAME_FRAMES: usize = 8;
+
+/// Reads a bitstream MSB-first
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// The index of the next bit to read
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> BitReader<'a> {
+        BitReader { data, pos: 0 }
+    }
+
+    /// Reads an `n`-bit unsigned integer, where `n <= 32`
+    fn read(&mut self, n: usize) -> Option<u32> {
+        let mut x = 0;
+        for _ in 0..n {
+            let byte = self.data.get(self.pos / 8)?;
+            let bit = (byte >> (7 - self.pos % 8)) & 1;
+            x = (x << 1) | u32::from(bit);
+            self.pos += 1;
+        }
+        Some(x)
+    }
+
+    /// Returns the number of bytes that have been read from, including partially read ones
+    fn bytes_read(&self) -> usize {
+        self.pos.div_ceil(8)
+    }
+}
+
+/// Returns the length of the part of the given frame that's left in the clear. This parses the
+/// uncompressed header (VP9 bitstream spec, section 6.2). Returns `None` if the frame doesn't start
+/// with a valid header.
+fn header_len(frame: &[u8]) -> Option<usize> {
+    let mut r = BitReader::new(frame);
+    if r.read(2)? != FRAME_MARKER {
+        return None;
+    }
+    let profile_low_bit = r.read(1)?;
+    let profile_high_bit = r.read(1)?;
+    let profile = (profile_high_bit << 1) | profile_low_bit;
+    if profile == 3 {
+        // reserved_zero
+        r.read(1)?;
+    }
+
+    // If this frame just shows an existing frame, all there is is frame_to_show_map_idx
+    let show_existing_frame = r.read(1)?;
+    if show_existing_frame == 1 {
+        r.read(3)?;
+        return Some(r.bytes_read());
+    }
+
+    let frame_type = r.read(1)?;
+    let show_frame = r.read(1)?;
+    let error_resilient_mode = r.read(1)?;
+    let is_intra = if frame_type == KEY_FRAME {
+        true
+    } else {
+        let intra_only = show_frame == 0 && r.read(1)? == 1;
+        if error_resilient_mode == 0 {
+            // reset_frame_context
+            r.read(2)?;
+        }
+        intra_only
+    };
+
+    // Inter frames don't say anything else the depacketizer cares about
+    if !is_intra {
+        return Some(r.bytes_read());
+    }
+
+    if r.read(24)? != SYNC_CODE {
+        return None;
+    }
+    if frame_type == KEY_FRAME || profile > 0 {
+        read_color_config(&mut r, profile)?;
+    }
+    if frame_type != KEY_FRAME {
+        // refresh_frame_flags
+        r.read(8)?;
+    }
+    // frame_width_minus_1 and frame_height_minus_1
+    r.read(32)?;
+    // render_and_frame_size_different, followed by the render size if it's set
+    if r.read(1)? == 1 {
+        r.read(32)?;
+    }
+
+    Some(r.bytes_read())
+}
+
+/// Reads past the color_config() syntax element
+fn read_color_config(r: &mut BitReader, profile: u32) -> Option<()> {
+    if profile >= 2 {
+        // ten_or_twelve_bit
+        r.read(1)?;
+    }
+    let color_space = r.read(3)?;
+    let has_subsampling = profile == 1 || profile == 3;
+    if color_space != CS_RGB {
+        // color_range
+        r.read(1)?;
+        if has_subsampling {
+            // subsampling_x, subsampling_y, reserved_zero
+            r.read(3)?;
+        }
+    } else if has_subsampling {
+        // reserved_zero
+        r.read(1)?;
+    }
+    Some(())
+}
+
+fn is_superframe_marker(b: u8) -> bool {
+    b & SUPERFRAME_MARKER_MASK == SUPERFRAME_MARKER
+}
+
+/// The frames of a superframe, along with the number of bytes the index uses for each frame size
+struct Superframe<'a> {
+    /// The frames, of which only the first `num_frames` are set. A superframe has few enough
+    /// frames that they fit in an array, so parsing one doesn't allocate
+    frames: [&'a [u8]; MAX_SUPERFRAME_FRAMES],
+    num_frames: usize,
+    bytes_per_size: usize,
+}
+
+impl<'a> Superframe<'a> {
+    /// Parses the superframe index at the end of `data`, if there is one, and splits `data` into
+    /// frames accordingly. Returns `None` if there's no index or the sizes don't add up.
+    fn parse(data: &'a [u8]) -> Option<Superframe<'a>> {
+        let &marker = data.last()?;
+        if !is_superframe_marker(marker) {
+            return None;
+        }
+        let bytes_per_size = usize::from((marker >> 3) & 0b11) + 1;
+        let num_frames = usize::from(marker & 0b111) + 1;
+
+        // The index is bracketed by two copies of the marker
+        let index_len = 2 + bytes_per_size * num_frames;
+        let index_start = data.len().checked_sub(index_len)?;
+        if data[index_start] != marker {
+            return None;
+        }
+
+        // Read the little-endian frame sizes and cut up the data
+        let mut rest = &data[..index_start];
+        let mut frames = [&[][..]; MAX_SUPERFRAME_FRAMES];
+        let sizes = data[index_start + 1..data.len() - 1].chunks(bytes_per_size);
+        for (frame, size) in frames.iter_mut().zip(sizes) {
+            let size = size
+                .iter()
+                .rev()
+                .fold(0, |acc, &b| (acc << 8) | usize::from(b));
+            (*frame, rest) = rest.split_at_checked(size)?;
+        }
+
+        // Every byte before the index has to belong to a frame
+        rest.is_empty().then_some(Superframe {
+            frames,
+            num_frames,
+            bytes_per_size,
+        })
+    }
+
+    fn frames(&self) -> &[&'a [u8]] {
+        &self.frames[..self.num_frames]
+    }
+}
+
+/// Appends a superframe index for frames of the given sizes to `out`. Frame sizes are written with
+/// at least `min_bytes_per_size` bytes
+fn push_superframe_index(out: &mut Vec<u8>, frame_sizes: &[usize], min_bytes_per_size: usize) {
+    let max_size = frame_sizes.iter().copied().max().unwrap_or(0);
+    let needed_bytes_per_size = (usize::BITS - max_size.leading_zeros()).div_ceil(8) as usize;
+    let bytes_per_size = needed_bytes_per_size.max(min_bytes_per_size);
+    let marker =
+        SUPERFRAME_MARKER | ((bytes_per_size as u8 - 1) << 3) | (frame_sizes.len() as u8 - 1);
+
+    out.push(marker);
+    for size in frame_sizes {
+        out.extend_from_slice(&size.to_le_bytes()[..bytes_per_size]);
+    }
+    out.push(marker);
+}
+
+/// Returns whether `data` ends in a superframe marker followed by any number of zeros. A single
+/// frame that ends like this gets an extra zero, so it isn't mistaken for a superframe.
+fn needs_padding(data: &[u8]) -> bool {
+    data.iter()
+        .rev()
+        .find(|&&b| b != 0)
+        .is_some_and(|&b| is_superframe_marker(b))
+}
+
+/// Encrypts a frame that isn't a superframe, appends it to `out`, and returns its size
+fn encrypt_single_frame(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    encrypt: &mut impl FnMut(&[u8], &mut Vec<u8>),
+) -> usize {
+    let start = out.len();
+    match header_len(frame) {
+        Some(header_len) => {
+            let (header, msg_to_encrypt) = frame.split_at(header_len);
+            out.extend_from_slice(header);
+            encrypt(msg_to_encrypt, out);
+        }
+        // If we can't parse the header, there's nothing we know to be safe to leave in the clear
+        None => {
+            out.push(WHOLE_FRAME_MARKER);
+            encrypt(frame, out);
+        }
+    }
+    out.len() - start
+}
+
+/// Decrypts a frame that isn't a superframe, appends it to `out`, and returns its size
+fn decrypt_single_frame<E: From<MalformedFrame>>(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    decrypt: &mut impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<usize, E> {
+    let start = out.len();
+    match header_len(frame) {
+        Some(header_len) => {
+            let (header, msg_to_decrypt) = frame.split_at(header_len);
+            out.extend_from_slice(header);
+            decrypt(msg_to_decrypt, out)?;
+        }
+        None => {
+            let msg_to_decrypt = frame
+                .strip_prefix(&[WHOLE_FRAME_MARKER])
+                .ok_or(MalformedFrame("VP9"))?;
+            decrypt(msg_to_decrypt, out)?;
+        }
+    }
+    Ok(out.len() - start)
+}
+
+pub(super) fn encrypt_frame(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut encrypt: impl FnMut(&[u8], &mut Vec<u8>),
+) {
+    if let Some(superframe) = Superframe::parse(frame) {
+        let mut sizes = [0; MAX_SUPERFRAME_FRAMES];
+        for (size, f) in sizes.iter_mut().zip(superframe.frames()) {
+            *size = encrypt_single_frame(f, out, &mut encrypt);
+        }
+        push_superframe_index(
+            out,
+            &sizes[..superframe.num_frames],
+            superframe.bytes_per_size,
+        );
+    } else {
+        let start = out.len();
+        encrypt_single_frame(frame, out, &mut encrypt);
+        if needs_padding(&out[start..]) {
+            out.push(0);
+        }
+    }
+}
+
+pub(super) fn decrypt_frame<E: From<MalformedFrame>>(
+    frame: &[u8],
+    out: &mut Vec<u8>,
+    mut decrypt: impl FnMut(&[u8], &mut Vec<u8>) -> Result<(), E>,
+) -> Result<(), E> {
+    if let Some(superframe) = Superframe::parse(frame) {
+        let mut sizes = [0; MAX_SUPERFRAME_FRAMES];
+        for (size, f) in sizes.iter_mut().zip(superframe.frames()) {
+            *size = decrypt_single_frame(f, out, &mut decrypt)?;
+        }
+        push_superframe_index(
+            out,
+            &sizes[..superframe.num_frames],
+            superframe.bytes_per_size,
+        );
+    } else {
+        // Undo the padding from encrypt_frame()
+        let frame = match frame.split_last() {
+            Some((0, rest)) if needs_padding(rest) => rest,
+            _ => frame,
+        };
+        decrypt_single_frame(frame, out, &mut decrypt)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        super::test_util::{collect, fake_decrypt, fake_encrypt, try_collect},
+        *,
+    };
+
+    /// A profile 0 keyframe. The uncompressed header up to the render size is 69 bits long
+    const KEYFRAME: &[u8] = &[
+        0x82, 0x49, 0x83, 0x42, 0x20, 0x13, 0xf0, 0x0e, 0xf0, 0xaa, 0xbb, 0xcc,
+    ];
+
+    /// A shown inter frame
+    const INTER_FRAME: &[u8] = &[0x86, 0x40, 0x11, 0x22, 0x33];
+
+    /// A hidden inter frame
+    const HIDDEN_FRAME: &[u8] = &[0x84, 0x20, 0x44, 0x55];
+
+    #[test]
+    fn header_lens() {
+        assert_eq!(header_len(KEYFRAME), Some(9));
+        assert_eq!(header_len(INTER_FRAME), Some(2));
+        assert_eq!(header_len(HIDDEN_FRAME), Some(2));
+        // show_existing_frame
+        assert_eq!(header_len(&[0x88]), Some(1));
+        // Bad frame marker
+        assert_eq!(header_len(&[0x42, 0x00]), None);
+        // Truncated keyframe
+        assert_eq!(header_len(&KEYFRAME[..6]), None);
+    }
+
+    #[test]
+    fn single_frame_roundtrip() {
+        for frame in [KEYFRAME, INTER_FRAME] {
+            let ct = collect(|out| encrypt_frame(frame, out, fake_encrypt));
+            let header_len = header_len(frame).unwrap();
+            assert_eq!(ct[..header_len], frame[..header_len]);
+            assert_eq!(
+                try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+                frame
+            );
+        }
+    }
+
+    #[test]
+    fn unparsable_roundtrip() {
+        // The frame marker is wrong, so the frame is encrypted whole, behind a marker
+        let frame = [0x42, 0x00];
+        let ct = collect(|out| encrypt_frame(&frame, out, fake_encrypt));
+        assert_eq!(ct[0], WHOLE_FRAME_MARKER);
+        assert_eq!(ct[1..], collect(|out| fake_encrypt(&frame, out)));
+        assert_eq!(
+            try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            frame
+        );
+
+        // A frame with neither a header nor the marker is rejected without decrypting anything
+        let res = decrypt_frame(&[0x42, 0x00], &mut Vec::new(), |_, _| {
+            panic!("decrypted a malformed frame")
+        });
+        assert_eq!(res, Err(MalformedFrame("VP9")));
+    }
+
+    #[test]
+    fn superframe_roundtrip() {
+        let superframe = [
+            HIDDEN_FRAME,
+            INTER_FRAME,
+            &[0xc9, 4, 0, 5, 0, 0xc9], // 2 frames, 2 bytes per size
+        ]
+        .concat();
+        let ct = collect(|out| encrypt_frame(&superframe, out, fake_encrypt));
+
+        // The index has been rewritten, and each frame has its header in the clear
+        let parsed = Superframe::parse(&ct).unwrap();
+        assert_eq!(parsed.frames().len(), 2);
+        assert_eq!(parsed.bytes_per_size, 2);
+        assert_eq!(parsed.frames()[0][..2], HIDDEN_FRAME[..2]);
+        assert_eq!(parsed.frames()[1][..2], INTER_FRAME[..2]);
+
+        assert_eq!(
+            try_collect(|out| decrypt_frame(&ct, out, fake_decrypt)).unwrap(),
+            superframe
+        );
+    }
+
+    #[test]
+    fn marker_lookalike_padding() {
+        // Ciphertexts that end like a superframe index get padded, and the padding is removed
+        for ct_suffix in [&[0xc1][..], &[0xc1, 0], &[0xc1, 0, 0]] {
+            let ct = collect(|out| {
+                encrypt_frame(INTER_FRAME, out, |_, out| out.extend_from_slice(ct_suffix))
+            });
+            assert!(Superframe::parse(&ct).is_none());
+            let pt = try_collect(|out| {
+                decrypt_frame(&ct, out, |ct, out| {
+                    assert_eq!(ct, ct_suffix);
+                    out.extend_from_slice(&INTER_FRAME[2..]);
+                    Ok::<_, MalformedFrame>(())
+                })
+            });
+            assert_eq!(pt.unwrap(), INTER_FRAME);
+        }
+    }
+}
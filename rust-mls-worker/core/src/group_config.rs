@@ -0,0 +1,510 @@
+//! The parameters of an MLS group that every member must agree on: how far the sender ratchets
+//! tolerate frames arriving out of order, how many past epochs are kept, and how frames are padded.
+//! The creator of a group writes them into the group context, and anyone who's welcomed into the
+//! group takes them on in place of its own, rather than silently failing to decrypt half of what it
+//! receives.
+//!
+//! The ciphersuite is negotiated instead. Every user accepts a list of ciphersuites, and shares a
+//! key package for each. The creator of a group makes it in its favorite, and a joining user is
+//! added with its key package in the group's ciphersuite, if it has one. The hybrid post-quantum
+//! ciphersuite is only accepted by those who ask for it.
+
+use openmls::prelude::{
+    Capabilities, Ciphersuite, Extension, ExtensionType, Extensions, KeyPackage,
+    MlsGroupJoinConfig, RequiredCapabilitiesExtension, SenderRatchetConfiguration,
+    UnknownExtension,
+};
+use serde::Deserialize;
+use ts_rs::TS;
+
+use crate::{
+    error::WorkerError,
+    padding::{Padding, PaddingPolicy, MAX_BUCKET_SIZE},
+};
+
+/// The type of the group context extension that holds the group's parameters. Types from 0xF000 up
+/// are for private use (RFC 9420 §17.3). The encoding below has no version, so if it ever changes,
+/// so must this
+const PARAMS_EXTENSION_TYPE: u16 = 0xff0a;
+
+/// The most frames a sender ratchet can be asked to keep keys around for, or to skip ahead by. Each
+/// skipped frame costs a key derivation, so a huge window would let one bogus frame hog the worker
+const MAX_RATCHET_WINDOW: u32 = 10_000;
+
+/// The most past epochs whose keys can be kept. In the SFrame format, at most 15 past epochs can be
+/// told apart anyway
+const MAX_PAST_EPOCHS: usize = 15;
+
+/// The ciphersuites the worker can run a group in, by their names in RFC 9420
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, TS)]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum CiphersuiteName {
+    /// The default, and the fastest wherever there's AES acceleration
+    #[serde(rename = "MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519")]
+    X25519Aes128GcmSha256Ed25519,
+    /// For when only FIPS-approved algorithms will do
+    #[serde(rename = "MLS_128_DHKEMP256_AES128GCM_SHA256_P256")]
+    P256Aes128GcmSha256P256,
+    /// For devices without AES acceleration. This only covers MLS messages: in the SFrame media
+    /// format, frames are still encrypted with AES-128-GCM
+    #[serde(rename = "MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519")]
+    X25519ChaCha20Poly1305Sha256Ed25519,
+    /// Hybrid post-quantum: X-Wing combines X25519 with ML-KEM-768, so recorded calls stay
+    /// confidential even against a future quantum computer. Its key packages are over a kilobyte
+    /// bigger, so it's opt-in. In the SFrame media format, the post-quantum exporter secret keys
+    /// the fixed AES_128_GCM_SHA256_128 SFrame suite, so media gets 128-bit symmetric security, not
+    /// the 256-bit level of the MLS ciphersuite
+    #[serde(rename = "MLS_256_XWING_CHACHA20POLY1305_SHA256_Ed25519")]
+    XWingChaCha20Poly1305Sha256Ed25519,
+}
+
+impl CiphersuiteName {
+    /// Every ciphersuite the worker supports
+    pub const ALL: [CiphersuiteName; 4] = [
+        CiphersuiteName::X25519Aes128GcmSha256Ed25519,
+        CiphersuiteName::X25519ChaCha20Poly1305Sha256Ed25519,
+        CiphersuiteName::P256Aes128GcmSha256P256,
+        CiphersuiteName::XWingChaCha20Poly1305Sha256Ed25519,
+    ];
+
+    /// The ciphersuites accepted unless told otherwise, in order of preference. That's all of them
+    /// but the post-quantum one
+    pub const DEFAULT: [CiphersuiteName; 3] = [
+        CiphersuiteName::X25519Aes128GcmSha256Ed25519,
+        CiphersuiteName::X25519ChaCha20Poly1305Sha256Ed25519,
+        CiphersuiteName::P256Aes128GcmSha256P256,
+    ];
+}
+
+impl From<CiphersuiteName> for Ciphersuite {
+    fn from(name: CiphersuiteName) -> Ciphersuite {
+        match name {
+            CiphersuiteName::X25519Aes128GcmSha256Ed25519 => {
+                Ciphersuite::MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519
+            }
+            CiphersuiteName::P256Aes128GcmSha256P256 => {
+                Ciphersuite::MLS_128_DHKEMP256_AES128GCM_SHA256_P256
+            }
+            CiphersuiteName::X25519ChaCha20Poly1305Sha256Ed25519 => {
+                Ciphersuite::MLS_128_DHKEMX25519_CHACHA20POLY1305_SHA256_Ed25519
+            }
+            CiphersuiteName::XWingChaCha20Poly1305Sha256Ed25519 => {
+                Ciphersuite::MLS_256_XWING_CHACHA20POLY1305_SHA256_Ed25519
+            }
+        }
+    }
+}
+
+/// The parameters everyone in a group must agree on, along with the ciphersuites this user accepts
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct GroupConfig {
+    /// The ciphersuites this user can be in a group in, in order of preference. This user shares a
+    /// key package for each, and a group it creates is in the first
+    pub ciphersuites: Vec<CiphersuiteName>,
+    /// How many frames older than the newest one from the same sender can still be decrypted. In
+    /// the SFrame format, each sender's replay window covers this many frames
+    pub out_of_order_tolerance: u32,
+    /// How many frames ahead of the newest one from the same sender can still be decrypted
+    pub max_message_seq_jump: u32,
+    /// How many past epochs' keys are kept around to decrypt frames that were still in flight when
+    /// the epoch changed. In the SFrame format, at most 15 past epochs can be told apart
+    pub max_past_epochs: usize,
+    /// How the encrypted parts of frames are padded
+    pub padding: PaddingPolicy,
+}
+
+impl Default for GroupConfig {
+    fn default() -> GroupConfig {
+        GroupConfig {
+            ciphersuites: CiphersuiteName::DEFAULT.to_vec(),
+            // Permit decryption of frames that are up to 500 frames old (for that sender)
+            out_of_order_tolerance: 500,
+            // Permit decryption of frames from up to 1000 frames in the future (for that sender).
+            // 1000 is OpenMLS's default
+            max_message_seq_jump: 1000,
+            max_past_epochs: 0,
+            padding: PaddingPolicy::default(),
+        }
+    }
+}
+
+impl GroupConfig {
+    /// Fails if any parameter is out of range
+    pub fn validate(&self) -> Result<(), WorkerError> {
+        let invalid = |msg: String| Err(WorkerError::InvalidGroupConfig(msg));
+        if self.ciphersuites.is_empty() {
+            return invalid("no ciphersuites".to_string());
+        }
+        for (i, suite) in self.ciphersuites.iter().enumerate() {
+            if self.ciphersuites[..i].contains(suite) {
+                return invalid(format!("ciphersuite {suite:?} is listed twice"));
+            }
+        }
+        if self.out_of_order_tolerance > MAX_RATCHET_WINDOW {
+            return invalid(format!(
+                "out-of-order tolerance {} is over {MAX_RATCHET_WINDOW}",
+                self.out_of_order_tolerance
+            ));
+        }
+        if !(1..=MAX_RATCHET_WINDOW).contains(&self.max_message_seq_jump) {
+            return invalid(format!(
+                "max message sequence jump {} is not between 1 and {MAX_RATCHET_WINDOW}",
+                self.max_message_seq_jump
+            ));
+        }
+        if self.max_past_epochs > MAX_PAST_EPOCHS {
+            return invalid(format!(
+                "max past epochs {} is over {MAX_PAST_EPOCHS}",
+                self.max_past_epochs
+            ));
+        }
+        for padding in [self.padding.audio, self.padding.video] {
+            if let Padding::Bucket(size) = padding {
+                if size.get() > MAX_BUCKET_SIZE {
+                    return invalid(format!(
+                        "padding bucket size {size} is over {MAX_BUCKET_SIZE}"
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ciphersuite of a group this user creates
+    pub(crate) fn preferred_ciphersuite(&self) -> Ciphersuite {
+        self.ciphersuites[0].into()
+    }
+
+    /// Returns the sender ratchet configuration of the group
+    pub(crate) fn sender_ratchet(&self) -> SenderRatchetConfiguration {
+        SenderRatchetConfiguration::new(self.out_of_order_tolerance, self.max_message_seq_jump)
+    }
+
+    /// Returns the configuration of this user's state in a group it joins
+    pub(crate) fn join_config(&self) -> MlsGroupJoinConfig {
+        // Permit decryption of old frames, including ones from past epochs
+        MlsGroupJoinConfig::builder()
+            .sender_ratchet_configuration(self.sender_ratchet())
+            .max_past_epochs(self.max_past_epochs)
+            .build()
+    }
+
+    /// Returns the capabilities of this user's leaf node, which say which ciphersuites it accepts,
+    /// and that it understands the group's parameters
+    pub(crate) fn capabilities(&self) -> Capabilities {
+        let ciphersuites: Vec<Ciphersuite> = self.ciphersuites.iter().map(|&c| c.into()).collect();
+        Capabilities::new(
+            None,
+            Some(&ciphersuites),
+            Some(&[ExtensionType::Unknown(PARAMS_EXTENSION_TYPE)]),
+            None,
+            None,
+        )
+    }
+
+    /// Returns the group context extensions of a group with these parameters. Members are required
+    /// to understand the parameters, so users too old to check them can't be added
+    pub(crate) fn group_context_extensions(&self) -> Extensions {
+        Extensions::from_vec(vec![
+            Extension::RequiredCapabilities(RequiredCapabilitiesExtension::new(
+                &[ExtensionType::Unknown(PARAMS_EXTENSION_TYPE)],
+                &[],
+                &[],
+            )),
+            Extension::Unknown(PARAMS_EXTENSION_TYPE, UnknownExtension(self.encode())),
+        ])
+        .expect("group parameters are distinct extensions")
+    }
+
+    /// Takes on the parameters of the group with the given ciphersuite and group context extensions,
+    /// since a user who's welcomed into a group can't pick its own. Fails, leaving these parameters
+    /// as they are, unless the group is in a ciphersuite this user accepts and has valid parameters
+    pub(crate) fn adopt_group(
+        &mut self,
+        ciphersuite: Ciphersuite,
+        extensions: &Extensions,
+    ) -> Result<(), WorkerError> {
+        let mismatch = |msg: String| Err(WorkerError::GroupConfigMismatch(msg));
+        if !self.ciphersuites.iter().any(|&c| ciphersuite == c.into()) {
+            return mismatch(format!(
+                "group uses {ciphersuite:?}, which isn't one of {:?}",
+                self.ciphersuites
+            ));
+        }
+        let Some(UnknownExtension(params)) = extensions.unknown(PARAMS_EXTENSION_TYPE) else {
+            return mismatch("group has no parameters".to_string());
+        };
+        let Some(params) = GroupParams::decode(params) else {
+            return mismatch("group has malformed parameters".to_string());
+        };
+
+        // The group's creator could have been talked into anything, so its parameters are held to
+        // the same limits as this user's own
+        let adopted = GroupConfig {
+            ciphersuites: self.ciphersuites.clone(),
+            out_of_order_tolerance: params.out_of_order_tolerance,
+            max_message_seq_jump: params.max_message_seq_jump,
+            max_past_epochs: params.max_past_epochs as usize,
+            padding: params.padding,
+        };
+        if let Err(WorkerError::InvalidGroupConfig(msg)) = adopted.validate() {
+            return mismatch(format!("group has invalid parameters: {msg}"));
+        }
+        *self = adopted;
+        Ok(())
+    }
+
+    /// Encodes the parameters that go in the group context. The ciphersuite is already there
+    fn encode(&self) -> Vec<u8> {
+        GroupParams::from(self).encode()
+    }
+}
+
+/// Returns whether the given key package can be added to a group in the given ciphersuite: it must
+/// be made for that ciphersuite, and its owner must understand the group's parameters
+pub(crate) fn fits_group(kp: &KeyPackage, ciphersuite: Ciphersuite) -> bool {
+    kp.ciphersuite() == ciphersuite
+        && kp
+            .leaf_node()
+            .capabilities()
+            .extensions()
+            .contains(&ExtensionType::Unknown(PARAMS_EXTENSION_TYPE))
+}
+
+/// The parameters of a [`GroupConfig`] that go in the group context extension
+#[derive(Debug, PartialEq, Eq)]
+struct GroupParams {
+    out_of_order_tolerance: u32,
+    max_message_seq_jump: u32,
+    max_past_epochs: u32,
+    padding: PaddingPolicy,
+}
+
+impl From<&GroupConfig> for GroupParams {
+    fn from(config: &GroupConfig) -> GroupParams {
+        GroupParams {
+            out_of_order_tolerance: config.out_of_order_tolerance,
+            max_message_seq_jump: config.max_message_seq_jump,
+            // Valid configs keep no more than MAX_PAST_EPOCHS
+            max_past_epochs: config.max_past_epochs as u32,
+            padding: config.padding,
+        }
+    }
+}
+
+impl GroupParams {
+    /// The tags of the paddings in the encoding. A bucket's tag is followed by its size
+    const PADDING_NONE: u8 = 0;
+    const PADDING_PADME: u8 = 1;
+    const PADDING_BUCKET: u8 = 2;
+
+    /// Encodes the parameters as big-endian integers, in the order they're declared in. A padding
+    /// is a tag, followed by a 64-bit bucket size if it's a bucket
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(30);
+        out.extend_from_slice(&self.out_of_order_tolerance.to_be_bytes());
+        out.extend_from_slice(&self.max_message_seq_jump.to_be_bytes());
+        out.extend_from_slice(&self.max_past_epochs.to_be_bytes());
+        for padding in [self.padding.audio, self.padding.video] {
+            match padding {
+                Padding::None => out.push(Self::PADDING_NONE),
+                Padding::Padme => out.push(Self::PADDING_PADME),
+                Padding::Bucket(size) => {
+                    out.push(Self::PADDING_BUCKET);
+                    out.extend_from_slice(&(size.get() as u64).to_be_bytes());
+                }
+            }
+        }
+        out
+    }
+
+    /// Decodes parameters encoded by [`GroupParams::encode`]. Returns `None` if they're malformed
+    fn decode(mut bytes: &[u8]) -> Option<GroupParams> {
+        fn take<const N: usize>(bytes: &mut &[u8]) -> Option<[u8; N]> {
+            let (taken, rest) = bytes.split_first_chunk()?;
+            *bytes = rest;
+            Some(*taken)
+        }
+        fn take_padding(bytes: &mut &[u8]) -> Option<Padding> {
+            match take::<1>(bytes)?[0] {
+                GroupParams::PADDING_NONE => Some(Padding::None),
+                GroupParams::PADDING_PADME => Some(Padding::Padme),
+                GroupParams::PADDING_BUCKET => {
+                    let size = u64::from_be_bytes(take(bytes)?);
+                    usize::try_from(size)
+                        .ok()
+                        .filter(|&size| size <= MAX_BUCKET_SIZE)
+                        .and_then(std::num::NonZeroUsize::new)
+                        .map(Padding::Bucket)
+                }
+                _ => None,
+            }
+        }
+
+        let params = GroupParams {
+            out_of_order_tolerance: u32::from_be_bytes(take(&mut bytes)?),
+            max_message_seq_jump: u32::from_be_bytes(take(&mut bytes)?),
+            max_past_epochs: u32::from_be_bytes(take(&mut bytes)?),
+            padding: PaddingPolicy {
+                audio: take_padding(&mut bytes)?,
+                video: take_padding(&mut bytes)?,
+            },
+        };
+        bytes.is_empty().then_some(params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+
+    #[test]
+    fn validation() {
+        assert_eq!(GroupConfig::default().validate(), Ok(()));
+
+        let cases = [
+            GroupConfig {
+                ciphersuites: Vec::new(),
+                ..Default::default()
+            },
+            GroupConfig {
+                ciphersuites: vec![
+                    CiphersuiteName::P256Aes128GcmSha256P256,
+                    CiphersuiteName::X25519Aes128GcmSha256Ed25519,
+                    CiphersuiteName::P256Aes128GcmSha256P256,
+                ],
+                ..Default::default()
+            },
+            GroupConfig {
+                out_of_order_tolerance: MAX_RATCHET_WINDOW + 1,
+                ..Default::default()
+            },
+            GroupConfig {
+                max_message_seq_jump: 0,
+                ..Default::default()
+            },
+            GroupConfig {
+                max_message_seq_jump: MAX_RATCHET_WINDOW + 1,
+                ..Default::default()
+            },
+            GroupConfig {
+                max_past_epochs: MAX_PAST_EPOCHS + 1,
+                ..Default::default()
+            },
+            GroupConfig {
+                padding: PaddingPolicy {
+                    audio: Padding::None,
+                    video: Padding::Bucket(NonZeroUsize::new(MAX_BUCKET_SIZE + 1).unwrap()),
+                },
+                ..Default::default()
+            },
+        ];
+        for config in cases {
+            assert!(
+                matches!(config.validate(), Err(WorkerError::InvalidGroupConfig(_))),
+                "{config:?} is valid"
+            );
+        }
+    }
+
+    #[test]
+    fn params_roundtrip() {
+        let config = GroupConfig {
+            ciphersuites: vec![CiphersuiteName::X25519ChaCha20Poly1305Sha256Ed25519],
+            out_of_order_tolerance: 100,
+            max_message_seq_jump: 2000,
+            max_past_epochs: 3,
+            padding: PaddingPolicy {
+                audio: Padding::Bucket(NonZeroUsize::new(32).unwrap()),
+                video: Padding::Padme,
+            },
+        };
+        let params = GroupParams::from(&config);
+        assert_eq!(GroupParams::decode(&params.encode()), Some(params));
+
+        let encoded = config.encode();
+        for len in 0..encoded.len() {
+            assert_eq!(GroupParams::decode(&encoded[..len]), None);
+        }
+        let mut trailing = encoded.clone();
+        trailing.push(0);
+        assert_eq!(GroupParams::decode(&trailing), None);
+
+        // A bucket size from the wire is bounded like one from the main thread
+        let huge_bucket = GroupParams {
+            padding: PaddingPolicy::uniform(Padding::Bucket(
+                NonZeroUsize::new(MAX_BUCKET_SIZE + 1).unwrap(),
+            )),
+            ..GroupParams::from(&config)
+        };
+        assert_eq!(GroupParams::decode(&huge_bucket.encode()), None);
+    }
+
+    #[test]
+    fn adopt_group() {
+        let config = GroupConfig::default();
+        let extensions = config.group_context_extensions();
+        let ciphersuite = config.preferred_ciphersuite();
+        let mut adopted = config.clone();
+        assert_eq!(adopted.adopt_group(ciphersuite, &extensions), Ok(()));
+        assert_eq!(adopted, config);
+
+        // Any ciphersuite the user accepts will do, not just its favorite
+        let fips_only = GroupConfig {
+            ciphersuites: vec![CiphersuiteName::P256Aes128GcmSha256P256],
+            ..config.clone()
+        };
+        assert_eq!(
+            config
+                .clone()
+                .adopt_group(fips_only.preferred_ciphersuite(), &extensions),
+            Ok(())
+        );
+        assert!(matches!(
+            fips_only.clone().adopt_group(ciphersuite, &extensions),
+            Err(WorkerError::GroupConfigMismatch(_))
+        ));
+
+        // The group's parameters win, but the user keeps its own ciphersuites
+        let other_params = GroupConfig {
+            max_past_epochs: 3,
+            padding: PaddingPolicy::uniform(Padding::Padme),
+            ..config.clone()
+        };
+        let mut adopted = GroupConfig {
+            ciphersuites: CiphersuiteName::ALL.to_vec(),
+            ..config.clone()
+        };
+        assert_eq!(
+            adopted.adopt_group(ciphersuite, &other_params.group_context_extensions()),
+            Ok(())
+        );
+        assert_eq!(
+            adopted,
+            GroupConfig {
+                ciphersuites: CiphersuiteName::ALL.to_vec(),
+                ..other_params
+            }
+        );
+
+        // Parameters that are missing, or out of range, are refused
+        let mut unchanged = config.clone();
+        assert!(matches!(
+            unchanged.adopt_group(ciphersuite, &Extensions::empty()),
+            Err(WorkerError::GroupConfigMismatch(_))
+        ));
+        let out_of_range = GroupConfig {
+            max_message_seq_jump: MAX_RATCHET_WINDOW + 1,
+            ..config.clone()
+        };
+        let Err(WorkerError::GroupConfigMismatch(msg)) =
+            unchanged.adopt_group(ciphersuite, &out_of_range.group_context_extensions())
+        else {
+            panic!("out-of-range parameters were adopted");
+        };
+        assert!(msg.contains("sequence jump"), "{msg}");
+        assert_eq!(unchanged, config);
+    }
+}
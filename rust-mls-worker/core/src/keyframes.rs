@@ -0,0 +1,95 @@
+//! When to ask for keyframes. A video decoder can't make use of frames until it has a keyframe to
+//! go from, so a receiver whose frames failed to decrypt asks the sender for a new one, and a sender
+//! that moved to a new epoch makes one, since members who just joined couldn't decrypt the frames
+//! from before. Frames tend to fail in bursts, so these requests are rate-limited.
+
+use std::time::Duration;
+
+use web_time::Instant;
+
+/// The minimum time between two keyframe requests of a stream
+const MIN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Tracks one stream of frames, and decides when it needs a keyframe. A keyframe that's needed while
+/// the rate limit is in effect isn't forgotten, but asked for as soon as the limit allows
+#[derive(Default)]
+pub struct KeyFrameTrigger {
+    /// The epoch the last frame was encrypted in. This is only used by sending streams
+    last_epoch: Option<u64>,
+    /// Whether a keyframe is needed but hasn't been asked for yet
+    pending: bool,
+    /// When a keyframe was last asked for
+    last_request: Option<Instant>,
+}
+
+impl KeyFrameTrigger {
+    /// Notes that a frame was encrypted at time `now`, in the given epoch if there is one. Returns
+    /// whether the sender should make a keyframe
+    pub fn encrypted(&mut self, epoch: Option<u64>, now: Instant) -> bool {
+        if epoch.is_some() && epoch != self.last_epoch {
+            self.pending = true;
+        }
+        self.last_epoch = epoch;
+        self.poll(now)
+    }
+
+    /// Notes whether a frame that arrived at time `now` was decrypted. Returns whether the receiver
+    /// should ask the sender for a keyframe
+    pub fn decrypted(&mut self, ok: bool, now: Instant) -> bool {
+        if !ok {
+            self.pending = true;
+        }
+        self.poll(now)
+    }
+
+    /// Returns whether a keyframe is needed and the rate limit allows asking for it. If so, it
+    /// counts as asked for
+    fn poll(&mut self, now: Instant) -> bool {
+        if !self.pending || self.last_request.is_some_and(|t| now < t + MIN_INTERVAL) {
+            return false;
+        }
+
+        self.pending = false;
+        self.last_request = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn epoch_changes() {
+        let start = Instant::now();
+        let mut trigger = KeyFrameTrigger::default();
+
+        // Nothing's needed before there's a group, and a keyframe is needed once there is one
+        assert!(!trigger.encrypted(None, start));
+        assert!(trigger.encrypted(Some(1), start));
+        assert!(!trigger.encrypted(Some(1), start + MIN_INTERVAL));
+
+        // The epoch changes right after the last request, so the keyframe waits for the rate limit
+        let changed = start + MIN_INTERVAL * 3;
+        assert!(trigger.encrypted(Some(2), changed));
+        assert!(!trigger.encrypted(Some(3), changed));
+        assert!(!trigger.encrypted(Some(3), changed + MIN_INTERVAL / 2));
+        assert!(trigger.encrypted(Some(3), changed + MIN_INTERVAL));
+        assert!(!trigger.encrypted(Some(3), changed + MIN_INTERVAL * 2));
+    }
+
+    #[test]
+    fn decryption_failures() {
+        let start = Instant::now();
+        let mut trigger = KeyFrameTrigger::default();
+
+        assert!(!trigger.decrypted(true, start));
+        // A burst of failures makes one request
+        assert!(trigger.decrypted(false, start));
+        assert!(!trigger.decrypted(false, start));
+        assert!(!trigger.decrypted(true, start + MIN_INTERVAL / 2));
+        // The failures during the rate limit make another once it's over
+        assert!(trigger.decrypted(true, start + MIN_INTERVAL));
+        assert!(!trigger.decrypted(true, start + MIN_INTERVAL * 3));
+    }
+}
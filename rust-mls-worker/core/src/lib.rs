@@ -0,0 +1,14 @@
+//! The platform-independent part of Orange Meets' end-to-end encryption: MLS group management, the
+//! SFrame encryption of media frames, and the codec-aware framing that decides which bytes of a
+//! frame stay in the clear. Nothing here knows about the browser, so it builds and tests natively.
+//! The WASM worker that drives it lives in `orange-mls-wasm`.
+
+pub mod diagnostics;
+pub mod error;
+pub mod framing;
+pub mod group_config;
+pub mod keyframes;
+pub mod mls_ops;
+pub mod padding;
+mod provider;
+mod sframe;
@@ -0,0 +1,3001 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    iter, slice,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use arc_swap::ArcSwapOption;
+use log::{debug, warn};
+use openmls::{
+    group::{MlsGroup, MlsGroupCreateConfig, ProcessMessageError, StagedWelcome},
+    prelude::{
+        tls_codec::Serialize as _, BasicCredential, Ciphersuite, CredentialWithKey,
+        DeserializeBytes, KeyPackage, KeyPackageBundle, KeyPackageIn, LeafNodeIndex,
+        MlsMessageBodyIn, MlsMessageIn, MlsMessageOut, OpenMlsProvider, ProcessedMessageContent,
+        ProtocolVersion, RatchetTreeIn,
+    },
+    treesync::RatchetTree,
+};
+use openmls_basic_credential::SignatureKeyPair;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use ts_rs::TS;
+use web_time::Instant;
+
+use crate::{
+    diagnostics::{Diagnostic, DiagnosticEvent, Diagnostics},
+    error::WorkerError,
+    framing::{AudioPolicy, Codec, FrameKind, MalformedFrame},
+    group_config::{fits_group, GroupConfig},
+    padding::InvalidPadding,
+    provider::MlsProvider,
+    sframe::{EpochKeys, SFrameError},
+};
+
+const PROT_VERSION: ProtocolVersion = ProtocolVersion::Mls10;
+
+pub type SafetyNumber = [u8; 32];
+
+/// Error incurred when attempting to decrypt an app message (in our case, an encrypted frame from
+/// a video/audio stream)
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum DecryptAppMsgError {
+    #[error(transparent)]
+    Mls(#[from] openmls::prelude::Error),
+
+    #[error(transparent)]
+    Processing(
+        #[from] openmls::prelude::ProcessMessageError<openmls_rust_crypto::MemoryStorageError>,
+    ),
+
+    #[error(transparent)]
+    Framing(#[from] MalformedFrame),
+
+    #[error(transparent)]
+    SFrame(#[from] SFrameError),
+
+    #[error(transparent)]
+    Padding(#[from] InvalidPadding),
+
+    #[error("Not in a group, so decryption does not make sense")]
+    NoGroup,
+
+    #[error("Wrong message type: {0}")]
+    WrongMsgType(&'static str),
+
+    #[error("Frame was encrypted for a different track")]
+    WrongTrack,
+}
+
+/// How the encrypted parts of media frames are protected. Everyone in a room must use the same
+/// format
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum MediaFormat {
+    /// Every encrypted part is an MLS application message, signed by its sender. Only the MLS
+    /// group can make and open these, so every frame waits on the session's state, and a slow
+    /// Commit or Welcome holds up media
+    Mls,
+    /// The default. Every encrypted part is an SFrame ciphertext, keyed from the MLS group. This is
+    /// much cheaper per frame, and frames are protected with a snapshot of the epoch's keys, so
+    /// they never wait on handshake processing. But frames aren't signed by their sender, and
+    /// they're always encrypted with AES-128-GCM, whatever the group's ciphersuite
+    #[default]
+    #[serde(rename = "sframe")]
+    SFrame,
+}
+
+/// What a sender sends in place of a frame under [`PreGroupPolicy::Marked`]. Every ciphertext is at
+/// least as long as an SFrame tag or an MLS message, so no encrypted frame is ever this short
+const NOT_KEYED_FRAME: &[u8] = b"not keyed";
+
+/// What to send in place of frames before this user is in a group, when there are no keys to
+/// encrypt them with
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum PreGroupPolicy {
+    /// Don't send frames at all
+    #[default]
+    Hold,
+    /// Send empty frames
+    Empty,
+    /// Send a marker frame, which receivers recognize and skip without counting it as a failure
+    Marked,
+}
+
+impl PreGroupPolicy {
+    /// Applies this policy to a frame that can't be encrypted yet, by appending what to send in its
+    /// place to `out`
+    fn apply(self, out: &mut Vec<u8>) -> EncryptOutcome {
+        match self {
+            PreGroupPolicy::Hold => EncryptOutcome::Held,
+            PreGroupPolicy::Empty => EncryptOutcome::NotKeyed,
+            PreGroupPolicy::Marked => {
+                out.extend_from_slice(NOT_KEYED_FRAME);
+                EncryptOutcome::NotKeyed
+            }
+        }
+    }
+}
+
+/// What became of a frame that was to be encrypted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncryptOutcome {
+    /// The frame was encrypted in the given epoch
+    Encrypted(u64),
+    /// There are no keys yet, and the frame shouldn't be sent
+    Held,
+    /// There are no keys yet, and an unencrypted stand-in is sent in the frame's place
+    NotKeyed,
+}
+
+impl EncryptOutcome {
+    /// Returns whether there's a frame to send
+    pub fn has_frame(self) -> bool {
+        self != EncryptOutcome::Held
+    }
+
+    /// Returns the epoch the frame was encrypted in, if it was
+    pub fn epoch(self) -> Option<u64> {
+        match self {
+            EncryptOutcome::Encrypted(epoch) => Some(epoch),
+            EncryptOutcome::Held | EncryptOutcome::NotKeyed => None,
+        }
+    }
+}
+
+/// What to do with a frame that can't be decrypted
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum DecryptFailurePolicy {
+    /// Don't pass the frame on at all
+    #[default]
+    Drop,
+    /// Pass the frame on as it came in. This is for rooms where not everyone encrypts
+    Passthrough,
+    /// Pass on a frame that the decoder can take in its place, i.e., silence for audio. Nothing can
+    /// stand in for a video frame, so video frames are dropped
+    Conceal,
+}
+
+impl DecryptFailurePolicy {
+    /// Applies this policy to the given frame of the given codec, which couldn't be decrypted, by
+    /// appending what to pass on in its place to `out`
+    fn apply(self, codec: Codec, ct: &[u8], out: &mut Vec<u8>) -> DecryptOutcome {
+        match self {
+            DecryptFailurePolicy::Passthrough => {
+                out.extend_from_slice(ct);
+                DecryptOutcome::PassedThrough
+            }
+            DecryptFailurePolicy::Conceal if codec.write_concealment_frame(ct, out) => {
+                DecryptOutcome::Concealed
+            }
+            DecryptFailurePolicy::Drop | DecryptFailurePolicy::Conceal => DecryptOutcome::Dropped,
+        }
+    }
+}
+
+/// What became of a frame that was to be decrypted
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecryptOutcome {
+    Decrypted,
+    /// The frame couldn't be decrypted, and shouldn't be passed on
+    Dropped,
+    /// The frame couldn't be decrypted, and is passed on as it came in
+    PassedThrough,
+    /// The frame couldn't be decrypted, and a concealment frame is passed on in its place
+    Concealed,
+    /// The frame is a marker from a sender who isn't in the group yet, and isn't passed on
+    NotKeyed,
+}
+
+impl DecryptOutcome {
+    /// Returns whether there's a frame to pass on
+    pub fn has_frame(self) -> bool {
+        !matches!(self, DecryptOutcome::Dropped | DecryptOutcome::NotKeyed)
+    }
+
+    /// Returns whether the frame couldn't be decrypted. A marker from a sender who isn't in the
+    /// group yet isn't a failure, since there was nothing to decrypt
+    pub fn is_failure(self) -> bool {
+        !matches!(self, DecryptOutcome::Decrypted | DecryptOutcome::NotKeyed)
+    }
+
+    /// Returns whether the frame was lost to the decoder, so a keyframe is needed to recover. A frame
+    /// that's passed through isn't: under [`DecryptFailurePolicy::Passthrough`], that's every frame
+    /// from a sender who doesn't encrypt, and no keyframe would change that
+    pub fn needs_key_frame(self) -> bool {
+        matches!(self, DecryptOutcome::Dropped | DecryptOutcome::Concealed)
+    }
+}
+
+/// How many frames had each outcome of decryption since the worker was initialized
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub struct DecryptCounts {
+    #[ts(type = "number")]
+    pub decrypted: u64,
+    #[ts(type = "number")]
+    pub dropped: u64,
+    #[ts(type = "number")]
+    pub passed_through: u64,
+    #[ts(type = "number")]
+    pub concealed: u64,
+    /// Markers from senders who weren't in the group yet
+    #[ts(type = "number")]
+    pub not_keyed: u64,
+}
+
+/// Running [`DecryptCounts`], which every stream counts its frames into without locking
+struct DecryptStats {
+    decrypted: AtomicU64,
+    dropped: AtomicU64,
+    passed_through: AtomicU64,
+    concealed: AtomicU64,
+    not_keyed: AtomicU64,
+}
+
+impl DecryptStats {
+    const fn new() -> DecryptStats {
+        DecryptStats {
+            decrypted: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+            passed_through: AtomicU64::new(0),
+            concealed: AtomicU64::new(0),
+            not_keyed: AtomicU64::new(0),
+        }
+    }
+
+    fn counter(&self, outcome: DecryptOutcome) -> &AtomicU64 {
+        match outcome {
+            DecryptOutcome::Decrypted => &self.decrypted,
+            DecryptOutcome::Dropped => &self.dropped,
+            DecryptOutcome::PassedThrough => &self.passed_through,
+            DecryptOutcome::Concealed => &self.concealed,
+            DecryptOutcome::NotKeyed => &self.not_keyed,
+        }
+    }
+
+    fn record(&self, outcome: DecryptOutcome) {
+        self.counter(outcome).fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn counts(&self) -> DecryptCounts {
+        DecryptCounts {
+            decrypted: self.decrypted.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            passed_through: self.passed_through.load(Ordering::Relaxed),
+            concealed: self.concealed.load(Ordering::Relaxed),
+            not_keyed: self.not_keyed.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How media frames are encrypted. The audio policy, media format, and group parameters must be
+/// the same for everyone in the room
+#[derive(Clone, Debug, Default)]
+pub struct MediaConfig {
+    /// What to leave in the clear in audio frames
+    pub audio_policy: AudioPolicy,
+    /// How the encrypted parts of frames are protected
+    pub media_format: MediaFormat,
+    /// The parameters of the MLS group, including padding and past-epoch retention. The group's
+    /// creator pins them in the group, and nobody with different ones can join it
+    pub group: GroupConfig,
+    /// How long to keep sending frames in the previous epoch after the epoch changes, so that
+    /// receivers have time to process the Commit. Members who just joined can't decrypt these
+    /// frames. This only applies to Commits that just add members: after a Commit that removes
+    /// anyone, the previous epoch's keys are still held by whoever was removed, so frames are sent
+    /// in the new epoch right away. This also only applies to the SFrame format, since MLS
+    /// application messages are always made in the group's current epoch, and only if past epochs
+    /// are kept
+    pub sender_delay: Duration,
+    /// What to do with frames that can't be decrypted
+    pub decrypt_failure_policy: DecryptFailurePolicy,
+    /// What to send before this user is in a group
+    pub pre_group_policy: PreGroupPolicy,
+}
+
+impl MediaConfig {
+    /// Returns the codec that a frame of the given kind is framed with. `mime_type` is the codec
+    /// the frame's metadata says it's in, if any. Video frames fall back to `stream_codec`, the
+    /// codec their stream was set up with, and audio frames are Opus, possibly wrapped in RED
+    pub fn codec_for(
+        &self,
+        kind: FrameKind,
+        mime_type: Option<&str>,
+        stream_codec: Codec,
+    ) -> Codec {
+        match kind {
+            FrameKind::Audio if mime_type.is_some_and(|m| m.eq_ignore_ascii_case("audio/red")) => {
+                Codec::Red(self.audio_policy)
+            }
+            FrameKind::Audio => Codec::Opus(self.audio_policy),
+            FrameKind::Video => mime_type
+                .and_then(Codec::from_mime_type)
+                .unwrap_or(stream_codec),
+        }
+    }
+}
+
+/// An immutable snapshot of everything needed to protect media frames in the SFrame format in one
+/// epoch. Handshake processing makes a new one whenever the epoch changes, and publishes it in
+/// its session, so frames never wait on the MLS group. Frames in the MLS format are MLS
+/// application messages, which only the group can make, so those can't use a snapshot.
+pub(crate) struct MediaKeys {
+    config: MediaConfig,
+    sframe: Arc<EpochKeys>,
+    /// The keys of the epochs before this one, newest first, for frames still in flight. These are
+    /// shared with the snapshots of those epochs
+    past_sframe: Vec<Arc<EpochKeys>>,
+    /// Until this time, frames are sent in the previous epoch
+    switch_at: Instant,
+}
+
+impl MediaKeys {
+    /// Makes the snapshot for the current epoch of the given group. `prev` is the snapshot of the
+    /// epoch before, if any, whose keys are retained according to the config. If `delay_switch`
+    /// is set, frames are sent in the previous epoch until the config's sender delay is up. Fails
+    /// if the group can't export the epoch's secret
+    fn new(
+        config: MediaConfig,
+        group: &MlsGroup,
+        provider: &MlsProvider,
+        prev: Option<&MediaKeys>,
+        delay_switch: bool,
+    ) -> Result<MediaKeys, WorkerError> {
+        let epoch_secret = group
+            .export_secret(
+                provider.crypto(),
+                EpochKeys::EXPORTER_LABEL,
+                &[],
+                EpochKeys::EXPORTER_LEN,
+            )
+            .map_err(WorkerError::mls("export SFrame secret"))?;
+        let sframe = EpochKeys::new(
+            group.epoch().as_u64(),
+            &epoch_secret,
+            group.own_leaf_index().u32(),
+            group.members().map(|m| m.index.u32()),
+            config.group.out_of_order_tolerance,
+        );
+        let past_sframe = prev
+            .into_iter()
+            .flat_map(|p| iter::once(&p.sframe).chain(&p.past_sframe))
+            .take(config.group.max_past_epochs)
+            .cloned()
+            .collect();
+
+        let now = Instant::now();
+        Ok(MediaKeys {
+            switch_at: if delay_switch {
+                now + config.sender_delay
+            } else {
+                now
+            },
+            config,
+            sframe: Arc::new(sframe),
+            past_sframe,
+        })
+    }
+
+    /// Returns the keys that frames sent at the given time are encrypted with
+    fn sending_keys(&self, now: Instant) -> &EpochKeys {
+        match self.past_sframe.first() {
+            Some(prev) if now < self.switch_at => prev,
+            _ => &self.sframe,
+        }
+    }
+
+    /// Takes a frame of the given codec, encrypts every part of it that the codec doesn't leave
+    /// plain into an SFrame ciphertext, and appends the result to `out`. `track_id` is bound to
+    /// every ciphertext as SFrame metadata. Returns the epoch the frame was encrypted in
+    fn encrypt_frame(&self, codec: Codec, track_id: &[u8], msg: &[u8], out: &mut Vec<u8>) -> u64 {
+        let padding = self.config.group.padding.for_kind(codec.kind());
+        let keys = self.sending_keys(Instant::now());
+        codec.encrypt_frame(msg, out, |msg_to_encrypt, out| {
+            keys.encrypt(track_id, out, |out| padding.pad_into(msg_to_encrypt, out))
+        });
+        keys.epoch()
+    }
+
+    /// Decrypts a frame produced by [`MediaKeys::encrypt_frame`] in this epoch or a retained past
+    /// one, strips the padding, and appends the plaintext frame to `out`. On error, `out` may
+    /// contain part of the frame.
+    fn decrypt_frame(
+        &self,
+        codec: Codec,
+        track_id: &[u8],
+        ct: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), DecryptAppMsgError> {
+        let padding = self.config.group.padding.for_kind(codec.kind());
+        // The track ID is SFrame metadata, so a frame from another track fails authentication.
+        // SFrame decrypts in place, so the padding is stripped from `out` afterwards
+        codec.decrypt_frame(ct, out, |msg_to_decrypt, out| {
+            let pt_start = out.len();
+            // The epoch is checked before anything else, so trying every epoch in turn is cheap
+            let mut res = Ok(());
+            for keys in iter::once(&self.sframe).chain(&self.past_sframe) {
+                res = keys.decrypt(track_id, msg_to_decrypt, out);
+                if !matches!(res, Err(SFrameError::WrongEpoch(_))) {
+                    break;
+                }
+            }
+            res?;
+            let pt_len = padding.unpadded_len(&out[pt_start..])?;
+            out.truncate(pt_start + pt_len);
+            Ok(())
+        })
+    }
+}
+
+/// Contains the data created by existing member that a new users needs to join a group. This is an
+/// MLS Welcome message along with the ratchet tree information
+pub struct WelcomePackageOut {
+    pub welcome: MlsMessageOut,
+    pub ratchet_tree: RatchetTree,
+}
+
+/// Same as [`WelcomePackageOut`] but intended for incoming messages. This is created when the new
+/// user deserializes some byte stream
+struct WelcomePackageIn {
+    welcome: MlsMessageIn,
+    ratchet_tree: RatchetTreeIn,
+}
+
+/// Helper function that turns a key package into a unique UID
+fn kp_to_uid(kp: &KeyPackage) -> &[u8] {
+    // The ID is just the credential string inside the key package
+    kp.leaf_node().credential().serialized_content()
+}
+
+/// Commits the addition of the user with the given key package to the group, and merges the
+/// Commit. Returns the Welcome package for the user and the Commit for the rest of the group. On
+/// error, the group is left as it was
+fn commit_add(
+    group: &mut MlsGroup,
+    provider: &MlsProvider,
+    signing_keys: &SignatureKeyPair,
+    kp: &KeyPackage,
+) -> Result<(WelcomePackageOut, MlsMessageOut), WorkerError> {
+    let (add, welcome, _) = group
+        .add_members(provider, signing_keys, slice::from_ref(kp))
+        .map_err(WorkerError::mls("add user to group"))?;
+
+    // Merge the pending proposal we just made so we can export the new ratchet tree and give it to
+    // the new user(s)
+    merge_own_commit(group, provider)?;
+    let ratchet_tree = group.export_ratchet_tree();
+
+    let wp = WelcomePackageOut {
+        welcome,
+        ratchet_tree,
+    };
+    Ok((wp, add))
+}
+
+/// Merges the Commit this user just made into the group. If that fails, the Commit is discarded,
+/// so the group stays in the epoch it was in
+fn merge_own_commit(group: &mut MlsGroup, provider: &MlsProvider) -> Result<(), WorkerError> {
+    if let Err(e) = group.merge_pending_commit(provider) {
+        if let Err(e) = group.clear_pending_commit(provider.storage()) {
+            warn!("Couldn't discard unmerged commit: {e:?}");
+        }
+        return Err(WorkerError::mls("merge commit")(e));
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct WorkerState {
+    mls_provider: MlsProvider,
+    mls_group: Option<MlsGroup>,
+
+    my_credential: Option<CredentialWithKey>,
+    my_signing_keys: Option<SignatureKeyPair>,
+    /// This user's signing keys for the signature schemes of the other ciphersuites it accepts,
+    /// until it's in a group. `my_signing_keys` are the ones for its favorite ciphersuite
+    spare_signing_keys: Vec<SignatureKeyPair>,
+    /// The UIDs of the users who were in the MLS group before this user was welcomed. These are
+    /// precisely the ones who would be a designated committer before this user. This is only
+    /// `Some` once this user has been welcomed
+    users_alive_before_i_was_welcomed: Option<BTreeSet<Vec<u8>>>,
+    /// The UIDs of the users who left the room after this user joined the room (not necessarily was
+    /// added)
+    users_who_left_since_i_joined: BTreeSet<Vec<u8>>,
+    /// The key packages of room members who have not yet been added to the MLS group. A member
+    /// has one per ciphersuite it accepts
+    pending_adds: Vec<KeyPackage>,
+    /// The set of UIDs of room members who have not yet been removed from the MLS group
+    pending_removes: Vec<Vec<u8>>,
+    /// How media frames are encrypted
+    media_config: MediaConfig,
+    /// The media key snapshot of the current epoch, if the media format is SFrame
+    media_keys: Option<Arc<MediaKeys>>,
+    /// Recent group events, which the session records errors in too
+    diagnostics: Arc<Diagnostics>,
+}
+
+impl WorkerState {
+    /// Initializes MLS state with a unique identifier for this user. Also returns the freshly
+    /// generated key packages of this user, one for each ciphersuite it accepts, in order of
+    /// preference.
+    /// This MUST be executed before anything else in this module.
+    fn new(
+        uid: Vec<u8>,
+        media_config: MediaConfig,
+    ) -> Result<(WorkerState, Vec<KeyPackageBundle>), WorkerError> {
+        media_config.group.validate()?;
+        let mut state = WorkerState {
+            media_config,
+            ..Default::default()
+        };
+        let credential = BasicCredential::new(uid);
+        let capabilities = state.media_config.group.capabilities();
+
+        let mut signing_keys: Vec<SignatureKeyPair> = Vec::new();
+        let mut key_packages = Vec::new();
+        for &name in &state.media_config.group.ciphersuites {
+            let ciphersuite = Ciphersuite::from(name);
+            let scheme = ciphersuite.signature_algorithm();
+
+            // Generate new signing keys, unless another ciphersuite has the same signature scheme
+            let signature_keys = match signing_keys.iter().find(|k| k.signature_scheme() == scheme)
+            {
+                Some(keys) => keys,
+                None => {
+                    let keys = SignatureKeyPair::new(scheme)
+                        .map_err(WorkerError::mls("generate signing key"))?;
+                    // Store the signature key into the key store so OpenMLS has access to it.
+                    keys.store(state.mls_provider.storage())
+                        .map_err(WorkerError::mls("store signature keys"))?;
+                    signing_keys.push(keys);
+                    signing_keys.last().unwrap()
+                }
+            };
+            let cred = CredentialWithKey {
+                credential: credential.clone().into(),
+                signature_key: signature_keys.public().into(),
+            };
+
+            // Construct the key package. Its capabilities say which ciphersuites this user accepts,
+            // and that it checks the group's parameters, which groups require
+            let key_package = KeyPackage::builder()
+                .leaf_node_capabilities(capabilities.clone())
+                .build(
+                    ciphersuite,
+                    &state.mls_provider,
+                    signature_keys,
+                    cred.clone(),
+                )
+                .map_err(WorkerError::mls("build key package"))?;
+            key_packages.push(key_package);
+
+            // Save the credential of the favorite ciphersuite into the state struct
+            state.my_credential.get_or_insert(cred);
+        }
+
+        // The first signing keys are the favorite ciphersuite's
+        let mut signing_keys = signing_keys.into_iter();
+        state.my_signing_keys = signing_keys.next();
+        state.spare_signing_keys = signing_keys.collect();
+
+        Ok((state, key_packages))
+    }
+
+    /// Switches to the signing keys for the given ciphersuite's signature scheme, which this user
+    /// joined a group in. The spare keys are no use after that, since a group's ciphersuite never
+    /// changes
+    fn use_ciphersuite(&mut self, ciphersuite: Ciphersuite) {
+        let scheme = ciphersuite.signature_algorithm();
+        if let Some(idx) = self
+            .spare_signing_keys
+            .iter()
+            .position(|k| k.signature_scheme() == scheme)
+        {
+            let keys = self.spare_signing_keys.swap_remove(idx);
+            self.my_credential.as_mut().unwrap().signature_key = keys.public().into();
+            self.my_signing_keys = Some(keys);
+        }
+        self.spare_signing_keys.clear();
+    }
+
+    fn safety_number(&self) -> SafetyNumber {
+        let mut sn = SafetyNumber::default();
+        // Get the epoch authenticator and truncate it to 256 bits
+        sn.copy_from_slice(
+            &self
+                .mls_group
+                .as_ref()
+                .unwrap()
+                .epoch_authenticator()
+                .as_slice()[..32],
+        );
+        sn
+    }
+
+    fn uid(&self) -> &[u8] {
+        self.my_credential
+            .as_ref()
+            .unwrap()
+            .credential
+            .serialized_content()
+    }
+
+    /// Fails if [`WorkerState::new`] hasn't made an identity for this user yet
+    fn check_initialized(&self) -> Result<(), WorkerError> {
+        if self.my_credential.is_none() {
+            return Err(WorkerError::NotInitialized);
+        }
+        Ok(())
+    }
+
+    /// Returns this user's UID as a string, which is how the rest of the room knows it. Fails if
+    /// it isn't UTF-8, which it always is when it comes from an event
+    fn uid_as_str(&self) -> Result<String, WorkerError> {
+        String::from_utf8(self.uid().to_vec())
+            .map_err(|_| WorkerError::MalformedEvent("user ID isn't UTF-8".to_string()))
+    }
+
+    /// Logs and records an error that came after the group had already changed. It's returned in
+    /// the response rather than failing the operation, so that the response is still acted on
+    fn late_error(&self, e: WorkerError) -> Option<WorkerError> {
+        warn!("Group changed, then failed: {e}");
+        self.diagnostics
+            .record(DiagnosticEvent::Error { code: e.code() });
+        Some(e)
+    }
+
+    /// Returns whether this user is the designated committer (DC) of the group
+    fn is_designated_committer(&self) -> bool {
+        // If everyone who was alive when I was welcomed is now dead, then I'm the DC
+        if let Some(alive_at_welcome) = &self.users_alive_before_i_was_welcomed {
+            let other_dc_candiates =
+                alive_at_welcome.difference(&self.users_who_left_since_i_joined);
+            other_dc_candiates.count() == 0
+        } else {
+            // If I haven't been welcomed, I'm certainly not the DC
+            false
+        }
+    }
+
+    /// Records the group's new epoch, and how many members it has, in the diagnostics
+    fn record_epoch(&self) {
+        let group = self.mls_group.as_ref().unwrap();
+        self.diagnostics.record(DiagnosticEvent::EpochChanged {
+            epoch: group.epoch().as_u64(),
+            members: group.members().count(),
+        });
+    }
+
+    /// Starts a new MLS group. This is called if this user is the first user in the room. The
+    /// group's parameters are written into its group context, so everyone who joins later can check
+    /// them. Returns a new safety number and nothing else
+    fn start_group(&mut self) -> Result<SafetyNumber, WorkerError> {
+        let (Some(signing_keys), Some(credential)) =
+            (self.my_signing_keys.as_ref(), self.my_credential.clone())
+        else {
+            return Err(WorkerError::NotInitialized);
+        };
+        let group_config = &self.media_config.group;
+        let config = MlsGroupCreateConfig::builder()
+            .ciphersuite(group_config.preferred_ciphersuite())
+            .capabilities(group_config.capabilities())
+            .with_group_context_extensions(group_config.group_context_extensions())
+            .map_err(WorkerError::mls("pin group parameters"))?
+            .sender_ratchet_configuration(group_config.sender_ratchet())
+            .max_past_epochs(group_config.max_past_epochs)
+            .build();
+
+        self.mls_group = Some(
+            MlsGroup::new(&self.mls_provider, signing_keys, &config, credential)
+                .map_err(WorkerError::mls("create group"))?,
+        );
+
+        // Starting a group means you don't have to be Welcomed
+        self.users_alive_before_i_was_welcomed = Some(BTreeSet::new());
+        self.spare_signing_keys.clear();
+        self.refresh_media_keys(false)?;
+        self.record_epoch();
+
+        // Return the new safety number
+        Ok(self.safety_number())
+    }
+
+    /// Join a group using the given MLS Welcome message
+    fn join_group(&mut self, wp: WelcomePackageIn) -> Result<WorkerResponse, WorkerError> {
+        self.check_initialized()?;
+        let WelcomePackageIn {
+            welcome,
+            ratchet_tree,
+        } = wp;
+
+        // Process the message
+        let MlsMessageBodyIn::Welcome(w) = welcome.extract() else {
+            return Err(WorkerError::WrongMsgType("Welcome"));
+        };
+        // If we can't process this Welcome, it's because it's not meant for us. Return early
+        let Ok(staged_join) = StagedWelcome::new_from_welcome(
+            &self.mls_provider,
+            &self.media_config.group.join_config(),
+            w,
+            Some(ratchet_tree),
+        ) else {
+            return Ok(WorkerResponse::default());
+        };
+        // The Welcome is for us. We'd fail to decrypt half the group's frames with different
+        // parameters than the group's creator picked, so we take on the group's
+        let context = staged_join.group_context();
+        let ciphersuite = context.ciphersuite();
+        let mut group_config = self.media_config.group.clone();
+        group_config.adopt_group(ciphersuite, context.extensions())?;
+
+        // Create a group from the processed welcome
+        let mut group = staged_join
+            .into_group(&self.mls_provider)
+            .map_err(WorkerError::mls("join group"))?;
+        group
+            .set_configuration(self.mls_provider.storage(), &group_config.join_config())
+            .map_err(WorkerError::mls("configure group"))?;
+        self.mls_group = Some(group);
+        if group_config != self.media_config.group {
+            warn!("Joined with the group's parameters, which differ from the configured ones");
+            self.media_config.group = group_config;
+        }
+        // The group might not be in this user's favorite ciphersuite, and this user's leaf is the
+        // one from its key package in the group's
+        self.use_ciphersuite(ciphersuite);
+
+        // Collect all the users in the group who will be the DC before me. This is simply all the
+        // users who were in the group before my Welcome
+        // Note: there is an optimization we're not doing. You can Welcome multiple people at once
+        // in MLS. This requires a more careful definition of "before" for determining DC. We can
+        // update it to mean "a user becomes DC before me iff the user was in the group prior to my
+        // welcome, OR they were welcomed at the same time as me and have a lower leaf index". This
+        // would require us to also send the list of UIDs being welcomed in every WelcomePackage,
+        // and remove this set from our pending adds once we're welcomed. We can leave this for
+        // future work. In practice, Welcomes almost never have more than 1 user in them anyway.
+        let my_uid = self.uid();
+        self.users_alive_before_i_was_welcomed = Some(
+            self.mls_group
+                .as_ref()
+                .unwrap()
+                .members()
+                .filter_map(|m| {
+                    let uid = m.credential.serialized_content().to_vec();
+                    // Don't collect my own UID
+                    if uid != my_uid {
+                        Some(uid)
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+        );
+        let error = self
+            .refresh_media_keys(false)
+            .err()
+            .and_then(|e| self.late_error(e));
+        self.record_epoch();
+
+        // Return the new safety number
+        Ok(WorkerResponse {
+            new_safety_number: Some(self.safety_number()),
+            error,
+            ..Default::default()
+        })
+    }
+
+    /// If this user is the designated committer, this catches up on the pending adds and removes.
+    /// If not, this does nothing. A user whose add can't be committed is dropped rather than
+    /// retried, so one bad key package can't hold up everyone queued after it. The removes stay
+    /// pending until their Commit is made. If that Commit can't be made before any others are,
+    /// this fails and the group is left as it was. If it can't be made after others are, those are
+    /// returned along with the error, since the group has moved on and the rest of the room has to
+    /// as well
+    fn process_pendings(&mut self) -> Result<WorkerResponse, WorkerError> {
+        if !self.is_designated_committer() {
+            if !self.pending_adds.is_empty() || !self.pending_removes.is_empty() {
+                let pending_adds = self
+                    .pending_adds
+                    .iter()
+                    .map(kp_to_uid)
+                    .collect::<BTreeSet<_>>();
+                self.diagnostics.record(DiagnosticEvent::Deferred {
+                    pending_adds: pending_adds.len(),
+                    pending_removes: self.pending_removes.len(),
+                });
+            }
+            return Ok(WorkerResponse::default());
+        }
+        let sender_id = self.uid_as_str()?;
+
+        // Process all the pending additions, one Commit each. The DC has been welcomed, so it has
+        // a group
+        let group = self.mls_group.as_mut().unwrap();
+        let signing_keys = self.my_signing_keys.as_ref().unwrap();
+        let mut adds = Vec::with_capacity(self.pending_adds.len());
+        while let Some(first) = self.pending_adds.first() {
+            // Add the user with its key package in the group's ciphersuite
+            let uid = kp_to_uid(first).to_vec();
+            let Some(kp) = self
+                .pending_adds
+                .iter()
+                .find(|kp| kp_to_uid(kp) == uid && fits_group(kp, group.ciphersuite()))
+            else {
+                // The user joined the room before this user was in the group, so it couldn't be
+                // turned away then
+                warn!(
+                    "Not adding {}, whose key packages don't fit the group",
+                    String::from_utf8_lossy(&uid)
+                );
+                self.pending_adds.retain(|kp| kp_to_uid(kp) != uid);
+                self.diagnostics.record(DiagnosticEvent::JoinerDropped);
+                continue;
+            };
+            // A failed add leaves the group as it was, so the users queued after it can still be
+            // added
+            match commit_add(group, &self.mls_provider, signing_keys, kp) {
+                Ok(add) => adds.push(add),
+                Err(e) => {
+                    warn!("Not adding {}: {e}", String::from_utf8_lossy(&uid));
+                    self.diagnostics.record(DiagnosticEvent::JoinerDropped);
+                }
+            }
+            self.pending_adds.retain(|kp| kp_to_uid(kp) != uid);
+        }
+
+        // Now process the pending removes
+        let mut removed = 0;
+        let mut remove = None;
+        let mut error = None;
+        if !self.pending_removes.is_empty() {
+            // Get the indices for all the users we're supposed to remove
+            let uid_idx_map: BTreeMap<Vec<u8>, LeafNodeIndex> = group
+                .members()
+                .map(|member| {
+                    (
+                        member.credential.serialized_content().to_vec(),
+                        member.index,
+                    )
+                })
+                .collect();
+            let pending_remove_idxs = self
+                .pending_removes
+                .iter()
+                .filter_map(|uid| uid_idx_map.get(uid).copied())
+                .collect::<Vec<_>>();
+
+            // Remove them. The pending removes are empty after this
+            let res = group
+                .remove_members(&self.mls_provider, signing_keys, &pending_remove_idxs)
+                .map_err(WorkerError::mls("remove users from group"))
+                .and_then(|(commit, _, _)| {
+                    merge_own_commit(group, &self.mls_provider)?;
+                    Ok(commit)
+                });
+            match res {
+                Ok(commit) => {
+                    self.pending_removes.clear();
+                    removed = pending_remove_idxs.len();
+                    remove = Some(commit);
+                }
+                Err(e) if adds.is_empty() => return Err(e),
+                Err(e) => error = Some(e),
+            }
+        }
+        // A removed user still has the keys of the epochs before its removal
+        if let Err(e) = self.refresh_media_keys(remove.is_none()) {
+            error.get_or_insert(e);
+        }
+        let error = error.and_then(|e| self.late_error(e));
+        if !adds.is_empty() || remove.is_some() {
+            self.diagnostics.record(DiagnosticEvent::Committed {
+                added: adds.len(),
+                removed,
+            });
+            self.record_epoch();
+        }
+
+        Ok(WorkerResponse {
+            adds,
+            remove,
+            new_safety_number: Some(self.safety_number()),
+            sender_id: Some(sender_id),
+            error,
+            ..Default::default()
+        })
+    }
+
+    /// If this user is the Designated Committer, this will create a welcome package for the for the
+    /// new user and a Commit with an Add operation in it, and it will update the current state to
+    /// include the Add. Otherwise, this will just note that a new user has joined the room but not
+    /// yet been added to the MLS group. The user shares a key package per ciphersuite it accepts.
+    /// Fails if any key package is invalid, or if this user is in the group and none of them fits
+    /// the group.
+    fn user_joined(&mut self, user_kps: Vec<KeyPackageIn>) -> Result<WorkerResponse, WorkerError> {
+        self.check_initialized()?;
+        // Extract the new user's key packages
+        let user_kps = user_kps
+            .into_iter()
+            .map(|kp| kp.validate(self.mls_provider.crypto(), PROT_VERSION))
+            .collect::<Result<Vec<_>, _>>()?;
+        let Some(first) = user_kps.first() else {
+            return Err(WorkerError::IncompatibleKeyPackage(
+                "no key packages".to_string(),
+            ));
+        };
+        // If we're in the group, we know its ciphersuite, so we can turn the user away right now
+        if let Some(group) = &self.mls_group {
+            let ciphersuite = group.ciphersuite();
+            if !user_kps.iter().any(|kp| fits_group(kp, ciphersuite)) {
+                let offered: Vec<_> = user_kps.iter().map(|kp| kp.ciphersuite()).collect();
+                return Err(WorkerError::IncompatibleKeyPackage(format!(
+                    "none of {}'s key packages, for {offered:?}, fits the group in {ciphersuite:?}",
+                    String::from_utf8_lossy(kp_to_uid(first)),
+                )));
+            }
+        }
+
+        // Add the user to the pending list, as long as it's not us (we might get this event when we join)
+        let uid = kp_to_uid(first).to_vec();
+        let is_me = self.uid() == uid;
+        if !is_me {
+            self.pending_adds.extend(user_kps);
+        }
+
+        // Process pending adds/removes (only does anything if we're the DC). If that fails, the
+        // user might still be pending, so take them back out
+        let resp = self.process_pendings();
+        if resp.is_err() && !is_me {
+            self.pending_adds.retain(|kp| kp_to_uid(kp) != uid);
+        }
+        resp
+    }
+
+    /// If this user is the Designated Committer, this will create a Remove message
+    /// for the rest of the group. Otherwise, this will just note that a user has been
+    /// removed from the room,  but not yet been removed from the MLS group.
+    /// If this user has not yet been welcomed, they add this to the pending removes and log the UID
+    /// as one they will not consider a DC candidate.
+    /// This fails if a user tries to remove themselves.
+    fn user_left(&mut self, uid_to_remove: &[u8]) -> Result<WorkerResponse, WorkerError> {
+        self.check_initialized()?;
+        if uid_to_remove == self.uid() {
+            return Err(WorkerError::RemoveSelf);
+        }
+
+        // Add this user to the pending removes
+        self.pending_removes.push(uid_to_remove.to_vec());
+        // Mark this user as left
+        let newly_left = self
+            .users_who_left_since_i_joined
+            .insert(uid_to_remove.to_vec());
+
+        // Process pending adds/removes (only does anything if we're the DC). If that fails, undo
+        // the above
+        let resp = self.process_pendings();
+        if resp.is_err() {
+            self.pending_removes.pop();
+            if newly_left {
+                self.users_who_left_since_i_joined.remove(uid_to_remove);
+            }
+        }
+        resp
+    }
+
+    /// Applies the given MLS commit to the group state. Fails if the message isn't a Commit or can't
+    /// be processed
+    fn handle_commit(&mut self, msg: MlsMessageIn) -> Result<WorkerResponse, WorkerError> {
+        // If we haven't been welcomed, just ignore this message
+        let Some(group) = self.mls_group.as_mut() else {
+            return Ok(WorkerResponse::default());
+        };
+
+        // Process the message into a Staged Commit
+        let prot_msg = msg
+            .try_into_protocol_message()
+            .map_err(|_| WorkerError::WrongMsgType("Commit"))?;
+
+        let processed_message = match group.process_message(&self.mls_provider, prot_msg) {
+            Ok(m) => m.into_content(),
+
+            // If the message is from the wrong epoch, ignore it. Things can't really get out of
+            // order when we have a designated committer and a strongly serializing message delivery
+            // service.
+            Err(ProcessMessageError::ValidationError(
+                openmls::group::ValidationError::WrongEpoch,
+            )) => {
+                self.diagnostics.record(DiagnosticEvent::StaleCommit);
+                return Ok(WorkerResponse::default());
+            }
+            Err(e) => return Err(WorkerError::mls("process message")(e)),
+        };
+        let ProcessedMessageContent::StagedCommitMessage(staged_com) = processed_message else {
+            return Err(WorkerError::WrongMsgType("Commit"));
+        };
+
+        // Collect all the UIDs of the users being added and removed
+        let uids_being_added: BTreeSet<_> = staged_com
+            .add_proposals()
+            .map(|p| kp_to_uid(p.add_proposal().key_package()).to_vec())
+            .collect();
+        let uids_being_removed: BTreeSet<_> = staged_com
+            .remove_proposals()
+            .filter_map(|p| {
+                let idx = p.remove_proposal().removed();
+                group
+                    .member(idx)
+                    .map(|cred| cred.serialized_content().to_vec())
+            })
+            .collect();
+        // A removed user still has the keys of the epochs before its removal, so only a Commit
+        // that just adds users can keep frames in the previous epoch for a while
+        let delay_switch = staged_com.add_proposals().next().is_some()
+            && staged_com.remove_proposals().next().is_none();
+
+        // Merge the Commit into the group state
+        group
+            .merge_staged_commit(&self.mls_provider, *staged_com)
+            .map_err(WorkerError::mls("merge commit"))?;
+
+        // After successful add, remove the UIDs from the pending list. In other words, retain
+        // the UIDs that aren't in the pending list
+        self.pending_adds
+            .retain(|kp| !uids_being_added.contains(kp_to_uid(kp)));
+        // Same thing for removes
+        self.pending_removes
+            .retain(|uid| !uids_being_removed.contains(uid));
+        let error = self
+            .refresh_media_keys(delay_switch)
+            .err()
+            .and_then(|e| self.late_error(e));
+        self.record_epoch();
+
+        // Return the new safety number
+        Ok(WorkerResponse {
+            new_safety_number: Some(self.safety_number()),
+            error,
+            ..Default::default()
+        })
+    }
+
+    /// Makes a new media key snapshot if the group moved to a new epoch. Keys are only made once
+    /// per epoch, since making them again would restart the frame counter and reuse nonces. The
+    /// previous snapshot's keys are carried over for frames still in flight. `delay_switch` says
+    /// whether to keep sending in the previous epoch for the sender delay, which is only safe if
+    /// the epoch changed without removing anyone. If the keys can't be made, there's no snapshot
+    /// until the next epoch, so frames are held back according to the pre-group policy rather than
+    /// sent in a stale epoch
+    fn refresh_media_keys(&mut self, delay_switch: bool) -> Result<(), WorkerError> {
+        let Some(group) = self.mls_group.as_ref() else {
+            return Ok(());
+        };
+        if self.media_config.media_format != MediaFormat::SFrame
+            || self
+                .media_keys
+                .as_ref()
+                .is_some_and(|k| k.sframe.epoch() == group.epoch().as_u64())
+        {
+            return Ok(());
+        }
+
+        let keys = MediaKeys::new(
+            self.media_config.clone(),
+            group,
+            &self.mls_provider,
+            self.media_keys.as_deref(),
+            delay_switch,
+        );
+        match keys {
+            Ok(keys) => {
+                self.media_keys = Some(Arc::new(keys));
+                Ok(())
+            }
+            Err(e) => {
+                self.media_keys = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Takes a frame of the given codec, encrypts it, and appends the result to `out`. In the MLS
+    /// media format, the ciphertext is framed as an `MlsMessageOut` and serialized, and in the
+    /// SFrame format, it's an SFrame ciphertext. `track_id` identifies the track the frame belongs
+    /// to, and is bound to the ciphertext as authenticated data. Plaintexts are padded according to
+    /// the padding policy. If `self.mls_group` doesn't exist, or this user can't send in it, e.g.,
+    /// after being removed, nothing is encrypted, and the pre-group policy says what to send
+    /// instead, if anything.
+    fn encrypt_app_msg_nofail(
+        &mut self,
+        codec: Codec,
+        track_id: &[u8],
+        msg: &[u8],
+        out: &mut Vec<u8>,
+    ) -> EncryptOutcome {
+        let Some(group) = self.mls_group.as_mut() else {
+            return self.media_config.pre_group_policy.apply(out);
+        };
+
+        if self.media_config.media_format == MediaFormat::SFrame {
+            // There's a snapshot in the SFrame format once there's a group, unless its keys
+            // couldn't be made
+            let Some(keys) = self.media_keys.as_ref() else {
+                return self.media_config.pre_group_policy.apply(out);
+            };
+            return EncryptOutcome::Encrypted(keys.encrypt_frame(codec, track_id, msg, out));
+        }
+
+        let padding = self.media_config.group.padding.for_kind(codec.kind());
+
+        // We can't encrypt every part of a frame. The codec decides what to leave plain
+        let start = out.len();
+        let mut failed = false;
+        codec.encrypt_frame(msg, out, |msg_to_encrypt, out| {
+            // The AAD is reset after every message, so set it for every part of the frame
+            group.set_aad(track_id.to_vec());
+            match group.create_message(
+                &self.mls_provider,
+                self.my_signing_keys.as_ref().unwrap(),
+                &padding.pad(msg_to_encrypt),
+            ) {
+                // Serializing into a Vec can't fail
+                Ok(ct) => {
+                    ct.tls_serialize(out).unwrap();
+                }
+                Err(e) => {
+                    debug!("Frame encryption failed: {e}");
+                    failed = true;
+                }
+            }
+        });
+        if failed {
+            out.truncate(start);
+            return self.media_config.pre_group_policy.apply(out);
+        }
+        EncryptOutcome::Encrypted(group.epoch().as_u64())
+    }
+
+    /// Takes an encrypted frame of the given codec, deserializes the ciphertext in it, decrypts it
+    /// into an Application Message, strips the padding, and appends the plaintext frame to `out`.
+    /// Fails if the frame wasn't encrypted for the track `track_id`, in which case `out` may contain
+    /// part of the frame.
+    fn decrypt_app_msg(
+        &mut self,
+        codec: Codec,
+        track_id: &[u8],
+        ct: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), DecryptAppMsgError> {
+        if self.media_config.media_format == MediaFormat::SFrame {
+            let keys = self
+                .media_keys
+                .as_ref()
+                .ok_or(DecryptAppMsgError::NoGroup)?;
+            return keys.decrypt_frame(codec, track_id, ct, out);
+        }
+
+        let padding = self.media_config.group.padding.for_kind(codec.kind());
+        codec.decrypt_frame(ct, out, |msg_to_decrypt, out| {
+            let pt = self.decrypt_payload(track_id, msg_to_decrypt)?;
+            out.extend_from_slice(&pt[..padding.unpadded_len(&pt)?]);
+            Ok(())
+        })
+    }
+
+    /// Takes a serialized `MlsMessageOut`, decrypts it into an Application Message, and returns the
+    /// bytes. Fails if the message's authenticated data isn't `track_id`.
+    fn decrypt_payload(
+        &mut self,
+        track_id: &[u8],
+        msg_to_decrypt: &[u8],
+    ) -> Result<Vec<u8>, DecryptAppMsgError> {
+        let group = self.mls_group.as_mut().ok_or(DecryptAppMsgError::NoGroup)?;
+        let framed = MlsMessageIn::tls_deserialize_exact_bytes(msg_to_decrypt)?;
+
+        // Process the ciphertext into an application message
+        let prot_msg = framed
+            .try_into_protocol_message()
+            .map_err(|_| DecryptAppMsgError::WrongMsgType("non-protocol message"))?;
+        let processed = group.process_message(&self.mls_provider, prot_msg)?;
+
+        // A frame that was moved here from another track decrypts fine, so check where it's from
+        if processed.aad() != track_id {
+            return Err(DecryptAppMsgError::WrongTrack);
+        }
+        let msg = processed.into_content();
+
+        match msg {
+            ProcessedMessageContent::ApplicationMessage(app_msg) => Ok(app_msg.into_bytes()),
+            ProcessedMessageContent::ProposalMessage(_) => {
+                Err(DecryptAppMsgError::WrongMsgType("proposal"))
+            }
+            ProcessedMessageContent::ExternalJoinProposalMessage(_) => {
+                Err(DecryptAppMsgError::WrongMsgType("external join proposal"))
+            }
+            ProcessedMessageContent::StagedCommitMessage(_) => {
+                Err(DecryptAppMsgError::WrongMsgType("staged commit"))
+            }
+        }
+    }
+
+    /// Takes a ciphertext, deserializes it, decrypts it into an Application Message, and appends
+    /// the bytes to `out`. If any error happens, appends whatever the decrypt failure policy says to
+    /// pass on instead, if anything
+    fn decrypt_app_msg_nofail(
+        &mut self,
+        codec: Codec,
+        track_id: &[u8],
+        ct: &[u8],
+        out: &mut Vec<u8>,
+    ) -> DecryptOutcome {
+        let start = out.len();
+        match self.decrypt_app_msg(codec, track_id, ct, out) {
+            Ok(()) => DecryptOutcome::Decrypted,
+            Err(e) => {
+                debug!("Frame decryption failed: {e}");
+                out.truncate(start);
+                self.media_config
+                    .decrypt_failure_policy
+                    .apply(codec, ct, out)
+            }
+        }
+    }
+}
+
+/// One MLS session: this user's identity and group in one room, along with the media keys and
+/// decrypt stats that go with them. A worker can be in several sessions at once, which share
+/// nothing. The WASM worker keeps them by session ID
+pub struct Session {
+    state: Mutex<WorkerState>,
+    /// The media key snapshot of the state's current epoch. Frames are protected with whatever
+    /// snapshot is here, without touching `state`, so a slow Commit or Welcome doesn't hold up
+    /// media. Handshake processing swaps in the next epoch's snapshot once it's made. There are
+    /// only snapshots in the SFrame format: frames in the MLS format lock `state` every time
+    media_keys: ArcSwapOption<MediaKeys>,
+    /// How many frames had each outcome of decryption since the session was created
+    decrypt_stats: DecryptStats,
+    /// Recent group events and errors, which can be dumped even while `state` is busy
+    diagnostics: Arc<Diagnostics>,
+}
+
+/// A create, join, add, or remove operation might result in a welcome package, one or more MLS
+/// proposals, a new safety number, and/or a user key pacakge
+#[derive(Default)]
+pub struct WorkerResponse {
+    /// Contains Welcomes and Adds for individual users. This must be processed in the same sequence
+    /// as it appears
+    pub adds: Vec<(WelcomePackageOut, MlsMessageOut)>,
+    /// Contains an optional Remove operation. This might remove many users at once
+    pub remove: Option<MlsMessageOut>,
+    /// The new safety number for this group
+    pub new_safety_number: Option<SafetyNumber>,
+    /// The key packages of a joining user, one per ciphersuite it accepts
+    pub key_pkgs: Vec<KeyPackage>,
+    /// The ID of this user if it's the DC
+    pub sender_id: Option<String>,
+    /// How many frames had each outcome of decryption, if asked for
+    pub decrypt_counts: Option<DecryptCounts>,
+    /// The session's recent diagnostic events, oldest first, if asked for
+    pub diagnostics: Option<Vec<Diagnostic>>,
+    /// An error the operation ran into after it had already changed the group, e.g., after the
+    /// Commits in this response were merged. The rest of the response must be acted on all the
+    /// same, or the rest of the room falls behind
+    pub error: Option<WorkerError>,
+}
+
+/// A serialized handshake message to be relayed to the rest of the room
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HandshakeMessage {
+    /// This user's key packages, one per ciphersuite it accepts, for the group's designated
+    /// committer
+    KeyPackages(Vec<Vec<u8>>),
+    /// A Welcome for a user the sender added, along with the group's ratchet tree
+    Welcome {
+        sender_id: String,
+        welcome: Vec<u8>,
+        rtree: Vec<u8>,
+    },
+    /// A Commit, for the rest of the group
+    Commit { sender_id: String, msg: Vec<u8> },
+}
+
+impl WorkerResponse {
+    /// Returns the handshake messages in this response, in the order they must be relayed in: key
+    /// packages, (Welcome, Add), (Welcome, Add), ..., Remove. Fails if a message can't be
+    /// serialized, or there are Welcomes or Commits but no sender ID
+    pub fn handshake_messages(&self) -> Result<Vec<HandshakeMessage>, WorkerError> {
+        // Every response with a Welcome or a Commit in it says who sent it
+        let sender_id = || self.sender_id.clone().ok_or(WorkerError::NotInitialized);
+
+        let mut msgs = Vec::new();
+        if !self.key_pkgs.is_empty() {
+            msgs.push(HandshakeMessage::KeyPackages(
+                self.key_pkgs
+                    .iter()
+                    .map(|kp| kp.tls_serialize_detached())
+                    .collect::<Result<_, _>>()
+                    .map_err(WorkerError::mls("serialize key package"))?,
+            ));
+        }
+        for (wp, add) in &self.adds {
+            msgs.push(HandshakeMessage::Welcome {
+                sender_id: sender_id()?,
+                welcome: wp
+                    .welcome
+                    .to_bytes()
+                    .map_err(WorkerError::mls("serialize Welcome"))?,
+                rtree: wp
+                    .ratchet_tree
+                    .tls_serialize_detached()
+                    .map_err(WorkerError::mls("serialize ratchet tree"))?,
+            });
+            msgs.push(HandshakeMessage::Commit {
+                sender_id: sender_id()?,
+                msg: add
+                    .tls_serialize_detached()
+                    .map_err(WorkerError::mls("serialize Commit"))?,
+            });
+        }
+        if let Some(remove) = &self.remove {
+            msgs.push(HandshakeMessage::Commit {
+                sender_id: sender_id()?,
+                msg: remove
+                    .tls_serialize_detached()
+                    .map_err(WorkerError::mls("serialize Commit"))?,
+            });
+        }
+        Ok(msgs)
+    }
+}
+
+impl Session {
+    fn from_state(state: WorkerState) -> Session {
+        Session {
+            media_keys: ArcSwapOption::new(state.media_keys.clone()),
+            diagnostics: state.diagnostics.clone(),
+            state: Mutex::new(state),
+            decrypt_stats: DecryptStats::new(),
+        }
+    }
+
+    /// Generates a new identity. Also returns the response sharing its key packages. Fails if the
+    /// media config's group config is invalid
+    pub fn new(
+        uid: &str,
+        media_config: MediaConfig,
+    ) -> Result<(Session, WorkerResponse), WorkerError> {
+        let (state, key_pkgs) = WorkerState::new(uid.as_bytes().to_vec(), media_config)?;
+
+        // Respond with the key packages
+        let resp = WorkerResponse {
+            key_pkgs: key_pkgs.iter().map(|kp| kp.key_package().clone()).collect(),
+            ..Default::default()
+        };
+        Ok((Session::from_state(state), resp))
+    }
+
+    /// Generates a new identity and starts a new MLS group with the media config's group config.
+    /// Also returns the response with the group's safety number
+    pub fn new_with_group(
+        uid: &str,
+        media_config: MediaConfig,
+    ) -> Result<(Session, WorkerResponse), WorkerError> {
+        let (mut state, _) = WorkerState::new(uid.as_bytes().to_vec(), media_config)?;
+        let safety_number = state.start_group()?;
+
+        // Respond with the safety number. Key packages aren't necessary because there's nobody to
+        // give them to yet
+        let resp = WorkerResponse {
+            new_safety_number: Some(safety_number),
+            ..Default::default()
+        };
+        Ok((Session::from_state(state), resp))
+    }
+
+    /// Acquires the state and runs `f` on it
+    fn with_state<T>(
+        &self,
+        f: impl FnOnce(&mut WorkerState) -> Result<T, WorkerError>,
+    ) -> Result<T, WorkerError> {
+        let mut state = self
+            .state
+            .lock()
+            .map_err(|_| WorkerError::StateUnavailable)?;
+        f(&mut state)
+    }
+
+    /// Runs `f`, recording the code of the error it fails with, if any, in the diagnostics
+    fn recording_errors<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, WorkerError>,
+    ) -> Result<T, WorkerError> {
+        let res = f();
+        if let Err(e) = &res {
+            self.diagnostics
+                .record(DiagnosticEvent::Error { code: e.code() });
+        }
+        res
+    }
+
+    /// Publishes the media key snapshot of the given state, which is this session's. This is
+    /// called with the state locked, so snapshots are published in epoch order
+    fn publish_media_keys(&self, state: &WorkerState) {
+        self.media_keys.store(state.media_keys.clone());
+    }
+
+    /// Encrypts the frame into `out` if the MLS group exists. If not, `out` holds whatever the
+    /// pre-group policy says to send instead, if anything. `out` is cleared first, and its
+    /// allocation is reused. See [`MediaConfig::codec_for`] for how the codec is picked. `track_id`
+    /// identifies the track this frame belongs to. If there's a media key snapshot, the frame is
+    /// encrypted with it, and otherwise this acquires the state. Returns what became of the frame.
+    /// Fails only if the state is unavailable, which is recorded in the diagnostics
+    pub fn encrypt_msg(
+        &self,
+        kind: FrameKind,
+        mime_type: Option<&str>,
+        stream_codec: Codec,
+        track_id: &[u8],
+        msg: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<EncryptOutcome, WorkerError> {
+        out.clear();
+        if let Some(keys) = self.media_keys.load().as_deref() {
+            let codec = keys.config.codec_for(kind, mime_type, stream_codec);
+            return Ok(EncryptOutcome::Encrypted(
+                keys.encrypt_frame(codec, track_id, msg, out),
+            ));
+        }
+
+        self.recording_errors(|| {
+            self.with_state(|state| {
+                let codec = state.media_config.codec_for(kind, mime_type, stream_codec);
+                Ok(state.encrypt_app_msg_nofail(codec, track_id, msg, out))
+            })
+        })
+    }
+
+    /// Attempts to decrypt the given encrypted frame into `out`. On failure, `out` holds whatever
+    /// the decrypt failure policy says to pass on instead, if anything. `out` is cleared first, and
+    /// its allocation is reused. See [`MediaConfig::codec_for`] for how the codec is picked. Frames
+    /// that weren't encrypted for the track `track_id` fail to decrypt. If there's a media key
+    /// snapshot, the frame is decrypted with it, and otherwise this acquires the state. Returns
+    /// what became of the frame, which is also counted in the decrypt stats. Fails only if the
+    /// state is unavailable, which is recorded in the diagnostics
+    pub fn decrypt_msg(
+        &self,
+        kind: FrameKind,
+        mime_type: Option<&str>,
+        stream_codec: Codec,
+        track_id: &[u8],
+        msg: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<DecryptOutcome, WorkerError> {
+        out.clear();
+        let outcome = if msg == NOT_KEYED_FRAME {
+            DecryptOutcome::NotKeyed
+        } else if let Some(keys) = self.media_keys.load().as_deref() {
+            let codec = keys.config.codec_for(kind, mime_type, stream_codec);
+            match keys.decrypt_frame(codec, track_id, msg, out) {
+                Ok(()) => DecryptOutcome::Decrypted,
+                Err(e) => {
+                    debug!("Frame decryption failed: {e}");
+                    out.clear();
+                    keys.config.decrypt_failure_policy.apply(codec, msg, out)
+                }
+            }
+        } else {
+            self.recording_errors(|| {
+                self.with_state(|state| {
+                    let codec = state.media_config.codec_for(kind, mime_type, stream_codec);
+                    Ok(state.decrypt_app_msg_nofail(codec, track_id, msg, out))
+                })
+            })?
+        };
+
+        self.decrypt_stats.record(outcome);
+        Ok(outcome)
+    }
+
+    /// Returns how many frames had each outcome since the session was created
+    pub fn decrypt_stats(&self) -> WorkerResponse {
+        WorkerResponse {
+            decrypt_counts: Some(self.decrypt_stats.counts()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the session's recent diagnostic events. This doesn't acquire the state
+    pub fn diagnostics(&self) -> WorkerResponse {
+        WorkerResponse {
+            diagnostics: Some(self.diagnostics.dump()),
+            ..Default::default()
+        }
+    }
+
+    /// Acquires the state and adds the given user by the key packages they shared
+    pub fn add_user(&self, serialized_kps: &[Vec<u8>]) -> Result<WorkerResponse, WorkerError> {
+        self.recording_errors(|| {
+            let key_pkgs = serialized_kps
+                .iter()
+                .map(|kp| KeyPackageIn::tls_deserialize_exact_bytes(kp))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(WorkerError::deserialization("key package"))?;
+
+            self.with_state(|state| {
+                let resp = state.user_joined(key_pkgs)?;
+                self.publish_media_keys(state);
+                Ok(resp)
+            })
+        })
+    }
+
+    /// Acquires the state and removes the given user by their UID
+    pub fn remove_user(&self, uid_to_remove: &str) -> Result<WorkerResponse, WorkerError> {
+        let uid_bytes = uid_to_remove.as_bytes();
+
+        self.recording_errors(|| {
+            self.with_state(|state| {
+                let resp = state.user_left(uid_bytes)?;
+                self.publish_media_keys(state);
+                Ok(resp)
+            })
+        })
+    }
+
+    /// Acquires the state and joins the group given by the welcome package and ratchet tree
+    pub fn join_group(
+        &self,
+        serialized_welcome: &[u8],
+        serialized_rtree: &[u8],
+    ) -> Result<WorkerResponse, WorkerError> {
+        self.recording_errors(|| {
+            let welcome = MlsMessageIn::tls_deserialize_exact_bytes(serialized_welcome)
+                .map_err(WorkerError::deserialization("Welcome"))?;
+            let ratchet_tree = RatchetTreeIn::tls_deserialize_exact_bytes(serialized_rtree)
+                .map_err(WorkerError::deserialization("ratchet tree"))?;
+
+            self.with_state(|state| {
+                let resp = state.join_group(WelcomePackageIn {
+                    welcome,
+                    ratchet_tree,
+                })?;
+                self.publish_media_keys(state);
+                Ok(resp)
+            })
+        })
+    }
+
+    /// Acquires the state and processes the given Commit message from the given sender
+    pub fn handle_commit(
+        &self,
+        serialized_commit: &[u8],
+        sender_uid: &str,
+    ) -> Result<WorkerResponse, WorkerError> {
+        let uid_bytes = sender_uid.as_bytes().to_vec();
+
+        self.recording_errors(|| {
+            let commit = MlsMessageIn::tls_deserialize_exact_bytes(serialized_commit)
+                .map_err(WorkerError::deserialization("Commit"))?;
+
+            self.with_state(|state| {
+                state.check_initialized()?;
+                // A user cannot process a commit created by themselves. Ignore
+                if state.uid() == uid_bytes {
+                    return Ok(WorkerResponse::default());
+                }
+                let resp = state.handle_commit(commit)?;
+                self.publish_media_keys(state);
+                Ok(resp)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        error::ErrorCode,
+        group_config::CiphersuiteName,
+        padding::{Padding, PaddingPolicy},
+    };
+    use openmls::prelude::tls_codec::Serialize;
+    use rand::{seq::SliceRandom, Rng};
+    use std::num::NonZeroUsize;
+
+    impl WorkerState {
+        fn encrypt_to_vec(&mut self, codec: Codec, track_id: &[u8], msg: &[u8]) -> Vec<u8> {
+            let mut out = Vec::new();
+            self.encrypt_app_msg_nofail(codec, track_id, msg, &mut out);
+            out
+        }
+
+        fn decrypt_to_vec(
+            &mut self,
+            codec: Codec,
+            track_id: &[u8],
+            ct: &[u8],
+        ) -> Result<Vec<u8>, DecryptAppMsgError> {
+            let mut out = Vec::new();
+            self.decrypt_app_msg(codec, track_id, ct, &mut out)?;
+            Ok(out)
+        }
+    }
+
+    // Converts an MlsMessageOut to an MlsMessageIn
+    fn msg_out_to_in(m: &MlsMessageOut) -> MlsMessageIn {
+        let bytes = m.tls_serialize_detached().unwrap();
+        MlsMessageIn::tls_deserialize_exact_bytes(&bytes).unwrap()
+    }
+
+    /// Converts a KeyPackage to a KeyPackageIn
+    fn key_pkg_out_to_in(kp: &KeyPackage) -> KeyPackageIn {
+        KeyPackageIn::tls_deserialize_exact_bytes(&kp.tls_serialize_detached().unwrap()).unwrap()
+    }
+
+    /// Converts the key packages a user shares to KeyPackageIns
+    fn key_pkgs_out_to_in(kps: &[KeyPackageBundle]) -> Vec<KeyPackageIn> {
+        kps.iter()
+            .map(|kp| key_pkg_out_to_in(kp.key_package()))
+            .collect()
+    }
+
+    // Converts a WelcomePackageOut to a WelcomePackageIn
+    fn welcome_out_to_in(wp: &WelcomePackageOut) -> WelcomePackageIn {
+        let WelcomePackageOut {
+            welcome,
+            ratchet_tree,
+        } = wp;
+
+        WelcomePackageIn {
+            welcome: msg_out_to_in(welcome),
+            ratchet_tree: RatchetTreeIn::tls_deserialize_exact_bytes(
+                &ratchet_tree.tls_serialize_detached().unwrap(),
+            )
+            .unwrap(),
+        }
+    }
+
+    /// A message sent from the service provider to users
+    enum Msg {
+        UserJoined(Vec<KeyPackageBundle>),
+        UserLeft(usize),
+        AddRemove(MlsMessageOut),
+        Welcome(WelcomePackageOut),
+    }
+
+    #[derive(Default)]
+    struct TestRoom {
+        /// Users states and current position in message queue (idx being the first message they
+        /// haven't read). If the user has left, this is None
+        states: Vec<Option<(WorkerState, usize)>>,
+        /// UIDs of the users in `states`
+        uids: Vec<Vec<u8>>,
+        /// The message queue
+        messages: Vec<Msg>,
+        /// The media config of every user
+        media_config: MediaConfig,
+    }
+
+    impl TestRoom {
+        /// Makes a new room whose first user has the given UID. Returns their user index (0)
+        fn new(uid: &[u8]) -> (TestRoom, usize) {
+            TestRoom::with_media_config(uid, MediaConfig::default())
+        }
+
+        /// Same as [`TestRoom::new`], but every user in the room uses the given media config
+        fn with_media_config(uid: &[u8], media_config: MediaConfig) -> (TestRoom, usize) {
+            // Make a new state and start a group
+            let (mut state, _) = WorkerState::new(uid.to_vec(), media_config.clone()).unwrap();
+            state.start_group().unwrap();
+
+            (
+                TestRoom {
+                    states: vec![Some((state, 0))],
+                    uids: vec![uid.to_vec()],
+                    messages: Vec::new(),
+                    media_config,
+                },
+                0,
+            )
+        }
+
+        // Returns the queue index of the user who is most behind on their messages
+        fn min_progress(&self) -> usize {
+            // Get all the queue indices of all the users
+            let idxs = self
+                .states
+                .iter()
+                .filter_map(|t| t.as_ref().map(|(_, i)| *i));
+
+            // Take the min
+            idxs.min().expect("all users are dead")
+        }
+
+        /// Unpacks the given worker response and adds it to the message queue. Ordering is
+        /// (welcome, add), (welcome, add), ..., remove
+        fn queue_response(&mut self, resp: WorkerResponse) {
+            let WorkerResponse { adds, remove, .. } = resp;
+
+            for (wp, add) in adds {
+                self.messages.push(Msg::Welcome(wp));
+                self.messages.push(Msg::AddRemove(add));
+            }
+            if let Some(r) = remove {
+                self.messages.push(Msg::AddRemove(r));
+            }
+        }
+
+        /// The user at the given index stops responding. They have not officially left yet though
+        // TODO: this doesn't capture a user dying in between sending a Welcome and an Add
+        fn user_dies(&mut self, idx: usize) {
+            self.states[idx] = None;
+        }
+
+        /// User at the given index joins. Returns the new user's idx and adds the response to the
+        /// message queue
+        fn user_joins(&mut self, uid: &[u8]) -> usize {
+            // Make the new user. Their idx in the queue is the very end
+            let (state, kps) = WorkerState::new(uid.to_vec(), self.media_config.clone()).unwrap();
+            // Add this state to the room states. The index into the queue is the very end
+            self.states.push(Some((state, self.messages.len())));
+            self.uids.push(uid.to_vec());
+
+            // Add this event to the message queue
+            self.messages.push(Msg::UserJoined(kps));
+
+            // New user idx is the last in the vec
+            self.states.len() - 1
+        }
+
+        /// User at the given index leaves. Adds the resulting response to the message queue
+        fn user_leaves(&mut self, idx: usize) {
+            // First kill the user if they're not already dead
+            self.user_dies(idx);
+
+            // Now add the event to the queue
+            self.messages.push(Msg::UserLeft(idx));
+        }
+
+        /// This user processes all the messages in the queue they haven't yet seen
+        fn user_catches_up(&mut self, idx: usize) {
+            // Make a one-hot vector where the desired user will be reading the whole queue
+            let mut num_msgs_to_read = vec![0; self.states.len()];
+            num_msgs_to_read[idx] = self.messages.len();
+            self.users_make_progress(&num_msgs_to_read);
+        }
+
+        /// All users in the room process the messages in the queue they haven't yet seen. They also
+        /// respond to these messages, adding to the queue as necessary. This iterates this process until everyone has seen everything
+        fn all_users_catch_up(&mut self) {
+            // Everyone processes as many messages as possible until the most behind user is fully
+            // up to date
+            while self.min_progress() < self.messages.len() {
+                let num_msgs_to_read = vec![self.messages.len(); self.states.len()];
+                self.users_make_progress(&num_msgs_to_read);
+            }
+        }
+
+        /// Users process the queue in random increments
+        fn users_make_progress_randomly(&mut self, rng: &mut impl Rng) {
+            let queue_len = self.messages.len();
+            // For each user pick a random number of messages to read
+            let num_messages_to_read = self
+                .states
+                .iter()
+                .map(|t| {
+                    if let Some((_, idx)) = t.as_ref() {
+                        rng.gen_range(0..=queue_len - idx)
+                    } else {
+                        // If the user is dead, it doesn't matter what we pick
+                        0
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            self.users_make_progress(&num_messages_to_read);
+        }
+
+        /// All users in the room process the messages in the queue they haven't yet seen. They also
+        /// respond to these messages, adding to the queue as necessary. The user at index `i`
+        /// processes up to `num_messages_to_read[i]` messages from the queue.
+        fn users_make_progress(&mut self, num_messages_to_read: &[usize]) {
+            assert_eq!(num_messages_to_read.len(), self.states.len());
+
+            for (i, &num_msgs) in num_messages_to_read.iter().enumerate() {
+                let Some((ref mut s, next_msg_idx)) = &mut self.states[i] else {
+                    continue;
+                };
+
+                // Go through the unprocessed messages and collect the responses
+                let resps: Vec<WorkerResponse> = self
+                    .messages
+                    .iter()
+                    .skip(*next_msg_idx)
+                    .take(num_msgs)
+                    .filter_map(|msg| {
+                        let out = match msg {
+                            // Join if possible
+                            Msg::Welcome(w) => {
+                                let wp = welcome_out_to_in(w);
+                                s.join_group(wp).unwrap();
+                                None
+                            }
+                            // Process a commit if possible
+                            Msg::AddRemove(commit) => {
+                                s.handle_commit(msg_out_to_in(commit)).unwrap();
+                                None
+                            }
+                            Msg::UserJoined(kps) => {
+                                Some(s.user_joined(key_pkgs_out_to_in(kps)).unwrap())
+                            }
+                            Msg::UserLeft(idx) => {
+                                let uid_to_remove = &self.uids[*idx];
+                                let resp = s.user_left(uid_to_remove).unwrap();
+                                Some(resp)
+                            }
+                        };
+                        // Update the next message index
+                        *next_msg_idx += 1;
+                        out
+                    })
+                    .collect();
+
+                // Queue the responses we collected
+                resps.into_iter().for_each(|r| self.queue_response(r));
+            }
+        }
+
+        /// Does a bunch of encryptions/decryptions of applications messages between parties in this group
+        fn test_app_msg_encryption(&mut self, rng: &mut impl Rng) {
+            let msg = b"hello world";
+
+            // Pick a distinct sender and receiver at random
+            let sender_idx = loop {
+                let idx: usize = rng.gen_range(0..self.states.len());
+                // Only select states of users that have been welcomed
+                if self.states[idx]
+                    .as_ref()
+                    .map(|(s, _)| s.mls_group.is_some())
+                    .unwrap_or(false)
+                {
+                    break idx;
+                }
+            };
+            let receiver_idx = loop {
+                let idx: usize = rng.gen_range(0..self.states.len());
+                if self.states[idx]
+                    .as_ref()
+                    .map(|(s, _)| s.mls_group.is_some())
+                    .unwrap_or(false)
+                    && idx != sender_idx
+                {
+                    break idx;
+                }
+            };
+
+            // Take the sender and receiver states
+            let mut sender = core::mem::take(&mut self.states[sender_idx]);
+            let mut receiver = core::mem::take(&mut self.states[receiver_idx]);
+
+            // Test that we can decrypt messages out of order. We'll deliver a bunch of messages in
+            // a totally random order
+            let group_config = &self.media_config.group;
+            let mut ciphertexts: Vec<_> = (0..core::cmp::min(
+                group_config.out_of_order_tolerance,
+                group_config.max_message_seq_jump,
+            ))
+                .map(|_| {
+                    sender
+                        .as_mut()
+                        .unwrap()
+                        .0
+                        .encrypt_to_vec(Codec::Vp8, b"track", msg)
+                })
+                .collect();
+            let newest = ciphertexts.last().unwrap().clone();
+            ciphertexts.shuffle(&mut rand::thread_rng());
+            // Open the ciphertexts
+            ciphertexts.into_iter().for_each(|ct| {
+                receiver
+                    .as_mut()
+                    .unwrap()
+                    .0
+                    .decrypt_to_vec(Codec::Vp8, b"track", &ct)
+                    .unwrap();
+            });
+
+            // Everyone else in the room gets the frames too. The newest one is enough to keep their
+            // ratchets from falling so far behind that the sender's next frames jump too far ahead
+            for (state, _) in self.states.iter_mut().flatten() {
+                if state.mls_group.is_some() {
+                    state.decrypt_to_vec(Codec::Vp8, b"track", &newest).unwrap();
+                }
+            }
+
+            // Set the sender and receiver states back where they were
+            self.states[sender_idx] = sender;
+            self.states[receiver_idx] = receiver;
+        }
+    }
+
+    #[test]
+    fn normal_join_leave() {
+        let mut rng = rand::thread_rng();
+
+        // Our tests are nondeterministic, so run them a few times to catch possible weird cases
+        for _ in 0..10 {
+            // Alice is the first member
+            let (mut room, alice_idx) = TestRoom::new(b"Alice");
+
+            // Bob joins. Make sure Alice adds him, because she'll leave later
+            let _bob_idx = room.user_joins(b"Bob");
+            room.users_make_progress_randomly(&mut rng);
+            // TODO: Alice might *eventually* add Bob and that'd be okay. This catchup means we miss
+            // some cases
+            room.user_catches_up(alice_idx);
+
+            // Charlie joins
+            let _charlie_idx = room.user_joins(b"Charlie");
+            room.users_make_progress_randomly(&mut rng);
+
+            // Alice leaves
+            room.user_leaves(alice_idx);
+            room.users_make_progress_randomly(&mut rng);
+
+            // Everyone catches up then exchanges some messages
+            room.all_users_catch_up();
+            room.test_app_msg_encryption(&mut rng);
+        }
+    }
+
+    // Tests the case where multiple users join while one user is dead
+    #[test]
+    fn multi_pending() {
+        let mut rng = rand::thread_rng();
+
+        // Our tests are nondeterministic, so run them a few times to catch possible weird cases
+        for _ in 0..10 {
+            // Alice is the first member
+            let (mut room, alice_idx) = TestRoom::new(b"Alice");
+
+            // Bob joins. Make sure Alice adds him, because she'll leave later
+            let bob_idx = room.user_joins(b"Bob");
+            room.users_make_progress_randomly(&mut rng);
+            room.user_catches_up(alice_idx);
+
+            // Charlie and Dave join. Alice isn't responding
+            room.user_dies(alice_idx);
+            let charlie_idx = room.user_joins(b"Charlie");
+            let _dave_idx = room.user_joins(b"Dave");
+
+            // Alice leaves, making Bob the DC. Make sure Bob adds Dave, since Bob will leave later
+            room.user_leaves(alice_idx);
+            room.users_make_progress_randomly(&mut rng);
+            room.user_catches_up(bob_idx);
+
+            // Eve joins
+            let _eve_idx = room.user_joins(b"Eve");
+            room.users_make_progress_randomly(&mut rng);
+
+            // Charlie leaves. Bob is still the DC
+            room.user_leaves(charlie_idx);
+            room.users_make_progress_randomly(&mut rng);
+
+            // Bob leaves. Dave is the DC
+            room.user_leaves(bob_idx);
+            room.users_make_progress_randomly(&mut rng);
+
+            // Everyone catches up then exchanges some messages
+            room.all_users_catch_up();
+            room.test_app_msg_encryption(&mut rng);
+        }
+    }
+
+    // Tests that frame metadata takes precedence over the codec a stream was set up with
+    #[test]
+    fn codec_detection() {
+        let config = MediaConfig::default();
+
+        assert_eq!(
+            config.codec_for(FrameKind::Video, Some("video/AV1"), Codec::Vp8),
+            Codec::Av1
+        );
+        // Unknown or missing codecs fall back to the stream's codec
+        assert_eq!(
+            config.codec_for(FrameKind::Video, Some("video/foo"), Codec::H264),
+            Codec::H264
+        );
+        assert_eq!(
+            config.codec_for(FrameKind::Video, None, Codec::Vp9),
+            Codec::Vp9
+        );
+        // Audio is Opus, unless it's RED
+        assert_eq!(
+            config.codec_for(FrameKind::Audio, Some("audio/opus"), Codec::Vp8),
+            Codec::Opus(AudioPolicy::default())
+        );
+        assert_eq!(
+            config.codec_for(FrameKind::Audio, Some("audio/red"), Codec::Vp8),
+            Codec::Red(AudioPolicy::default())
+        );
+    }
+
+    // Tests that frames with more than one encrypted part, like AV1 frames with several OBUs, make it
+    // through the group intact
+    #[test]
+    fn multi_part_frame() {
+        // Alice and Bob are in a group
+        let (mut room, alice_idx) = TestRoom::new(b"Alice");
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+
+        // A sequence header followed by two frame OBUs
+        let frame = [
+            &[0x0a, 0x03, 0x00, 0x00, 0x00][..],
+            &[0x32, 0x03, 0x01, 0x02, 0x03],
+            &[0x32, 0x02, 0x04, 0x05],
+        ]
+        .concat();
+
+        let ct =
+            room.states[alice_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .encrypt_to_vec(Codec::Av1, b"video", &frame);
+        let pt = room.states[bob_idx]
+            .as_mut()
+            .unwrap()
+            .0
+            .decrypt_to_vec(Codec::Av1, b"video", &ct)
+            .unwrap();
+        assert_eq!(pt, frame);
+    }
+
+    // Tests that the SFrame media format works within an epoch and after the epoch changes
+    #[test]
+    fn sframe_format() {
+        // Alice and Bob are in a group
+        let (mut room, alice_idx) = TestRoom::with_media_config(
+            b"Alice",
+            MediaConfig {
+                media_format: MediaFormat::SFrame,
+                ..Default::default()
+            },
+        );
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+
+        let frame = [
+            0x10, 0x02, 0x00, 0x9d, 0x01, 0x2a, 0x03, 0x04, 0x05, 0x06, 0x07,
+        ];
+        let send = |room: &mut TestRoom, from: usize, to: usize| {
+            let ct =
+                room.states[from]
+                    .as_mut()
+                    .unwrap()
+                    .0
+                    .encrypt_to_vec(Codec::Vp8, b"video", &frame);
+            room.states[to]
+                .as_mut()
+                .unwrap()
+                .0
+                .decrypt_to_vec(Codec::Vp8, b"video", &ct)
+        };
+        assert_eq!(send(&mut room, alice_idx, bob_idx).unwrap(), frame);
+        assert_eq!(send(&mut room, bob_idx, alice_idx).unwrap(), frame);
+
+        // Charlie joins, which moves everyone to a new epoch. Charlie can decrypt frames from both
+        let charlie_idx = room.user_joins(b"Charlie");
+        room.all_users_catch_up();
+        assert_eq!(send(&mut room, alice_idx, charlie_idx).unwrap(), frame);
+        assert_eq!(send(&mut room, bob_idx, charlie_idx).unwrap(), frame);
+        assert_eq!(send(&mut room, charlie_idx, alice_idx).unwrap(), frame);
+    }
+
+    // Tests that padded frames make it through the group intact, and that padding hides their length
+    #[test]
+    fn padded_frames() {
+        // Alice and Bob are in a group, and pad audio to 64-byte buckets
+        let (mut room, alice_idx) = TestRoom::with_media_config(
+            b"Alice",
+            MediaConfig {
+                group: GroupConfig {
+                    padding: PaddingPolicy {
+                        audio: Padding::Bucket(NonZeroUsize::new(64).unwrap()),
+                        video: Padding::None,
+                    },
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+
+        let codec = Codec::Opus(AudioPolicy::EncryptAll);
+        let cts: Vec<_> = [&[0x78; 10][..], &[0x78; 40]]
+            .into_iter()
+            .map(|frame| {
+                let ct = room.states[alice_idx]
+                    .as_mut()
+                    .unwrap()
+                    .0
+                    .encrypt_to_vec(codec, b"audio", frame);
+                let pt = room.states[bob_idx]
+                    .as_mut()
+                    .unwrap()
+                    .0
+                    .decrypt_to_vec(codec, b"audio", &ct)
+                    .unwrap();
+                assert_eq!(pt, frame);
+                ct
+            })
+            .collect();
+        assert_eq!(cts[0].len(), cts[1].len());
+    }
+
+    // Tests that in the MLS format, a frame encrypted for one track can't be passed off as a frame
+    // of another track. SFrame binds the track ID in its own tests
+    #[test]
+    fn wrong_track() {
+        // Alice and Bob are in a group
+        let (mut room, alice_idx) = TestRoom::with_media_config(
+            b"Alice",
+            MediaConfig {
+                media_format: MediaFormat::Mls,
+                ..Default::default()
+            },
+        );
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+
+        let frame = b"hello world";
+        let mut encrypt_for = |track_id: &[u8]| {
+            room.states[alice_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .encrypt_to_vec(Codec::Vp8, track_id, frame)
+        };
+        let ct1 = encrypt_for(b"camera");
+        let ct2 = encrypt_for(b"camera");
+        let ct3 = encrypt_for(b"camera");
+
+        // Bob receives a camera frame on the screenshare track, and another on the camera track
+        let bob = &mut room.states[bob_idx].as_mut().unwrap().0;
+        assert_eq!(
+            bob.decrypt_to_vec(Codec::Vp8, b"screenshare", &ct1),
+            Err(DecryptAppMsgError::WrongTrack)
+        );
+        assert_eq!(
+            bob.decrypt_to_vec(Codec::Vp8, b"camera", &ct2).unwrap(),
+            frame
+        );
+
+        // A failed decryption leaves nothing behind in the output buffer, not even the VP8 header
+        let mut out = b"previous frame".to_vec();
+        bob.decrypt_app_msg_nofail(Codec::Vp8, b"screenshare", &ct3, &mut out);
+        assert_eq!(out, b"previous frame");
+    }
+
+    // Tests that media key snapshots are made once per epoch, and that a snapshot that's still in
+    // use when the epoch changes keeps working
+    #[test]
+    fn media_key_snapshots() {
+        // Alice and Bob are in a group
+        let (mut room, alice_idx) = TestRoom::with_media_config(
+            b"Alice",
+            MediaConfig {
+                media_format: MediaFormat::SFrame,
+                ..Default::default()
+            },
+        );
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+        let snapshot = |room: &TestRoom, idx: usize| {
+            room.states[idx]
+                .as_ref()
+                .unwrap()
+                .0
+                .media_keys
+                .clone()
+                .unwrap()
+        };
+        let alice_keys = snapshot(&room, alice_idx);
+        let bob_keys = snapshot(&room, bob_idx);
+
+        // Alice has nothing pending, so processing her pendings doesn't make a new snapshot
+        let alice = &mut room.states[alice_idx].as_mut().unwrap().0;
+        alice.process_pendings().unwrap();
+        assert!(Arc::ptr_eq(&alice_keys, alice.media_keys.as_ref().unwrap()));
+
+        // Charlie joins, which moves everyone to a new epoch with new snapshots
+        room.user_joins(b"Charlie");
+        room.all_users_catch_up();
+        let new_alice_keys = snapshot(&room, alice_idx);
+        assert_eq!(new_alice_keys.sframe.epoch(), alice_keys.sframe.epoch() + 1);
+
+        // A frame Alice was encrypting with the old snapshot when the epoch changed can still be
+        // decrypted with the old snapshot
+        let frame = b"hello world";
+        let mut ct = Vec::new();
+        alice_keys.encrypt_frame(Codec::Vp8, b"video", frame, &mut ct);
+        let mut pt = Vec::new();
+        bob_keys
+            .decrypt_frame(Codec::Vp8, b"video", &ct, &mut pt)
+            .unwrap();
+        assert_eq!(pt, frame);
+    }
+
+    // Tests that frames still in flight when the epoch changes can be decrypted if past epochs are
+    // retained, in both media formats
+    #[test]
+    fn past_epoch_frames() {
+        for media_format in [MediaFormat::Mls, MediaFormat::SFrame] {
+            for max_past_epochs in [0, 1] {
+                // Alice and Bob are in a group
+                let (mut room, alice_idx) = TestRoom::with_media_config(
+                    b"Alice",
+                    MediaConfig {
+                        media_format,
+                        group: GroupConfig {
+                            max_past_epochs,
+                            ..Default::default()
+                        },
+                        ..Default::default()
+                    },
+                );
+                let bob_idx = room.user_joins(b"Bob");
+                room.all_users_catch_up();
+
+                // Alice sends a frame, but Charlie joins before it gets to Bob
+                let frame = b"hello world";
+                let ct = room.states[alice_idx].as_mut().unwrap().0.encrypt_to_vec(
+                    Codec::Vp8,
+                    b"video",
+                    frame,
+                );
+                room.user_joins(b"Charlie");
+                room.all_users_catch_up();
+
+                let pt = room.states[bob_idx].as_mut().unwrap().0.decrypt_to_vec(
+                    Codec::Vp8,
+                    b"video",
+                    &ct,
+                );
+                if max_past_epochs == 0 {
+                    assert!(pt.is_err());
+                } else {
+                    assert_eq!(pt.unwrap(), frame);
+                }
+            }
+        }
+    }
+
+    // Tests that with a sender delay, frames are sent in the previous epoch until the delay is up
+    #[test]
+    fn sender_delay() {
+        // Alice and Bob are in a group
+        let delay = Duration::from_secs(3600);
+        let (mut room, alice_idx) = TestRoom::with_media_config(
+            b"Alice",
+            MediaConfig {
+                media_format: MediaFormat::SFrame,
+                group: GroupConfig {
+                    max_past_epochs: 1,
+                    ..Default::default()
+                },
+                sender_delay: delay,
+                ..Default::default()
+            },
+        );
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+        let old_epoch = room.states[alice_idx]
+            .as_ref()
+            .unwrap()
+            .0
+            .media_keys
+            .as_ref()
+            .unwrap()
+            .sframe
+            .epoch();
+
+        // Charlie joins. Alice keeps sending in the old epoch, which Bob still has keys for and
+        // Charlie never had keys for
+        let charlie_idx = room.user_joins(b"Charlie");
+        room.all_users_catch_up();
+        let frame = b"hello world";
+        let ct =
+            room.states[alice_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .encrypt_to_vec(Codec::Vp8, b"video", frame);
+        assert_eq!(
+            room.states[bob_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .decrypt_to_vec(Codec::Vp8, b"video", &ct)
+                .unwrap(),
+            frame
+        );
+        assert_eq!(
+            room.states[charlie_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .decrypt_to_vec(Codec::Vp8, b"video", &ct),
+            Err(DecryptAppMsgError::SFrame(SFrameError::WrongEpoch(
+                old_epoch % 16
+            )))
+        );
+
+        // Once the delay is up, Alice switches to the new epoch
+        let keys = room.states[alice_idx]
+            .as_ref()
+            .unwrap()
+            .0
+            .media_keys
+            .clone()
+            .unwrap();
+        assert_eq!(keys.sending_keys(Instant::now()).epoch(), old_epoch);
+        assert_eq!(
+            keys.sending_keys(Instant::now() + delay).epoch(),
+            old_epoch + 1
+        );
+    }
+
+    // Tests that the sender delay doesn't apply to removals, so a removed user can't decrypt the
+    // frames sent right after it's removed
+    #[test]
+    fn no_sender_delay_after_removal() {
+        // Alice, Bob, and Charlie are in a group
+        let (mut room, alice_idx) = TestRoom::with_media_config(
+            b"Alice",
+            MediaConfig {
+                media_format: MediaFormat::SFrame,
+                group: GroupConfig {
+                    max_past_epochs: 1,
+                    ..Default::default()
+                },
+                sender_delay: Duration::from_secs(3600),
+                ..Default::default()
+            },
+        );
+        let bob_idx = room.user_joins(b"Bob");
+        let charlie_idx = room.user_joins(b"Charlie");
+        room.all_users_catch_up();
+
+        // Charlie is removed, but hangs on to his state
+        let (mut charlie, _) = room.states[charlie_idx].take().unwrap();
+        room.user_leaves(charlie_idx);
+        room.all_users_catch_up();
+
+        // Alice sends in the new epoch right away, which Bob can decrypt and Charlie can't
+        let frame = b"hello world";
+        let ct =
+            room.states[alice_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .encrypt_to_vec(Codec::Vp8, b"video", frame);
+        assert_eq!(
+            room.states[bob_idx]
+                .as_mut()
+                .unwrap()
+                .0
+                .decrypt_to_vec(Codec::Vp8, b"video", &ct)
+                .unwrap(),
+            frame
+        );
+        assert!(matches!(
+            charlie.decrypt_to_vec(Codec::Vp8, b"video", &ct),
+            Err(DecryptAppMsgError::SFrame(SFrameError::WrongEpoch(_)))
+        ));
+    }
+
+    // Tests what each decrypt failure policy passes on in place of a frame that can't be decrypted
+    #[test]
+    fn decrypt_failure_policies() {
+        let opus = Codec::Opus(AudioPolicy::EncryptAll);
+        let garbage = [0x78, 0x01, 0x02, 0x03];
+        let silence = {
+            let mut out = Vec::new();
+            assert!(opus.write_concealment_frame(&garbage, &mut out));
+            out
+        };
+
+        for (policy, codec, expected_outcome, expected_frame) in [
+            (
+                DecryptFailurePolicy::Drop,
+                opus,
+                DecryptOutcome::Dropped,
+                &[][..],
+            ),
+            (
+                DecryptFailurePolicy::Passthrough,
+                opus,
+                DecryptOutcome::PassedThrough,
+                &garbage,
+            ),
+            (
+                DecryptFailurePolicy::Conceal,
+                opus,
+                DecryptOutcome::Concealed,
+                &silence,
+            ),
+            // There's no concealment frame for video
+            (
+                DecryptFailurePolicy::Conceal,
+                Codec::Vp8,
+                DecryptOutcome::Dropped,
+                &[],
+            ),
+        ] {
+            let mut state = WorkerState::default();
+            state.media_config.decrypt_failure_policy = policy;
+
+            let mut out = Vec::new();
+            let outcome = state.decrypt_app_msg_nofail(codec, b"audio", &garbage, &mut out);
+            assert_eq!(outcome, expected_outcome);
+            assert_eq!(
+                outcome.has_frame(),
+                policy != DecryptFailurePolicy::Drop && codec == opus
+            );
+            // A frame passed through isn't lost to the decoder, so asking for keyframes won't help
+            assert_eq!(
+                outcome.needs_key_frame(),
+                policy != DecryptFailurePolicy::Passthrough
+            );
+            assert_eq!(out, expected_frame);
+        }
+    }
+
+    // Tests what each pre-group policy sends before there's a group, and that frames are encrypted
+    // once there is one
+    #[test]
+    fn pre_group_policies() {
+        let frame = b"hello world";
+        for (policy, expected_outcome, expected_frame) in [
+            (PreGroupPolicy::Hold, EncryptOutcome::Held, &[][..]),
+            (PreGroupPolicy::Empty, EncryptOutcome::NotKeyed, &[]),
+            (
+                PreGroupPolicy::Marked,
+                EncryptOutcome::NotKeyed,
+                NOT_KEYED_FRAME,
+            ),
+        ] {
+            let mut state = WorkerState::default();
+            state.media_config.pre_group_policy = policy;
+
+            let mut out = Vec::new();
+            let outcome = state.encrypt_app_msg_nofail(Codec::Vp8, b"video", frame, &mut out);
+            assert_eq!(outcome, expected_outcome);
+            assert_eq!(outcome.has_frame(), policy != PreGroupPolicy::Hold);
+            assert_eq!(outcome.epoch(), None);
+            assert_eq!(out, expected_frame);
+        }
+
+        // Receivers skip the marker without counting it as a failure
+        let session = Session::from_state(WorkerState::default());
+        let mut out = Vec::new();
+        let outcome = session
+            .decrypt_msg(
+                FrameKind::Video,
+                None,
+                Codec::Vp8,
+                b"video",
+                NOT_KEYED_FRAME,
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(outcome, DecryptOutcome::NotKeyed);
+        assert!(!outcome.has_frame());
+        assert!(!outcome.is_failure());
+        assert!(!outcome.needs_key_frame());
+        assert_eq!(session.decrypt_stats.counts().not_keyed, 1);
+
+        // Once Alice is in a group, her frames are encrypted in its epoch
+        for media_format in [MediaFormat::Mls, MediaFormat::SFrame] {
+            let (mut room, alice_idx) = TestRoom::with_media_config(
+                b"Alice",
+                MediaConfig {
+                    media_format,
+                    pre_group_policy: PreGroupPolicy::Marked,
+                    ..Default::default()
+                },
+            );
+            let alice = &mut room.states[alice_idx].as_mut().unwrap().0;
+            let epoch = alice.mls_group.as_ref().unwrap().epoch().as_u64();
+
+            let mut out = Vec::new();
+            let outcome = alice.encrypt_app_msg_nofail(Codec::Vp8, b"video", frame, &mut out);
+            assert_eq!(outcome, EncryptOutcome::Encrypted(epoch));
+            assert_ne!(out, NOT_KEYED_FRAME);
+        }
+    }
+
+    // Tests that malformed or unexpected messages from peers are reported as errors, and leave the
+    // state as it was
+    #[test]
+    fn malformed_peer_messages() {
+        // Garbage doesn't even deserialize
+        let session = Session::from_state(WorkerState::default());
+        assert!(matches!(
+            session.add_user(&[b"garbage".to_vec()]),
+            Err(WorkerError::Deserialization {
+                what: "key package",
+                ..
+            })
+        ));
+        assert!(matches!(
+            session.handle_commit(b"garbage", "Mallory"),
+            Err(WorkerError::Deserialization { what: "Commit", .. })
+        ));
+
+        // Alice and Bob are in a group
+        let (mut room, alice_idx) = TestRoom::new(b"Alice");
+        let bob_idx = room.user_joins(b"Bob");
+        room.all_users_catch_up();
+        let (welcome, commit) = match &room.messages[..] {
+            [Msg::UserJoined(_), Msg::Welcome(w), Msg::AddRemove(c)] => {
+                (welcome_out_to_in(w), msg_out_to_in(c))
+            }
+            _ => panic!("unexpected messages"),
+        };
+        let bob = &mut room.states[bob_idx].as_mut().unwrap().0;
+        let app_msg = bob
+            .mls_group
+            .as_mut()
+            .unwrap()
+            .create_message(
+                &bob.mls_provider,
+                bob.my_signing_keys.as_ref().unwrap(),
+                b"hello",
+            )
+            .unwrap();
+        let alice = &mut room.states[alice_idx].as_mut().unwrap().0;
+        let epoch = alice.mls_group.as_ref().unwrap().epoch();
+
+        // A key package whose signature doesn't verify isn't added
+        let (_, charlie_kp) =
+            WorkerState::new(b"Charlie".to_vec(), MediaConfig::default()).unwrap();
+        let mut kp_bytes = charlie_kp[0]
+            .key_package()
+            .tls_serialize_detached()
+            .unwrap();
+        *kp_bytes.last_mut().unwrap() ^= 1;
+        let kp = KeyPackageIn::tls_deserialize_exact_bytes(&kp_bytes).unwrap();
+        assert!(matches!(
+            alice.user_joined(vec![kp]),
+            Err(WorkerError::InvalidKeyPackage(_))
+        ));
+        assert!(alice.pending_adds.is_empty());
+
+        // Nobody can remove themselves
+        assert!(matches!(
+            alice.user_left(b"Alice"),
+            Err(WorkerError::RemoveSelf)
+        ));
+        assert!(alice.pending_removes.is_empty());
+
+        // Only Commits are handled as Commits, and only Welcomes as Welcomes
+        assert!(matches!(
+            alice.handle_commit(msg_out_to_in(&app_msg)),
+            Err(WorkerError::WrongMsgType("Commit"))
+        ));
+        assert!(matches!(
+            alice.handle_commit(welcome.welcome),
+            Err(WorkerError::WrongMsgType("Commit"))
+        ));
+        assert!(matches!(
+            alice.join_group(WelcomePackageIn {
+                welcome: commit,
+                ratchet_tree: welcome.ratchet_tree,
+            }),
+            Err(WorkerError::WrongMsgType("Welcome"))
+        ));
+        assert_eq!(alice.mls_group.as_ref().unwrap().epoch(), epoch);
+
+        // Without an identity, there's nothing to do
+        assert!(matches!(
+            WorkerState::default().user_left(b"Bob"),
+            Err(WorkerError::NotInitialized)
+        ));
+    }
+
+    // Tests that the group's creator pins its parameters in the group context, and that users
+    // welcomed with different parameters take on the group's
+    #[test]
+    fn pinned_group_config() {
+        let config = MediaConfig {
+            group: GroupConfig {
+                max_past_epochs: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let (mut alice, _) = WorkerState::new(b"Alice".to_vec(), config.clone()).unwrap();
+        alice.start_group().unwrap();
+        let group = alice.mls_group.as_ref().unwrap();
+        let mut adopted = config.group.clone();
+        assert_eq!(
+            adopted.adopt_group(group.ciphersuite(), group.extensions()),
+            Ok(())
+        );
+        assert_eq!(adopted, config.group);
+
+        // Out-of-range parameters are rejected up front
+        let invalid = MediaConfig {
+            group: GroupConfig {
+                max_message_seq_jump: 0,
+                ..config.group.clone()
+            },
+            ..config.clone()
+        };
+        assert!(matches!(
+            Session::new("Bob", invalid),
+            Err(WorkerError::InvalidGroupConfig(_))
+        ));
+
+        // Bob keeps fewer past epochs and pads differently than the group does. Alice adds him,
+        // since she can't tell, and he joins with the group's parameters
+        let bob_config = MediaConfig {
+            group: GroupConfig {
+                max_past_epochs: 0,
+                padding: PaddingPolicy::uniform(Padding::None),
+                ..config.group.clone()
+            },
+            ..config.clone()
+        };
+        let (mut bob, bob_kps) = WorkerState::new(b"Bob".to_vec(), bob_config).unwrap();
+        let resp = alice.user_joined(key_pkgs_out_to_in(&bob_kps)).unwrap();
+        let (wp, _) = &resp.adds[0];
+        let resp = bob.join_group(welcome_out_to_in(wp)).unwrap();
+        assert_eq!(resp.new_safety_number, Some(alice.safety_number()));
+        assert_eq!(bob.media_config.group, config.group);
+        assert_eq!(
+            *bob.mls_group.as_ref().unwrap().configuration(),
+            config.group.join_config()
+        );
+
+        // Frames go both ways
+        let frame = b"hello world";
+        let ct = bob.encrypt_to_vec(Codec::Vp8, b"track", frame);
+        assert_eq!(
+            alice.decrypt_to_vec(Codec::Vp8, b"track", &ct).unwrap(),
+            frame
+        );
+        let ct = alice.encrypt_to_vec(Codec::Vp8, b"track", frame);
+        assert_eq!(
+            bob.decrypt_to_vec(Codec::Vp8, b"track", &ct).unwrap(),
+            frame
+        );
+    }
+
+    // Tests that a user whose add can't be committed is dropped, rather than holding up the users
+    // queued after it
+    #[test]
+    fn failed_adds_are_dropped() {
+        let (mut alice, _) = WorkerState::new(b"Alice".to_vec(), MediaConfig::default()).unwrap();
+        alice.start_group().unwrap();
+        let (mut bob, bob_kps) = WorkerState::new(b"Bob".to_vec(), MediaConfig::default()).unwrap();
+
+        // Mallory's key package reuses Alice's signature key, so it can't be added to her group
+        let alice_keys = alice.my_signing_keys.as_ref().unwrap();
+        let mallory_kp = KeyPackage::builder()
+            .leaf_node_capabilities(alice.media_config.group.capabilities())
+            .build(
+                alice.mls_group.as_ref().unwrap().ciphersuite(),
+                &MlsProvider::default(),
+                alice_keys,
+                CredentialWithKey {
+                    credential: BasicCredential::new(b"Mallory".to_vec()).into(),
+                    signature_key: alice_keys.public().into(),
+                },
+            )
+            .unwrap()
+            .key_package()
+            .clone();
+
+        // On its own, it's dropped without changing anything
+        let epoch = alice.mls_group.as_ref().unwrap().epoch();
+        let resp = alice
+            .user_joined(vec![key_pkg_out_to_in(&mallory_kp)])
+            .unwrap();
+        assert!(resp.adds.is_empty());
+        assert_eq!(alice.mls_group.as_ref().unwrap().epoch(), epoch);
+        assert!(alice.pending_adds.is_empty());
+
+        // Queued ahead of Bob, it's dropped, and Bob is still added
+        alice.pending_adds.push(mallory_kp);
+        let resp = alice.user_joined(key_pkgs_out_to_in(&bob_kps)).unwrap();
+        assert!(resp.error.is_none());
+        assert_eq!(resp.adds.len(), 1);
+        assert!(alice.pending_adds.is_empty());
+        let dropped = alice
+            .diagnostics
+            .dump()
+            .into_iter()
+            .filter(|d| d.event == DiagnosticEvent::JoinerDropped)
+            .count();
+        assert_eq!(dropped, 2);
+
+        // Bob joins Alice in her new epoch
+        let (wp, _) = &resp.adds[0];
+        let resp = bob.join_group(welcome_out_to_in(wp)).unwrap();
+        assert_eq!(resp.new_safety_number, Some(alice.safety_number()));
+    }
+
+    // Tests that a committer whose UID can't be sent to the room fails before committing anything
+    #[test]
+    fn non_utf8_uid() {
+        let (mut alice, _) = WorkerState::new(vec![0xff], MediaConfig::default()).unwrap();
+        alice.start_group().unwrap();
+        let (_, bob_kps) = WorkerState::new(b"Bob".to_vec(), MediaConfig::default()).unwrap();
+
+        let epoch = alice.mls_group.as_ref().unwrap().epoch();
+        assert!(matches!(
+            alice.user_joined(key_pkgs_out_to_in(&bob_kps)),
+            Err(WorkerError::MalformedEvent(_))
+        ));
+        assert_eq!(alice.mls_group.as_ref().unwrap().epoch(), epoch);
+        assert!(alice.pending_adds.is_empty());
+    }
+
+    // Tests that users are added in the group's ciphersuite when they accept it, even if it's not
+    // their favorite, and turned away with a clear error when they don't
+    #[test]
+    fn negotiated_ciphersuite() {
+        let accepting = |ciphersuites: &[CiphersuiteName]| MediaConfig {
+            group: GroupConfig {
+                ciphersuites: ciphersuites.to_vec(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        use CiphersuiteName::*;
+
+        // Alice's group is in ChaCha20-Poly1305, her favorite
+        let (mut alice, _) = WorkerState::new(
+            b"Alice".to_vec(),
+            accepting(&[
+                X25519ChaCha20Poly1305Sha256Ed25519,
+                X25519Aes128GcmSha256Ed25519,
+            ]),
+        )
+        .unwrap();
+        alice.start_group().unwrap();
+        let ciphersuite = alice.mls_group.as_ref().unwrap().ciphersuite();
+        assert_eq!(ciphersuite, X25519ChaCha20Poly1305Sha256Ed25519.into());
+
+        // Bob prefers P-256, which has a different signature scheme, but accepts ChaCha20-Poly1305
+        let (mut bob, bob_kps) = WorkerState::new(
+            b"Bob".to_vec(),
+            accepting(&[P256Aes128GcmSha256P256, X25519ChaCha20Poly1305Sha256Ed25519]),
+        )
+        .unwrap();
+        assert_eq!(bob_kps.len(), 2);
+        let resp = alice.user_joined(key_pkgs_out_to_in(&bob_kps)).unwrap();
+        let (wp, _) = &resp.adds[0];
+        bob.join_group(welcome_out_to_in(wp)).unwrap();
+        assert_eq!(bob.mls_group.as_ref().unwrap().ciphersuite(), ciphersuite);
+        assert_eq!(bob.safety_number(), alice.safety_number());
+
+        // Bob signs with the keys of his leaf in the group, so Alice can verify his frames
+        let frame = b"hello world";
+        let ct = bob.encrypt_to_vec(Codec::Vp8, b"video", frame);
+        assert_eq!(
+            alice.decrypt_to_vec(Codec::Vp8, b"video", &ct).unwrap(),
+            frame
+        );
+        let ct = alice.encrypt_to_vec(Codec::Vp8, b"video", frame);
+        assert_eq!(
+            bob.decrypt_to_vec(Codec::Vp8, b"video", &ct).unwrap(),
+            frame
+        );
+
+        // Someone has to offer at least one key package
+        assert!(matches!(
+            alice.user_joined(Vec::new()),
+            Err(WorkerError::IncompatibleKeyPackage(_))
+        ));
+
+        // Charlie only accepts P-256, so neither Alice nor Bob will add him
+        let (_, charlie_kps) =
+            WorkerState::new(b"Charlie".to_vec(), accepting(&[P256Aes128GcmSha256P256])).unwrap();
+        for member in [&mut alice, &mut bob] {
+            let Err(WorkerError::IncompatibleKeyPackage(msg)) =
+                member.user_joined(key_pkgs_out_to_in(&charlie_kps))
+            else {
+                panic!("Charlie's key package was accepted");
+            };
+            assert!(msg.contains("Charlie"), "{msg}");
+            assert!(member.pending_adds.is_empty());
+        }
+
+        // Someone who isn't in the group yet can't tell, so it keeps Charlie pending, but never
+        // adds him once it's the designated committer
+        let (mut dave, dave_kps) = WorkerState::new(
+            b"Dave".to_vec(),
+            accepting(&[X25519ChaCha20Poly1305Sha256Ed25519]),
+        )
+        .unwrap();
+        dave.user_joined(key_pkgs_out_to_in(&charlie_kps)).unwrap();
+        assert_eq!(dave.pending_adds.len(), 1);
+        let resp = alice.user_joined(key_pkgs_out_to_in(&dave_kps)).unwrap();
+        let (wp, add) = &resp.adds[0];
+        bob.handle_commit(msg_out_to_in(add)).unwrap();
+        dave.join_group(welcome_out_to_in(wp)).unwrap();
+        // Once Alice and Bob leave, Dave is the designated committer
+        dave.user_left(b"Alice").unwrap();
+        let resp = dave.user_left(b"Bob").unwrap();
+        assert!(resp.adds.is_empty());
+        assert!(resp.remove.is_some());
+        assert!(dave.pending_adds.is_empty());
+    }
+
+    // Tests that groups work in every ciphersuite
+    #[test]
+    fn every_ciphersuite() {
+        let mut rng = rand::thread_rng();
+        for ciphersuite in CiphersuiteName::ALL {
+            let (mut room, alice_idx) = TestRoom::with_media_config(
+                b"Alice",
+                MediaConfig {
+                    group: GroupConfig {
+                        ciphersuites: vec![ciphersuite],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            );
+            room.user_joins(b"Bob");
+            room.all_users_catch_up();
+            room.user_joins(b"Charlie");
+            room.user_leaves(alice_idx);
+            room.all_users_catch_up();
+            room.test_app_msg_encryption(&mut rng);
+        }
+    }
+
+    // Tests that the hybrid post-quantum ciphersuite survives joins and leaves, in both media
+    // formats, and that it has to be asked for
+    #[test]
+    fn post_quantum_ciphersuite() {
+        let mut rng = rand::thread_rng();
+        for media_format in [MediaFormat::Mls, MediaFormat::SFrame] {
+            let (mut room, alice_idx) = TestRoom::with_media_config(
+                b"Alice",
+                MediaConfig {
+                    media_format,
+                    group: GroupConfig {
+                        ciphersuites: vec![CiphersuiteName::XWingChaCha20Poly1305Sha256Ed25519],
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                },
+            );
+            let bob_idx = room.user_joins(b"Bob");
+            room.user_joins(b"Charlie");
+            room.all_users_catch_up();
+            for (state, _) in room.states.iter().flatten() {
+                assert_eq!(
+                    state.mls_group.as_ref().unwrap().ciphersuite(),
+                    Ciphersuite::MLS_256_XWING_CHACHA20POLY1305_SHA256_Ed25519
+                );
+            }
+            for _ in 0..4 {
+                room.test_app_msg_encryption(&mut rng);
+            }
+
+            // Bob leaves, and then so does Alice, the designated committer, as Dave joins
+            room.user_leaves(bob_idx);
+            room.all_users_catch_up();
+            room.test_app_msg_encryption(&mut rng);
+            room.user_leaves(alice_idx);
+            room.user_joins(b"Dave");
+            room.all_users_catch_up();
+            for _ in 0..4 {
+                room.test_app_msg_encryption(&mut rng);
+            }
+
+            // Someone who didn't opt in has no key package for the group
+            let (_, eve_kps) = WorkerState::new(b"Eve".to_vec(), MediaConfig::default()).unwrap();
+            let (member, _) = room.states.iter_mut().flatten().next().unwrap();
+            assert!(matches!(
+                member.user_joined(key_pkgs_out_to_in(&eve_kps)),
+                Err(WorkerError::IncompatibleKeyPackage(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn decrypt_stats() {
+        let stats = DecryptStats::new();
+        stats.record(DecryptOutcome::Decrypted);
+        stats.record(DecryptOutcome::Decrypted);
+        stats.record(DecryptOutcome::Concealed);
+        assert_eq!(
+            stats.counts(),
+            DecryptCounts {
+                decrypted: 2,
+                concealed: 1,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn diagnostics() {
+        let (alice, _) = Session::new_with_group("Alice", MediaConfig::default()).unwrap();
+        let (bob, joined) = Session::new("Bob", MediaConfig::default()).unwrap();
+        let [HandshakeMessage::KeyPackages(key_pkgs)] = &joined.handshake_messages().unwrap()[..]
+        else {
+            panic!("expected key packages");
+        };
+        let added = alice.add_user(key_pkgs).unwrap();
+        let HandshakeMessage::Welcome { welcome, rtree, .. } =
+            &added.handshake_messages().unwrap()[0]
+        else {
+            panic!("expected a Welcome");
+        };
+        bob.join_group(welcome, rtree).unwrap();
+
+        // Bob isn't the DC, so he leaves Charlie's removal to Alice
+        bob.remove_user("Charlie").unwrap();
+        assert!(alice.handle_commit(b"garbage", "Bob").is_err());
+
+        let events = |session: &Session| -> Vec<DiagnosticEvent> {
+            let diagnostics = session.diagnostics().diagnostics.unwrap();
+            diagnostics.into_iter().map(|d| d.event).collect()
+        };
+        assert_eq!(
+            events(&alice),
+            [
+                DiagnosticEvent::EpochChanged {
+                    epoch: 0,
+                    members: 1
+                },
+                DiagnosticEvent::Committed {
+                    added: 1,
+                    removed: 0
+                },
+                DiagnosticEvent::EpochChanged {
+                    epoch: 1,
+                    members: 2
+                },
+                DiagnosticEvent::Error {
+                    code: ErrorCode::MalformedMessage
+                },
+            ]
+        );
+        assert_eq!(
+            events(&bob),
+            [
+                DiagnosticEvent::EpochChanged {
+                    epoch: 1,
+                    members: 2
+                },
+                DiagnosticEvent::Deferred {
+                    pending_adds: 0,
+                    pending_removes: 1
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,341 @@
+//! Length padding of frame plaintexts. Opus is usually run in VBR mode, so the length of an audio
+//! frame says a fair amount about what's being said, and encryption preserves that length. Padding
+//! each plaintext before it's encrypted hides some of it, at the cost of bandwidth. Padded
+//! plaintexts end in a 0x80 marker followed by zeros, so the padding can be stripped after
+//! decryption without knowing how much was added. Both sides must use the same policy.
+
+use std::{borrow::Cow, fmt, num::NonZeroUsize};
+
+use serde::{
+    de::{self, MapAccess, Visitor},
+    Deserialize, Deserializer,
+};
+use thiserror::Error;
+use ts_rs::TS;
+
+use crate::framing::FrameKind;
+
+/// Error incurred when a decrypted plaintext doesn't end in valid padding
+#[derive(Error, Debug, PartialEq, Clone)]
+#[error("invalid padding")]
+pub struct InvalidPadding;
+
+/// The byte that marks the start of the padding
+const MARKER: u8 = 0x80;
+
+/// The largest bucket size. A frame is rarely bigger than this, so bigger buckets would only waste
+/// bandwidth, and a huge one would make padding a tiny frame allocate gigabytes
+pub const MAX_BUCKET_SIZE: usize = 64 * 1024;
+
+/// How to pad plaintexts. In events from the main thread, this is `"none"`, `"padme"`, or a bucket
+/// size of at most 64 KiB
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TS)]
+#[ts(export_to = "E2eeProtocol.ts", type = "'none' | 'padme' | number")]
+pub enum Padding {
+    /// Don't pad at all, not even with a marker
+    #[default]
+    None,
+    /// Pad to the next Padmé length, which leaks at most O(log log n) bits of the length n and
+    /// costs at most 12% overhead
+    Padme,
+    /// Pad to the next multiple of the given size, which is at most [`MAX_BUCKET_SIZE`]
+    Bucket(NonZeroUsize),
+}
+
+impl Padding {
+    /// Returns the length that a plaintext of length `len`, including the marker, is padded to
+    fn padded_len(self, len: usize) -> usize {
+        match self {
+            Padding::None => len,
+            Padding::Padme => padme(len),
+            // This can only overflow for a plaintext that doesn't fit in memory anyway, in which
+            // case it's left unpadded rather than panicking
+            Padding::Bucket(size) => len
+                .div_ceil(size.get())
+                .checked_mul(size.get())
+                .unwrap_or(len),
+        }
+    }
+
+    /// Pads the given plaintext. This is a no-op if the padding is [`Padding::None`]. Where the
+    /// padded plaintext can go straight into a bigger buffer, [`Padding::pad_into`] saves a copy
+    pub fn pad(self, pt: &[u8]) -> Cow<'_, [u8]> {
+        if self == Padding::None {
+            return Cow::Borrowed(pt);
+        }
+
+        let mut padded = Vec::new();
+        self.pad_into(pt, &mut padded);
+        Cow::Owned(padded)
+    }
+
+    /// Appends the given plaintext to `out`, padded like [`Padding::pad`] pads it
+    pub fn pad_into(self, pt: &[u8], out: &mut Vec<u8>) {
+        if self == Padding::None {
+            return out.extend_from_slice(pt);
+        }
+
+        let padded_len = self.padded_len(pt.len() + 1);
+        let start = out.len();
+        out.reserve(padded_len);
+        out.extend_from_slice(pt);
+        out.push(MARKER);
+        out.resize(start + padded_len, 0);
+    }
+
+    /// Returns the length of the given plaintext once the padding added by [`Padding::pad`] is
+    /// stripped
+    pub fn unpadded_len(self, pt: &[u8]) -> Result<usize, InvalidPadding> {
+        if self == Padding::None {
+            return Ok(pt.len());
+        }
+
+        let marker_idx = pt.iter().rposition(|&b| b != 0).ok_or(InvalidPadding)?;
+        if pt[marker_idx] != MARKER {
+            return Err(InvalidPadding);
+        }
+        Ok(marker_idx)
+    }
+}
+
+/// Returns the Padmé length (Nikitin et al., "Reducing Metadata Leakage from Encrypted Files and
+/// Communication with PURBs") of `len`. This keeps the top log2(log2(len)) + 1 bits of `len` and
+/// rounds the rest up
+fn padme(len: usize) -> usize {
+    if len < 2 {
+        return len;
+    }
+
+    let e = len.ilog2();
+    let s = e.ilog2() + 1;
+    let mask = (1 << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+/// How to pad audio and video frames. In events from the main thread, this is either one padding
+/// for both kinds of frames, or an object with an optional padding per kind. Everyone in a room must
+/// use the same policy
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, TS)]
+#[ts(
+    export_to = "E2eeProtocol.ts",
+    type = "Padding | { audio?: Padding; video?: Padding }"
+)]
+pub struct PaddingPolicy {
+    pub audio: Padding,
+    pub video: Padding,
+}
+
+impl PaddingPolicy {
+    /// Returns the policy that pads both kinds of frames the same way
+    pub fn uniform(padding: Padding) -> PaddingPolicy {
+        PaddingPolicy {
+            audio: padding,
+            video: padding,
+        }
+    }
+
+    /// Returns the padding for frames of the given kind
+    pub fn for_kind(self, kind: FrameKind) -> Padding {
+        match kind {
+            FrameKind::Audio => self.audio,
+            FrameKind::Video => self.video,
+        }
+    }
+}
+
+/// Parses a [`Padding`] from its name or bucket size. Also parses a uniform [`PaddingPolicy`]
+struct PaddingVisitor;
+
+impl<'de> Visitor<'de> for PaddingVisitor {
+    type Value = Padding;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "\"none\", \"padme\", or an integer from 1 to {MAX_BUCKET_SIZE}"
+        )
+    }
+
+    fn visit_str<E: de::Error>(self, name: &str) -> Result<Padding, E> {
+        match name {
+            "none" => Ok(Padding::None),
+            "padme" => Ok(Padding::Padme),
+            _ => Err(E::unknown_variant(name, &["none", "padme"])),
+        }
+    }
+
+    fn visit_u64<E: de::Error>(self, size: u64) -> Result<Padding, E> {
+        usize::try_from(size)
+            .ok()
+            .filter(|&size| size <= MAX_BUCKET_SIZE)
+            .and_then(NonZeroUsize::new)
+            .map(Padding::Bucket)
+            .ok_or_else(|| E::invalid_value(de::Unexpected::Unsigned(size), &self))
+    }
+
+    fn visit_i64<E: de::Error>(self, size: i64) -> Result<Padding, E> {
+        let size = u64::try_from(size)
+            .map_err(|_| E::invalid_value(de::Unexpected::Signed(size), &self))?;
+        self.visit_u64(size)
+    }
+
+    fn visit_f64<E: de::Error>(self, size: f64) -> Result<Padding, E> {
+        // JS numbers are all floats
+        if size.fract() != 0.0 || size < 0.0 || size > u64::MAX as f64 {
+            return Err(E::invalid_value(de::Unexpected::Float(size), &self));
+        }
+        self.visit_u64(size as u64)
+    }
+
+    /// An undefined padding, e.g., of a frame kind that's left out, is no padding
+    fn visit_unit<E: de::Error>(self) -> Result<Padding, E> {
+        Ok(Padding::None)
+    }
+}
+
+impl<'de> Deserialize<'de> for Padding {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Padding, D::Error> {
+        d.deserialize_any(PaddingVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for PaddingPolicy {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<PaddingPolicy, D::Error> {
+        /// A policy with a padding per frame kind
+        #[derive(Deserialize)]
+        #[serde(deny_unknown_fields)]
+        struct PerKind {
+            #[serde(default)]
+            audio: Padding,
+            #[serde(default)]
+            video: Padding,
+        }
+
+        struct PolicyVisitor;
+
+        impl<'de> Visitor<'de> for PolicyVisitor {
+            type Value = PaddingPolicy;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a padding, or an object with a padding for audio and video")
+            }
+
+            fn visit_str<E: de::Error>(self, name: &str) -> Result<PaddingPolicy, E> {
+                PaddingVisitor.visit_str(name).map(PaddingPolicy::uniform)
+            }
+
+            fn visit_u64<E: de::Error>(self, size: u64) -> Result<PaddingPolicy, E> {
+                PaddingVisitor.visit_u64(size).map(PaddingPolicy::uniform)
+            }
+
+            fn visit_i64<E: de::Error>(self, size: i64) -> Result<PaddingPolicy, E> {
+                PaddingVisitor.visit_i64(size).map(PaddingPolicy::uniform)
+            }
+
+            fn visit_f64<E: de::Error>(self, size: f64) -> Result<PaddingPolicy, E> {
+                PaddingVisitor.visit_f64(size).map(PaddingPolicy::uniform)
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<PaddingPolicy, A::Error> {
+                let per_kind = PerKind::deserialize(de::value::MapAccessDeserializer::new(map))?;
+                Ok(PaddingPolicy {
+                    audio: per_kind.audio,
+                    video: per_kind.video,
+                })
+            }
+        }
+
+        d.deserialize_any(PolicyVisitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padme_lengths() {
+        assert_eq!(padme(0), 0);
+        assert_eq!(padme(1), 1);
+        assert_eq!(padme(3), 3);
+        assert_eq!(padme(9), 10);
+        assert_eq!(padme(100), 104);
+        assert_eq!(padme(1000), 1024);
+        // Padmé never adds more than 12%
+        for len in 1..10_000 {
+            assert!(padme(len) >= len);
+            assert!(padme(len) * 100 <= len * 112);
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let bucket = Padding::Bucket(NonZeroUsize::new(16).unwrap());
+        for padding in [Padding::None, Padding::Padme, bucket] {
+            for pt in [&[][..], &[0x00], &[0x80, 0x00], &[0x01; 40]] {
+                let padded = padding.pad(pt);
+                assert_eq!(padded.len(), padding.padded_len(padded.len()));
+                assert_eq!(padded[..padding.unpadded_len(&padded).unwrap()], *pt);
+            }
+        }
+
+        // Padding into a buffer appends the same thing
+        let mut out = vec![1, 2, 3];
+        bucket.pad_into(&[0x01; 3], &mut out);
+        assert_eq!(out[..3], [1, 2, 3]);
+        assert_eq!(out[3..], *bucket.pad(&[0x01; 3]));
+
+        // Buckets hide the length within a bucket
+        assert_eq!(bucket.pad(&[0x01; 3]).len(), 16);
+        assert_eq!(bucket.pad(&[0x01; 15]).len(), 16);
+        assert_eq!(bucket.pad(&[0x01; 16]).len(), 32);
+
+        // The length of a plaintext that would overflow is left as it is
+        let biggest = Padding::Bucket(NonZeroUsize::new(MAX_BUCKET_SIZE).unwrap());
+        assert_eq!(biggest.padded_len(usize::MAX), usize::MAX);
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(Padding::Padme.unpadded_len(&[]), Err(InvalidPadding));
+        assert_eq!(
+            Padding::Padme.unpadded_len(&[0x00, 0x00]),
+            Err(InvalidPadding)
+        );
+        assert_eq!(
+            Padding::Padme.unpadded_len(&[0x80, 0x01]),
+            Err(InvalidPadding)
+        );
+    }
+
+    #[test]
+    fn deserialize() {
+        use serde::de::{value::Error, IntoDeserializer};
+        use std::collections::BTreeMap;
+
+        fn parse<'de, T: IntoDeserializer<'de, Error>>(v: T) -> Result<PaddingPolicy, Error> {
+            PaddingPolicy::deserialize(v.into_deserializer())
+        }
+        let bucket = Padding::Bucket(NonZeroUsize::new(16).unwrap());
+
+        assert_eq!(parse("padme"), Ok(PaddingPolicy::uniform(Padding::Padme)));
+        assert_eq!(parse(16u64), Ok(PaddingPolicy::uniform(bucket)));
+        assert_eq!(parse(16.0f64), Ok(PaddingPolicy::uniform(bucket)));
+        assert_eq!(
+            parse(BTreeMap::from([("audio", "padme")])),
+            Ok(PaddingPolicy {
+                audio: Padding::Padme,
+                video: Padding::None
+            })
+        );
+
+        assert!(parse("pad").is_err());
+        assert!(parse(0u64).is_err());
+        assert!(parse(MAX_BUCKET_SIZE as u64).is_ok());
+        assert!(parse(MAX_BUCKET_SIZE as u64 + 1).is_err());
+        assert!(parse(u64::MAX).is_err());
+        assert!(parse(16.5f64).is_err());
+        assert!(parse(-16i64).is_err());
+        assert!(parse(BTreeMap::from([("screen", "padme")])).is_err());
+    }
+}
@@ -0,0 +1,288 @@
+//! The OpenMLS provider the worker runs on. It's OpenMLS's RustCrypto provider, plus HPKE with the
+//! X-Wing KEM, which RustCrypto doesn't do, from libcrux. That's all the hybrid post-quantum
+//! ciphersuite needs beyond the others: its AEAD, hash, and signature scheme are the same as those
+//! of the X25519 ciphersuites.
+
+use hpke_rs::{Hpke, HpkeError, Mode};
+use hpke_rs_crypto::types::{AeadAlgorithm, KdfAlgorithm, KemAlgorithm};
+use hpke_rs_libcrux::HpkeLibcrux;
+use openmls::prelude::{
+    tls_codec::SecretVLBytes, AeadType, Ciphersuite, CryptoError, ExporterSecret, HashType,
+    HpkeAeadType, HpkeCiphertext, HpkeConfig, HpkeKdfType, HpkeKemType, HpkeKeyPair, OpenMlsCrypto,
+    OpenMlsProvider, OpenMlsRand, SignatureScheme,
+};
+use openmls_rust_crypto::{MemoryStorage, RandError, RustCrypto};
+
+/// The hybrid post-quantum ciphersuite, the one ciphersuite RustCrypto can't run on its own
+const XWING_CIPHERSUITE: Ciphersuite = Ciphersuite::MLS_256_XWING_CHACHA20POLY1305_SHA256_Ed25519;
+
+/// Keeps MLS state in memory, and does its crypto with [`HybridCrypto`]
+#[derive(Debug, Default)]
+pub struct MlsProvider {
+    crypto: HybridCrypto,
+    storage: MemoryStorage,
+}
+
+impl OpenMlsProvider for MlsProvider {
+    type CryptoProvider = HybridCrypto;
+    type RandProvider = HybridCrypto;
+    type StorageProvider = MemoryStorage;
+
+    fn storage(&self) -> &MemoryStorage {
+        &self.storage
+    }
+
+    fn crypto(&self) -> &HybridCrypto {
+        &self.crypto
+    }
+
+    fn rand(&self) -> &HybridCrypto {
+        &self.crypto
+    }
+}
+
+/// RustCrypto, except that HPKE with the X-Wing KEM is done by libcrux
+#[derive(Debug, Default)]
+pub struct HybridCrypto(RustCrypto);
+
+/// Returns an HPKE instance for the given config if its KEM is X-Wing, or `None` if RustCrypto
+/// should handle it
+fn xwing_hpke(config: &HpkeConfig) -> Option<Hpke<HpkeLibcrux>> {
+    if config.0 != HpkeKemType::XWingKemDraft6 {
+        return None;
+    }
+    let kdf = match config.1 {
+        HpkeKdfType::HkdfSha256 => KdfAlgorithm::HkdfSha256,
+        HpkeKdfType::HkdfSha384 => KdfAlgorithm::HkdfSha384,
+        HpkeKdfType::HkdfSha512 => KdfAlgorithm::HkdfSha512,
+    };
+    let aead = match config.2 {
+        HpkeAeadType::AesGcm128 => AeadAlgorithm::Aes128Gcm,
+        HpkeAeadType::AesGcm256 => AeadAlgorithm::Aes256Gcm,
+        HpkeAeadType::ChaCha20Poly1305 => AeadAlgorithm::ChaCha20Poly1305,
+        HpkeAeadType::Export => AeadAlgorithm::HpkeExport,
+    };
+    Some(Hpke::new(Mode::Base, KemAlgorithm::XWingDraft06, kdf, aead))
+}
+
+impl OpenMlsCrypto for HybridCrypto {
+    fn supports(&self, ciphersuite: Ciphersuite) -> Result<(), CryptoError> {
+        if ciphersuite == XWING_CIPHERSUITE {
+            Ok(())
+        } else {
+            self.0.supports(ciphersuite)
+        }
+    }
+
+    fn supported_ciphersuites(&self) -> Vec<Ciphersuite> {
+        let mut ciphersuites = self.0.supported_ciphersuites();
+        ciphersuites.push(XWING_CIPHERSUITE);
+        ciphersuites
+    }
+
+    fn hkdf_extract(
+        &self,
+        hash_type: HashType,
+        salt: &[u8],
+        ikm: &[u8],
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.0.hkdf_extract(hash_type, salt, ikm)
+    }
+
+    fn hmac(
+        &self,
+        hash_type: HashType,
+        key: &[u8],
+        message: &[u8],
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.0.hmac(hash_type, key, message)
+    }
+
+    fn hkdf_expand(
+        &self,
+        hash_type: HashType,
+        prk: &[u8],
+        info: &[u8],
+        okm_len: usize,
+    ) -> Result<SecretVLBytes, CryptoError> {
+        self.0.hkdf_expand(hash_type, prk, info, okm_len)
+    }
+
+    fn hash(&self, hash_type: HashType, data: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.hash(hash_type, data)
+    }
+
+    fn aead_encrypt(
+        &self,
+        alg: AeadType,
+        key: &[u8],
+        data: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.0.aead_encrypt(alg, key, data, nonce, aad)
+    }
+
+    fn aead_decrypt(
+        &self,
+        alg: AeadType,
+        key: &[u8],
+        ct_tag: &[u8],
+        nonce: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        self.0.aead_decrypt(alg, key, ct_tag, nonce, aad)
+    }
+
+    fn signature_key_gen(&self, alg: SignatureScheme) -> Result<(Vec<u8>, Vec<u8>), CryptoError> {
+        self.0.signature_key_gen(alg)
+    }
+
+    fn verify_signature(
+        &self,
+        alg: SignatureScheme,
+        data: &[u8],
+        pk: &[u8],
+        signature: &[u8],
+    ) -> Result<(), CryptoError> {
+        self.0.verify_signature(alg, data, pk, signature)
+    }
+
+    fn sign(&self, alg: SignatureScheme, data: &[u8], key: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        self.0.sign(alg, data, key)
+    }
+
+    fn hpke_seal(
+        &self,
+        config: HpkeConfig,
+        pk_r: &[u8],
+        info: &[u8],
+        aad: &[u8],
+        ptxt: &[u8],
+    ) -> Result<HpkeCiphertext, CryptoError> {
+        let Some(mut hpke) = xwing_hpke(&config) else {
+            return self.0.hpke_seal(config, pk_r, info, aad, ptxt);
+        };
+        let (kem_output, ciphertext) = hpke
+            .seal(&pk_r.into(), info, aad, ptxt, None, None, None)
+            .map_err(|e| match e {
+            HpkeError::InvalidInput => CryptoError::InvalidLength,
+            _ => CryptoError::CryptoLibraryError,
+        })?;
+        Ok(HpkeCiphertext {
+            kem_output: kem_output.into(),
+            ciphertext: ciphertext.into(),
+        })
+    }
+
+    fn hpke_open(
+        &self,
+        config: HpkeConfig,
+        input: &HpkeCiphertext,
+        sk_r: &[u8],
+        info: &[u8],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, CryptoError> {
+        let Some(hpke) = xwing_hpke(&config) else {
+            return self.0.hpke_open(config, input, sk_r, info, aad);
+        };
+        hpke.open(
+            input.kem_output.as_slice(),
+            &sk_r.into(),
+            info,
+            aad,
+            input.ciphertext.as_slice(),
+            None,
+            None,
+            None,
+        )
+        .map_err(|_| CryptoError::HpkeDecryptionError)
+    }
+
+    fn hpke_setup_sender_and_export(
+        &self,
+        config: HpkeConfig,
+        pk_r: &[u8],
+        info: &[u8],
+        exporter_context: &[u8],
+        exporter_length: usize,
+    ) -> Result<(Vec<u8>, ExporterSecret), CryptoError> {
+        let Some(mut hpke) = xwing_hpke(&config) else {
+            return self.0.hpke_setup_sender_and_export(
+                config,
+                pk_r,
+                info,
+                exporter_context,
+                exporter_length,
+            );
+        };
+        let (kem_output, context) = hpke
+            .setup_sender(&pk_r.into(), info, None, None, None)
+            .map_err(|_| CryptoError::SenderSetupError)?;
+        let exported_secret = context
+            .export(exporter_context, exporter_length)
+            .map_err(|_| CryptoError::ExporterError)?;
+        Ok((kem_output, exported_secret.into()))
+    }
+
+    fn hpke_setup_receiver_and_export(
+        &self,
+        config: HpkeConfig,
+        enc: &[u8],
+        sk_r: &[u8],
+        info: &[u8],
+        exporter_context: &[u8],
+        exporter_length: usize,
+    ) -> Result<ExporterSecret, CryptoError> {
+        let Some(hpke) = xwing_hpke(&config) else {
+            return self.0.hpke_setup_receiver_and_export(
+                config,
+                enc,
+                sk_r,
+                info,
+                exporter_context,
+                exporter_length,
+            );
+        };
+        let context = hpke
+            .setup_receiver(enc, &sk_r.into(), info, None, None, None)
+            .map_err(|_| CryptoError::ReceiverSetupError)?;
+        let exported_secret = context
+            .export(exporter_context, exporter_length)
+            .map_err(|_| CryptoError::ExporterError)?;
+        Ok(exported_secret.into())
+    }
+
+    fn derive_hpke_keypair(
+        &self,
+        config: HpkeConfig,
+        ikm: &[u8],
+    ) -> Result<HpkeKeyPair, CryptoError> {
+        let Some(hpke) = xwing_hpke(&config) else {
+            return self.0.derive_hpke_keypair(config, ikm);
+        };
+        let (private, public) = hpke
+            .derive_key_pair(ikm)
+            .map_err(|e| match e {
+                HpkeError::InvalidInput => CryptoError::InvalidLength,
+                _ => CryptoError::CryptoLibraryError,
+            })?
+            .into_keys();
+        Ok(HpkeKeyPair {
+            private: private.as_slice().into(),
+            public: public.as_slice().into(),
+        })
+    }
+}
+
+impl OpenMlsRand for HybridCrypto {
+    type Error = RandError;
+
+    fn random_array<const N: usize>(&self) -> Result<[u8; N], RandError> {
+        self.0.random_array()
+    }
+
+    fn random_vec(&self, len: usize) -> Result<Vec<u8>, RandError> {
+        self.0.random_vec(len)
+    }
+}
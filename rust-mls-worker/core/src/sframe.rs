@@ -0,0 +1,593 @@
+//! SFrame (RFC 9605) protection of media frames, keyed from the MLS group. Rather than wrapping
+//! every frame in an MLS application message, which carries a signature and a lot of framing, each
+//! member derives an SFrame base key per epoch from the MLS exporter (RFC 9605 §5.2) and protects
+//! frames with a compact header and a single AEAD operation.
+
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+};
+
+use aes_gcm::{aead::AeadInPlace, Aes128Gcm, KeyInit};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use thiserror::Error;
+
+/// The SFrame cipher suite AES_128_GCM_SHA256_128. Media is always protected with this suite,
+/// whatever the group's MLS ciphersuite: RFC 9605 defines no ChaCha20-Poly1305 suite, so a group in
+/// a ChaCha20 or X-Wing ciphersuite still runs AES-128-GCM on every frame. Only the base keys come
+/// from that ciphersuite's exporter
+const CIPHER_SUITE: u16 = 0x0004;
+/// The size of a key of the AEAD
+const AEAD_NK: usize = 16;
+/// The size of a nonce of the AEAD
+const AEAD_NN: usize = 12;
+/// The size of an authentication tag of the AEAD
+const AEAD_NT: usize = 16;
+/// The output size of the KDF's hash
+const KDF_NH: usize = 32;
+
+/// The longest an SFrame header can be: a config byte, then an 8-byte KID and an 8-byte counter
+const MAX_HEADER_LEN: usize = 17;
+
+/// How many low bits of a KID hold the epoch. Receivers use this to tell frames from the previous
+/// or next epoch apart from frames of the current one
+const EPOCH_BITS: u32 = 4;
+
+/// Error incurred when an SFrame ciphertext can't be decrypted
+#[derive(Error, Debug, PartialEq, Clone)]
+pub enum SFrameError {
+    #[error("malformed SFrame header")]
+    MalformedHeader,
+
+    #[error("frame is from epoch {0} (mod {max}), not the current one", max = 1 << EPOCH_BITS)]
+    WrongEpoch(u64),
+
+    #[error("frame is from a sender who isn't in the epoch")]
+    UnknownSender,
+
+    #[error("SFrame authentication failed")]
+    Authentication,
+
+    #[error("frame was already received, or is too old to tell")]
+    Replayed,
+}
+
+/// Returns the number of bytes needed to encode `value` in big-endian, with a minimum of 1
+fn encoded_len(value: u64) -> usize {
+    (8 - value.leading_zeros() as usize / 8).max(1)
+}
+
+/// An encoded SFrame header, kept on the stack
+struct Header {
+    bytes: [u8; MAX_HEADER_LEN],
+    len: usize,
+}
+
+impl Deref for Header {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// Encodes an SFrame header (RFC 9605 §4.3) with the given KID and counter
+fn encode_header(kid: u64, ctr: u64) -> Header {
+    let mut header = Header {
+        bytes: [0; MAX_HEADER_LEN],
+        len: 1,
+    };
+
+    // Values under 8 fit in the config byte. Anything else follows it, with its length in the
+    // config byte
+    for (value, shift) in [(kid, 4), (ctr, 0)] {
+        if value < 8 {
+            header.bytes[0] |= (value as u8) << shift;
+        } else {
+            let len = encoded_len(value);
+            header.bytes[0] |= (0b1000 | (len - 1) as u8) << shift;
+            header.bytes[header.len..header.len + len]
+                .copy_from_slice(&value.to_be_bytes()[8 - len..]);
+            header.len += len;
+        }
+    }
+
+    header
+}
+
+thread_local! {
+    /// Scratch space for the AAD, which the AEAD wants as a single slice. Reused so that frames
+    /// don't allocate
+    static AAD: RefCell<Vec<u8>> = RefCell::default();
+}
+
+/// Calls `f` with the AAD of a frame: its SFrame header followed by its metadata
+fn with_aad<T>(header: &[u8], metadata: &[u8], f: impl FnOnce(&[u8]) -> T) -> T {
+    AAD.with_borrow_mut(|aad| {
+        aad.clear();
+        aad.extend_from_slice(header);
+        aad.extend_from_slice(metadata);
+        f(aad)
+    })
+}
+
+/// Parses an SFrame header at the start of `data`. Returns the KID, the counter, and the length of
+/// the header.
+fn parse_header(data: &[u8]) -> Option<(u64, u64, usize)> {
+    let &config = data.first()?;
+    let mut header_len = 1;
+
+    let mut read_value = |nibble: u8| {
+        if nibble & 0b1000 == 0 {
+            return Some(u64::from(nibble));
+        }
+        let len = usize::from(nibble & 0b111) + 1;
+        let bytes = data.get(header_len..header_len + len)?;
+        header_len += len;
+        Some(bytes.iter().fold(0, |acc, &b| (acc << 8) | u64::from(b)))
+    };
+    let kid = read_value(config >> 4)?;
+    let ctr = read_value(config & 0b1111)?;
+
+    Some((kid, ctr, header_len))
+}
+
+/// The AEAD key and salt derived from a sender's base key (RFC 9605 §4.4.2)
+struct KeyAndSalt {
+    cipher: Aes128Gcm,
+    salt: [u8; AEAD_NN],
+}
+
+impl KeyAndSalt {
+    fn derive(kid: u64, base_key: &[u8]) -> KeyAndSalt {
+        let hkdf = Hkdf::<Sha256>::new(None, base_key);
+        let label = |name: &str| {
+            [
+                name.as_bytes(),
+                &kid.to_be_bytes(),
+                &CIPHER_SUITE.to_be_bytes(),
+            ]
+            .concat()
+        };
+
+        let mut key = [0; AEAD_NK];
+        let mut salt = [0; AEAD_NN];
+        hkdf.expand(&label("SFrame 1.0 Secret key "), &mut key)
+            .unwrap();
+        hkdf.expand(&label("SFrame 1.0 Secret salt "), &mut salt)
+            .unwrap();
+
+        KeyAndSalt {
+            cipher: Aes128Gcm::new(&key.into()),
+            salt,
+        }
+    }
+
+    /// Returns the nonce for the frame with the given counter
+    fn nonce(&self, ctr: u64) -> [u8; AEAD_NN] {
+        let mut nonce = self.salt;
+        for (n, c) in nonce[AEAD_NN - 8..].iter_mut().zip(ctr.to_be_bytes()) {
+            *n ^= c;
+        }
+        nonce
+    }
+}
+
+/// Which frames were recently received from one sender, to catch replayed frames (RFC 9605 §9.3).
+/// Frames can arrive out of order, so a frame is accepted if it's at most the window size older
+/// than the newest one and hasn't been received yet.
+struct ReplayWindow {
+    size: u64,
+    /// The counter of the newest frame received, if any
+    newest: Option<u64>,
+    /// A ring of bits, one per counter, set for the counters in the window that were received
+    received: Vec<u64>,
+}
+
+impl ReplayWindow {
+    fn new(size: u32) -> ReplayWindow {
+        let size = u64::from(size);
+        ReplayWindow {
+            size,
+            newest: None,
+            // One more bit than the window size, for the newest frame itself
+            received: vec![0; size as usize / 64 + 1],
+        }
+    }
+
+    /// Returns the word and bit of the ring that the given counter maps to
+    fn bit(&self, ctr: u64) -> (usize, u64) {
+        let i = ctr % (self.received.len() as u64 * 64);
+        ((i / 64) as usize, 1 << (i % 64))
+    }
+
+    /// Records the frame with the given counter as received. Returns `false` if it was received
+    /// already or is too old, in which case nothing is recorded
+    fn accept(&mut self, ctr: u64) -> bool {
+        let ring_len = self.received.len() as u64 * 64;
+        match self.newest {
+            Some(newest) if ctr <= newest => {
+                if newest - ctr > self.size {
+                    return false;
+                }
+            }
+            // The bits of the counters up to the new newest one held counters that are now out of
+            // the window
+            Some(newest) if ctr - newest < ring_len => {
+                for passed in newest + 1..=ctr {
+                    let (word, bit) = self.bit(passed);
+                    self.received[word] &= !bit;
+                }
+                self.newest = Some(ctr);
+            }
+            _ => {
+                self.received.fill(0);
+                self.newest = Some(ctr);
+            }
+        }
+
+        let (word, bit) = self.bit(ctr);
+        let fresh = self.received[word] & bit == 0;
+        self.received[word] |= bit;
+        fresh
+    }
+}
+
+/// The keys of one sender in an epoch, and which of its frames were received
+struct Sender {
+    key: KeyAndSalt,
+    replay_window: Mutex<ReplayWindow>,
+}
+
+/// The SFrame keys of one MLS epoch. The keys of every member are derived up front, so once made,
+/// these can be shared by every stream. Only the replay windows are locked, one sender at a time,
+/// once a frame is authenticated.
+pub(crate) struct EpochKeys {
+    epoch: u64,
+    /// The KID this member sends with
+    my_kid: u64,
+    /// The counter of the next frame this member sends. This is atomic so that frames can be
+    /// encrypted through a shared reference
+    next_ctr: AtomicU64,
+    /// The keys of every member in this epoch, including this one, by KID
+    keys: BTreeMap<u64, Sender>,
+}
+
+impl EpochKeys {
+    /// The label of the secret to export from the MLS group, with an empty context
+    pub(crate) const EXPORTER_LABEL: &str = "SFrame 1.0";
+    /// The length of the secret to export from the MLS group
+    pub(crate) const EXPORTER_LEN: usize = KDF_NH;
+
+    /// Makes the keys for the given epoch from the secret exported from the MLS group in that
+    /// epoch. `my_index` is the leaf index of this member, and `member_indices` are the leaf
+    /// indices of everyone in the group in that epoch. A frame more than `replay_window` frames
+    /// older than the newest one from the same sender is rejected, like one that was already
+    /// received
+    pub(crate) fn new(
+        epoch: u64,
+        epoch_secret: &[u8],
+        my_index: u32,
+        member_indices: impl IntoIterator<Item = u32>,
+        replay_window: u32,
+    ) -> EpochKeys {
+        let keys = member_indices
+            .into_iter()
+            .chain([my_index])
+            .map(|index| {
+                let kid = Self::kid(epoch, index);
+                let sender = Sender {
+                    key: Self::derive_key(epoch_secret, index, kid),
+                    replay_window: Mutex::new(ReplayWindow::new(replay_window)),
+                };
+                (kid, sender)
+            })
+            .collect();
+
+        EpochKeys {
+            epoch,
+            my_kid: Self::kid(epoch, my_index),
+            next_ctr: AtomicU64::new(0),
+            keys,
+        }
+    }
+
+    pub(crate) fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the KID of the sender with the given leaf index in the given epoch (RFC 9605 §5.2)
+    fn kid(epoch: u64, index: u32) -> u64 {
+        (u64::from(index) << EPOCH_BITS) | (epoch % (1 << EPOCH_BITS))
+    }
+
+    /// Derives the key and salt for the given KID. The sender's base key is derived from the epoch
+    /// secret and the sender's leaf index, and is as long as an AEAD key (RFC 9605 §5.2)
+    fn derive_key(epoch_secret: &[u8], index: u32, kid: u64) -> KeyAndSalt {
+        let mut base_key = [0; AEAD_NK];
+        Hkdf::<Sha256>::from_prk(epoch_secret)
+            .expect("epoch secret is a full-size PRK")
+            .expand(&index.to_be_bytes(), &mut base_key)
+            .unwrap();
+
+        KeyAndSalt::derive(kid, &base_key)
+    }
+
+    /// Encrypts the plaintext that `write_pt` appends to the buffer it's given. `metadata` is
+    /// authenticated along with the SFrame header. Appends the header followed by the ciphertext to
+    /// `out`. The plaintext is written straight into `out` and encrypted in place there, so there
+    /// are no intermediate copies.
+    pub(crate) fn encrypt(
+        &self,
+        metadata: &[u8],
+        out: &mut Vec<u8>,
+        write_pt: impl FnOnce(&mut Vec<u8>),
+    ) {
+        let ctr = self.next_ctr.fetch_add(1, Ordering::Relaxed);
+        let my_key = &self.keys[&self.my_kid].key;
+
+        let header = encode_header(self.my_kid, ctr);
+        out.extend_from_slice(&header);
+        let ct_start = out.len();
+        write_pt(out);
+        let tag = with_aad(&header, metadata, |aad| {
+            my_key.cipher.encrypt_in_place_detached(
+                &my_key.nonce(ctr).into(),
+                aad,
+                &mut out[ct_start..],
+            )
+        })
+        .expect("couldn't encrypt frame");
+        out.extend_from_slice(&tag);
+    }
+
+    /// Decrypts an SFrame ciphertext produced by [`EpochKeys::encrypt`] in place, and appends the
+    /// plaintext to `out`. Fails if it wasn't encrypted by a member of this epoch or with the given
+    /// `metadata`, or if it's a replay, in which case nothing is appended.
+    pub(crate) fn decrypt(
+        &self,
+        metadata: &[u8],
+        ct: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<(), SFrameError> {
+        let (kid, ctr, header_len) = parse_header(ct).ok_or(SFrameError::MalformedHeader)?;
+        let (header, ct) = ct.split_at(header_len);
+        let (ct, tag) = ct
+            .split_last_chunk::<AEAD_NT>()
+            .ok_or(SFrameError::Authentication)?;
+
+        let frame_epoch = kid % (1 << EPOCH_BITS);
+        if frame_epoch != self.epoch % (1 << EPOCH_BITS) {
+            return Err(SFrameError::WrongEpoch(frame_epoch));
+        }
+        let sender = self.keys.get(&kid).ok_or(SFrameError::UnknownSender)?;
+        let key = &sender.key;
+
+        let pt_start = out.len();
+        out.extend_from_slice(ct);
+        let res = with_aad(header, metadata, |aad| {
+            key.cipher.decrypt_in_place_detached(
+                &key.nonce(ctr).into(),
+                aad,
+                &mut out[pt_start..],
+                tag.into(),
+            )
+        })
+        .map_err(|_| SFrameError::Authentication)
+        .and_then(|()| {
+            // Only authenticated frames count, or anyone could fill the window with bogus
+            // counters. The window is never left half-updated, so a poisoned lock is still
+            // fine to use
+            let mut window = sender
+                .replay_window
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner);
+            if window.accept(ctr) {
+                Ok(())
+            } else {
+                Err(SFrameError::Replayed)
+            }
+        });
+        if res.is_err() {
+            out.truncate(pt_start);
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use aes_gcm::aead::Aead;
+
+    use super::*;
+
+    impl EpochKeys {
+        fn encrypt_to_vec(&self, metadata: &[u8], pt: &[u8]) -> Vec<u8> {
+            let mut out = Vec::new();
+            self.encrypt(metadata, &mut out, |out| out.extend_from_slice(pt));
+            out
+        }
+
+        fn decrypt_to_vec(&self, metadata: &[u8], ct: &[u8]) -> Result<Vec<u8>, SFrameError> {
+            let mut out = Vec::new();
+            self.decrypt(metadata, ct, &mut out)?;
+            Ok(out)
+        }
+    }
+
+    #[test]
+    fn headers() {
+        // Small values fit in the config byte, bigger ones are minimally encoded after it
+        assert_eq!(*encode_header(0, 0), [0x00]);
+        assert_eq!(*encode_header(0, 7), [0x07]);
+        assert_eq!(*encode_header(0, 8), [0x08, 0x08]);
+        assert_eq!(*encode_header(8, 0), [0x80, 0x08]);
+        assert_eq!(*encode_header(7, 0x0100), [0x79, 0x01, 0x00]);
+        assert_eq!(*encode_header(u64::MAX, u64::MAX), [0xff; MAX_HEADER_LEN]);
+
+        for (kid, ctr) in [(0, 0), (3, 9), (0x1234, 5), (u64::MAX, 1 << 40)] {
+            let header = encode_header(kid, ctr);
+            assert_eq!(parse_header(&header), Some((kid, ctr, header.len())));
+        }
+        // Truncated KID
+        assert_eq!(parse_header(&[0x90, 0x01]), None);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let epoch_secret = vec![7; KDF_NH];
+        let alice = EpochKeys::new(3, &epoch_secret, 0, [0, 1], 2);
+        let bob = EpochKeys::new(3, &epoch_secret, 1, [0, 1], 2);
+
+        for _ in 0..3 {
+            let ct = alice.encrypt_to_vec(b"track", b"hello world");
+            assert_eq!(bob.decrypt_to_vec(b"track", &ct).unwrap(), b"hello world");
+        }
+        // Counters are per sender, so Bob's first frame doesn't reuse a nonce of Alice's
+        let ct = bob.encrypt_to_vec(b"track", b"hello world");
+        assert_eq!(parse_header(&ct).unwrap().1, 0);
+        assert_eq!(alice.decrypt_to_vec(b"track", &ct).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn rejects() {
+        let epoch_secret = vec![7; KDF_NH];
+        let alice = EpochKeys::new(3, &epoch_secret, 0, [0, 1], 2);
+        let bob = EpochKeys::new(3, &epoch_secret, 1, [0, 1], 2);
+        let ct = alice.encrypt_to_vec(b"track", b"hello world");
+
+        // Different metadata
+        assert_eq!(
+            bob.decrypt_to_vec(b"other track", &ct),
+            Err(SFrameError::Authentication)
+        );
+        // Tampered ciphertext
+        let mut tampered = ct.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            bob.decrypt_to_vec(b"track", &tampered),
+            Err(SFrameError::Authentication)
+        );
+        // Different epoch
+        let bob_next_epoch = EpochKeys::new(4, &epoch_secret, 1, [0, 1], 2);
+        assert_eq!(
+            bob_next_epoch.decrypt_to_vec(b"track", &ct),
+            Err(SFrameError::WrongEpoch(3))
+        );
+        // Someone who isn't in the epoch
+        let mallory = EpochKeys::new(3, &epoch_secret, 2, [0, 1, 2], 2);
+        assert_eq!(
+            bob.decrypt_to_vec(b"track", &mallory.encrypt_to_vec(b"track", b"hello world")),
+            Err(SFrameError::UnknownSender)
+        );
+        // Truncated header
+        assert_eq!(
+            bob.decrypt_to_vec(b"track", &[0x90]),
+            Err(SFrameError::MalformedHeader)
+        );
+
+        // Nothing is written on failure
+        let mut out = vec![1, 2, 3];
+        assert!(bob.decrypt(b"track", &tampered, &mut out).is_err());
+        assert_eq!(out, [1, 2, 3]);
+    }
+
+    #[test]
+    fn replays() {
+        let epoch_secret = vec![7; KDF_NH];
+        let alice = EpochKeys::new(3, &epoch_secret, 0, [0, 1], 2);
+        let bob = EpochKeys::new(3, &epoch_secret, 1, [0, 1], 2);
+        let cts: Vec<_> = (0..5)
+            .map(|_| alice.encrypt_to_vec(b"track", b"hello world"))
+            .collect();
+
+        // A replayed frame is rejected, and nothing is written
+        assert!(bob.decrypt_to_vec(b"track", &cts[1]).is_ok());
+        let mut out = vec![1, 2, 3];
+        assert_eq!(
+            bob.decrypt(b"track", &cts[1], &mut out),
+            Err(SFrameError::Replayed)
+        );
+        assert_eq!(out, [1, 2, 3]);
+
+        // Frames can arrive out of order within the window, but only once
+        assert!(bob.decrypt_to_vec(b"track", &cts[3]).is_ok());
+        assert!(bob.decrypt_to_vec(b"track", &cts[2]).is_ok());
+        assert_eq!(
+            bob.decrypt_to_vec(b"track", &cts[2]),
+            Err(SFrameError::Replayed)
+        );
+        // A frame older than the window can't be told apart from a replay
+        assert!(bob.decrypt_to_vec(b"track", &cts[4]).is_ok());
+        assert_eq!(
+            bob.decrypt_to_vec(b"track", &cts[0]),
+            Err(SFrameError::Replayed)
+        );
+
+        // A bogus frame doesn't use up its counter
+        let mut tampered = alice.encrypt_to_vec(b"track", b"hello world");
+        let ct = tampered.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            bob.decrypt_to_vec(b"track", &tampered),
+            Err(SFrameError::Authentication)
+        );
+        assert!(bob.decrypt_to_vec(b"track", &ct).is_ok());
+    }
+
+    // Tests that keys are derived as in RFC 9605 §4.4.2 and §5.2, spelled out step by step
+    #[test]
+    fn key_derivation() {
+        let epoch_secret = [7; KDF_NH];
+        let (index, kid) = (5, EpochKeys::kid(3, 5));
+        let key = EpochKeys::derive_key(&epoch_secret, index, kid);
+
+        let mut base_key = [0; 16];
+        Hkdf::<Sha256>::from_prk(&epoch_secret)
+            .unwrap()
+            .expand(&[0, 0, 0, 5], &mut base_key)
+            .unwrap();
+        let sframe_secret = Hkdf::<Sha256>::new(Some(&[]), &base_key);
+        let label = |name: &[u8]| [name, &kid.to_be_bytes(), &[0x00, 0x04]].concat();
+        let mut sframe_key = [0; 16];
+        let mut sframe_salt = [0; 12];
+        sframe_secret
+            .expand(&label(b"SFrame 1.0 Secret key "), &mut sframe_key)
+            .unwrap();
+        sframe_secret
+            .expand(&label(b"SFrame 1.0 Secret salt "), &mut sframe_salt)
+            .unwrap();
+
+        assert_eq!(key.salt, sframe_salt);
+        let expected_cipher = Aes128Gcm::new(&sframe_key.into());
+        let nonce = key.nonce(0).into();
+        assert_eq!(
+            key.cipher.encrypt(&nonce, &b"hello world"[..]).unwrap(),
+            expected_cipher
+                .encrypt(&nonce, &b"hello world"[..])
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new(100);
+        assert!(window.accept(5));
+        assert!(!window.accept(5));
+        // Jumping far ahead forgets everything before the window, and frames from long ago don't
+        // alias the ring's bits
+        assert!(window.accept(1000));
+        assert!(!window.accept(5));
+        assert!(window.accept(1000 - 100));
+        assert!(!window.accept(1000 - 101));
+        assert!(window.accept(1000 + 128));
+        assert!(window.accept(1000 + 128 - 64));
+        assert!(!window.accept(1000 + 128 - 64));
+    }
+}
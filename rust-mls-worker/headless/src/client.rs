@@ -0,0 +1,150 @@
+//! Drives a [`Participant`] over a room's websocket: relays the room's messages to it, sends back
+//! whatever it answers, and keeps the connection alive with heartbeats like a browser does.
+
+use std::{sync::Arc, time::Duration};
+
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use orange_mls_core::{error::WorkerError, mls_ops::SafetyNumber};
+use thiserror::Error;
+use tokio::{net::TcpStream, sync::mpsc, time};
+use tokio_tungstenite::{
+    tungstenite::{self, client::IntoClientRequest, Message},
+    MaybeTlsStream, WebSocketStream,
+};
+
+use crate::{
+    participant::{Output, Participant},
+    room::{ClientMessage, ServerMessage},
+};
+
+/// How often to tell the room we're still there. This is what `useRoom` does
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Error incurred when talking to the room. Errors in the MLS group don't end the connection, and
+/// are reported as [`Event::Error`]s instead
+#[derive(Error, Debug)]
+pub enum ClientError {
+    #[error("websocket failed: {0}")]
+    Websocket(#[from] tungstenite::Error),
+}
+
+/// Something the application might want to know about
+#[derive(Debug, PartialEq)]
+pub enum Event {
+    /// The group's safety number changed, e.g., because someone was added or removed
+    SafetyNumber(SafetyNumber),
+    /// A handshake message from the room couldn't be handled. The group is as it was before it,
+    /// unless the error came after the group changed, in which case the resulting messages were
+    /// still sent
+    Error(WorkerError),
+}
+
+/// A participant's connection to a room
+pub struct Client {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    participant: Arc<Participant>,
+    /// What joining the group left to do, which is done once the client runs
+    joined: Output,
+}
+
+impl Client {
+    /// Connects to the room at `request`, e.g., `wss://example.com/parties/rooms/my-room?_pk=bot`.
+    /// The room must know the participant by the same ID as its MLS identity, which for a
+    /// PartyKit room is the `_pk` query parameter. `joined` is what [`Participant::join`] returned
+    pub async fn connect(
+        request: impl IntoClientRequest + Unpin,
+        participant: Arc<Participant>,
+        joined: Output,
+    ) -> Result<Client, ClientError> {
+        let (ws, _) = tokio_tungstenite::connect_async(request).await?;
+        info!("{} connected to the room", participant.id());
+        Ok(Client {
+            ws,
+            participant,
+            joined,
+        })
+    }
+
+    /// Returns the participant this client drives
+    pub fn participant(&self) -> &Arc<Participant> {
+        &self.participant
+    }
+
+    /// Takes part in the room until the room closes the connection, or until `events` is closed,
+    /// at which point the participant leaves the room. Events are sent to `events` as they happen
+    pub async fn run(mut self, events: mpsc::UnboundedSender<Event>) -> Result<(), ClientError> {
+        let joined = std::mem::take(&mut self.joined);
+        self.apply(joined, &events).await?;
+
+        let mut heartbeat = time::interval(HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = self.ws.next() => match msg {
+                    Some(Ok(Message::Text(text))) => self.handle(&text, &events).await?,
+                    Some(Ok(Message::Close(_))) | None => return Ok(()),
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => return Err(e.into()),
+                },
+                _ = heartbeat.tick() => self.send(&ClientMessage::Heartbeat).await?,
+                _ = events.closed() => {
+                    info!("{} is leaving the room", self.participant.id());
+                    self.send(&ClientMessage::UserLeft).await?;
+                    self.ws.close(None).await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Handles a message from the room
+    async fn handle(
+        &mut self,
+        text: &str,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> Result<(), ClientError> {
+        let msg = match serde_json::from_str::<ServerMessage>(text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                warn!("Ignoring malformed message from the room: {e}");
+                return Ok(());
+            }
+        };
+        match self.participant.handle(&msg) {
+            Ok(output) => self.apply(output, events).await,
+            Err(e) => {
+                warn!("Failed to handle message from the room: {e}");
+                // Nobody listening is fine, the client is about to leave anyway
+                let _ = events.send(Event::Error(e));
+                Ok(())
+            }
+        }
+    }
+
+    /// Sends the given output's messages to the room, and reports its safety number and error, if
+    /// any
+    async fn apply(
+        &mut self,
+        output: Output,
+        events: &mpsc::UnboundedSender<Event>,
+    ) -> Result<(), ClientError> {
+        for msg in &output.outgoing {
+            self.send(msg).await?;
+        }
+        if let Some(sn) = output.safety_number {
+            let _ = events.send(Event::SafetyNumber(sn));
+        }
+        if let Some(e) = output.error {
+            warn!("Handled message from the room partway: {e}");
+            let _ = events.send(Event::Error(e));
+        }
+        Ok(())
+    }
+
+    /// Sends a message to the room
+    async fn send(&mut self, msg: &ClientMessage) -> Result<(), ClientError> {
+        let text = serde_json::to_string(msg).expect("client messages always serialize");
+        self.ws.send(Message::text(text)).await?;
+        Ok(())
+    }
+}
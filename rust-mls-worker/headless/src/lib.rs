@@ -0,0 +1,13 @@
+//! A headless participant in Orange Meets' end-to-end encrypted rooms, for bots and other clients
+//! that don't run in a browser. It takes part in the room's MLS group over the room's websocket,
+//! exactly like the browser's worker does through `e2ee.ts`, and protects frames with the same
+//! framing code, from `orange-mls-core`.
+//!
+//! [`participant::Participant`] is the group membership itself, without any I/O, and
+//! [`client::Client`] drives it over a websocket. [`relay`] is a local stand-in for the room, for
+//! testing without the rest of Orange Meets.
+
+pub mod client;
+pub mod participant;
+pub mod relay;
+pub mod room;
@@ -0,0 +1,181 @@
+//! The command-line headless participant. `join` takes part in a room's MLS group until it's
+//! interrupted, printing the group's safety number whenever it changes, and `relay` runs the local
+//! stand-in for the room.
+
+use std::{error::Error, net::SocketAddr, sync::Arc};
+
+use clap::{Args, Parser};
+use log::warn;
+use orange_mls_core::{
+    framing::AudioPolicy,
+    group_config::{CiphersuiteName, GroupConfig},
+    mls_ops::{DecryptFailurePolicy, MediaConfig, MediaFormat, PreGroupPolicy, SafetyNumber},
+    padding::PaddingPolicy,
+};
+use orange_mls_headless::{
+    client::{Client, Event},
+    participant::Participant,
+    relay,
+};
+use serde::{de::IntoDeserializer, Deserialize};
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_tungstenite::tungstenite::{
+    client::IntoClientRequest,
+    http::{HeaderName, HeaderValue},
+};
+
+/// A headless participant in Orange Meets' end-to-end encrypted rooms
+#[derive(Parser)]
+enum Command {
+    /// Join a room and take part in its MLS group until interrupted
+    Join(JoinArgs),
+    /// Run a local stand-in for a room, which relays handshake messages between participants
+    Relay {
+        /// The address to listen on
+        #[arg(long, default_value = "127.0.0.1:1999")]
+        listen: SocketAddr,
+    },
+}
+
+#[derive(Args)]
+struct JoinArgs {
+    /// The room's websocket URL, e.g., wss://example.com/parties/rooms/my-room
+    url: String,
+    /// The ID to join as. The room knows the participant by it, and it's the participant's MLS
+    /// identity
+    #[arg(long)]
+    id: String,
+    /// Start the room's MLS group, for when this is the first participant in the room
+    #[arg(long)]
+    create_group: bool,
+    /// An extra header for the websocket handshake, e.g., `Cookie: ...` to authenticate with the
+    /// room. Can be given more than once
+    #[arg(long = "header", value_parser = parse_header)]
+    headers: Vec<(HeaderName, HeaderValue)>,
+    /// What to leave in the clear in audio frames: keepToc or encryptAll
+    #[arg(long, value_parser = parse_name::<AudioPolicy>)]
+    audio_policy: Option<AudioPolicy>,
+    /// How the encrypted parts of frames are protected: sframe (the default) or mls
+    #[arg(long, value_parser = parse_name::<MediaFormat>)]
+    media_format: Option<MediaFormat>,
+    /// A ciphersuite to accept, by its name in RFC 9420, e.g.,
+    /// MLS_128_DHKEMX25519_AES128GCM_SHA256_Ed25519. Can be given more than once, favorite first.
+    /// By default, every supported ciphersuite but the post-quantum one is accepted
+    #[arg(long = "ciphersuite", value_parser = parse_name::<CiphersuiteName>)]
+    ciphersuites: Vec<CiphersuiteName>,
+    /// How many frames older than the newest one from the same sender still decrypt
+    #[arg(long)]
+    out_of_order_tolerance: Option<u32>,
+    /// How many frames ahead of the newest one from the same sender still decrypt
+    #[arg(long)]
+    max_message_seq_jump: Option<u32>,
+    /// How the encrypted parts of frames are padded: none or padme
+    #[arg(long, value_parser = parse_name::<PaddingPolicy>)]
+    padding: Option<PaddingPolicy>,
+    /// How many past epochs' keys to keep for frames still in flight
+    #[arg(long, default_value_t = 0)]
+    max_past_epochs: usize,
+    /// How long to keep sending frames in the previous epoch after the epoch changes
+    #[arg(long, default_value_t = 0)]
+    sender_delay_ms: u64,
+    /// What to do with frames that can't be decrypted: drop, passthrough, or conceal
+    #[arg(long, value_parser = parse_name::<DecryptFailurePolicy>)]
+    decrypt_failure_policy: Option<DecryptFailurePolicy>,
+    /// What to send before joining the group: hold, empty, or marked
+    #[arg(long, value_parser = parse_name::<PreGroupPolicy>)]
+    pre_group_policy: Option<PreGroupPolicy>,
+}
+
+impl JoinArgs {
+    /// Returns the media config these arguments ask for. Like in the browser, whatever isn't given
+    /// is the default, and everyone in the room must agree on it
+    fn media_config(&self) -> MediaConfig {
+        let default = GroupConfig::default();
+        MediaConfig {
+            audio_policy: self.audio_policy.unwrap_or_default(),
+            media_format: self.media_format.unwrap_or_default(),
+            group: GroupConfig {
+                ciphersuites: if self.ciphersuites.is_empty() {
+                    default.ciphersuites
+                } else {
+                    self.ciphersuites.clone()
+                },
+                out_of_order_tolerance: self
+                    .out_of_order_tolerance
+                    .unwrap_or(default.out_of_order_tolerance),
+                max_message_seq_jump: self
+                    .max_message_seq_jump
+                    .unwrap_or(default.max_message_seq_jump),
+                max_past_epochs: self.max_past_epochs,
+                padding: self.padding.unwrap_or(default.padding),
+            },
+            sender_delay: std::time::Duration::from_millis(self.sender_delay_ms),
+            decrypt_failure_policy: self.decrypt_failure_policy.unwrap_or_default(),
+            pre_group_policy: self.pre_group_policy.unwrap_or_default(),
+        }
+    }
+}
+
+/// Parses a policy by the name it has in the worker's events
+fn parse_name<T: for<'de> Deserialize<'de>>(name: &str) -> Result<T, serde::de::value::Error> {
+    T::deserialize(name.into_deserializer())
+}
+
+/// Parses a `Name: value` header
+fn parse_header(header: &str) -> Result<(HeaderName, HeaderValue), Box<dyn Error + Send + Sync>> {
+    let (name, value) = header.split_once(':').ok_or("expected `Name: value`")?;
+    Ok((name.trim().parse()?, value.trim().parse()?))
+}
+
+/// Formats a safety number the way the browser shows it
+fn format_safety_number(sn: &SafetyNumber) -> String {
+    sn.iter().map(|b| format!("{b:02}")).collect()
+}
+
+async fn join(args: JoinArgs) -> Result<(), Box<dyn Error>> {
+    let (participant, joined) =
+        Participant::join(&args.id, args.media_config(), args.create_group)?;
+
+    // The room knows a connection by its `_pk`
+    let separator = if args.url.contains('?') { '&' } else { '?' };
+    let mut request = format!("{}{separator}_pk={}", args.url, args.id).into_client_request()?;
+    request.headers_mut().extend(args.headers);
+
+    let client = Client::connect(request, Arc::new(participant), joined).await?;
+    let (events_tx, mut events) = mpsc::unbounded_channel();
+    let run = tokio::spawn(client.run(events_tx));
+
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(Event::SafetyNumber(sn)) => {
+                    println!("Safety number: {}", format_safety_number(&sn));
+                }
+                Some(Event::Error(e)) => warn!("{e}"),
+                // The room closed the connection
+                None => break,
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // Closing the events makes the client leave the room
+    drop(events);
+    run.await??;
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    match Command::parse() {
+        Command::Join(args) => join(args).await,
+        Command::Relay { listen } => {
+            let listener = TcpListener::bind(listen).await?;
+            println!("Relaying on ws://{}", listener.local_addr()?);
+            relay::serve(listener).await?;
+            Ok(())
+        }
+    }
+}
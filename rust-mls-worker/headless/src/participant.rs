@@ -0,0 +1,156 @@
+//! A participant in a room's MLS group, without any I/O. It takes the messages the room sends and
+//! returns the ones to send back, just like the worker does for `e2ee.ts`, and protects frames with
+//! the same framing code.
+
+use log::{info, warn};
+use orange_mls_core::{
+    diagnostics::Diagnostic,
+    error::WorkerError,
+    framing::{Codec, FrameKind},
+    mls_ops::{
+        DecryptCounts, DecryptOutcome, EncryptOutcome, MediaConfig, SafetyNumber, Session,
+        WorkerResponse,
+    },
+};
+
+use crate::room::{ClientMessage, MlsPayload, ServerMessage};
+
+/// What a participant wants done after joining or handling a message
+#[derive(Debug, Default)]
+pub struct Output {
+    /// The messages to send to the room, in order
+    pub outgoing: Vec<ClientMessage>,
+    /// The group's new safety number, if it changed
+    pub safety_number: Option<SafetyNumber>,
+    /// The error that stopped handling the message partway, after the group had already changed.
+    /// The messages above still have to be sent
+    pub error: Option<WorkerError>,
+}
+
+impl TryFrom<WorkerResponse> for Output {
+    type Error = WorkerError;
+
+    fn try_from(resp: WorkerResponse) -> Result<Output, WorkerError> {
+        Ok(Output {
+            outgoing: resp
+                .handshake_messages()?
+                .into_iter()
+                .map(|msg| MlsPayload::from(msg).to_message())
+                .collect(),
+            safety_number: resp.new_safety_number,
+            error: resp.error,
+        })
+    }
+}
+
+/// A participant in a room's MLS group. The room knows it by `id`, which is also its MLS identity
+pub struct Participant {
+    id: String,
+    session: Session,
+}
+
+impl Participant {
+    /// Generates a new identity for the given ID. If `create_group` is set, this participant is
+    /// the first in the room and starts the group. Otherwise, it shares its key package so the
+    /// group's designated committer adds it. Also returns what to do about it
+    pub fn join(
+        id: &str,
+        media_config: MediaConfig,
+        create_group: bool,
+    ) -> Result<(Participant, Output), WorkerError> {
+        let (session, resp) = if create_group {
+            Session::new_with_group(id, media_config)?
+        } else {
+            Session::new(id, media_config)?
+        };
+        let participant = Participant {
+            id: id.to_string(),
+            session,
+        };
+        Ok((participant, resp.try_into()?))
+    }
+
+    /// Returns the ID the room knows this participant by
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Handles a message from the room. Messages that aren't about encryption, and malformed
+    /// payloads, are ignored. On error, the group is left as it was before the message. An error
+    /// after the group changed is in the output instead, along with the messages to send
+    pub fn handle(&self, msg: &ServerMessage) -> Result<Output, WorkerError> {
+        let resp = match msg {
+            ServerMessage::E2eeMlsMessage { payload } => {
+                let payload = match MlsPayload::parse(payload) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!("Ignoring malformed e2eeMlsMessage: {e}");
+                        return Ok(Output::default());
+                    }
+                };
+                match payload {
+                    MlsPayload::ShareKeyPackage { key_pkgs } => self.session.add_user(&key_pkgs)?,
+                    // Like the worker, we don't really use the sender ID of a Welcome
+                    MlsPayload::SendMlsWelcome { welcome, rtree, .. } => {
+                        self.session.join_group(&welcome, &rtree)?
+                    }
+                    MlsPayload::SendMlsMessage { sender_id, msg } => {
+                        self.session.handle_commit(&msg, &sender_id)?
+                    }
+                }
+            }
+            ServerMessage::UserLeftNotification { id } => {
+                info!("{id} left the room");
+                self.session.remove_user(id)?
+            }
+            ServerMessage::Other => WorkerResponse::default(),
+        };
+        resp.try_into()
+    }
+
+    /// Encrypts a frame for the track `track_id` into `out`. `mime_type` is the frame's codec as a
+    /// browser gives it in the frame's metadata, e.g., `video/VP8`, `audio/opus`, or `audio/red`.
+    /// Video frames in codecs we don't know how to frame are framed as VP8. See
+    /// [`Session::encrypt_msg`]
+    pub fn encrypt_frame(
+        &self,
+        kind: FrameKind,
+        mime_type: &str,
+        track_id: &[u8],
+        frame: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<EncryptOutcome, WorkerError> {
+        self.session
+            .encrypt_msg(kind, Some(mime_type), Codec::Vp8, track_id, frame, out)
+    }
+
+    /// Decrypts a frame from the track `track_id` into `out`. `mime_type` is as in
+    /// [`Participant::encrypt_frame`]. See [`Session::decrypt_msg`]
+    pub fn decrypt_frame(
+        &self,
+        kind: FrameKind,
+        mime_type: &str,
+        track_id: &[u8],
+        frame: &[u8],
+        out: &mut Vec<u8>,
+    ) -> Result<DecryptOutcome, WorkerError> {
+        self.session
+            .decrypt_msg(kind, Some(mime_type), Codec::Vp8, track_id, frame, out)
+    }
+
+    /// Returns how many frames had each outcome of decryption since this participant joined
+    pub fn decrypt_stats(&self) -> DecryptCounts {
+        self.session
+            .decrypt_stats()
+            .decrypt_counts
+            .expect("decrypt stats always have counts")
+    }
+
+    /// Returns this participant's recent group events, oldest first. See [`Session::diagnostics`]
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        self.session
+            .diagnostics()
+            .diagnostics
+            .expect("diagnostics are always dumped")
+    }
+}
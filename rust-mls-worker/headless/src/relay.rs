@@ -0,0 +1,142 @@
+//! A local stand-in for the `ChatRoom` Durable Object, as far as end-to-end encryption is
+//! concerned. Like the real room, it knows each connection by its `_pk` query parameter, relays
+//! every `e2eeMlsMessage` as is to everyone else in the same order, and tells everyone when someone
+//! leaves. Unlike the real room, there's no authentication or room state, and a participant leaves
+//! as soon as its connection closes rather than once it stops sending heartbeats.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use futures_util::{SinkExt, StreamExt};
+use log::{info, warn};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+};
+use tokio_tungstenite::tungstenite::{
+    self,
+    handshake::server::{Request, Response},
+    Message,
+};
+
+use crate::room::{ClientMessage, ServerMessage};
+
+/// The connected participants, by ID, along with where to put messages for them
+type Peers = Arc<Mutex<BTreeMap<String, mpsc::UnboundedSender<Message>>>>;
+
+/// Accepts connections on `listener` and relays messages between them, forever
+pub async fn serve(listener: TcpListener) -> io::Result<()> {
+    let peers = Peers::default();
+    // For connections that don't give an ID
+    let next_id = Arc::new(AtomicU64::new(0));
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let peers = peers.clone();
+        let next_id = next_id.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle_connection(stream, peers, &next_id).await {
+                warn!("Connection from {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+/// Relays messages from the given connection until it closes or its participant leaves
+// The handshake callback's error type is tungstenite's, and it never errs anyway
+#[allow(clippy::result_large_err)]
+async fn handle_connection(
+    stream: TcpStream,
+    peers: Peers,
+    next_id: &AtomicU64,
+) -> Result<(), tungstenite::Error> {
+    // The participant is in the room as soon as the handshake is done, so nothing sent after it
+    // connects is lost. Until the writer below runs, its messages just queue up
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let own_tx = tx.clone();
+    let mut id = None;
+    let ws = tokio_tungstenite::accept_hdr_async(stream, |req: &Request, resp: Response| {
+        let given_id = req
+            .uri()
+            .query()
+            .and_then(|q| q.split('&').find_map(|param| param.strip_prefix("_pk=")));
+        let new_id = given_id
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("anonymous-{}", next_id.fetch_add(1, Ordering::Relaxed)));
+        peers.lock().unwrap().insert(new_id.clone(), tx);
+        id = Some(new_id);
+        Ok(resp)
+    })
+    .await?;
+    // The handshake only succeeds once the callback has run
+    let id = id.unwrap();
+    info!("{id} joined");
+
+    let (mut sink, mut stream) = ws.split();
+    let writer = tokio::spawn(async move {
+        while let Some(msg) = rx.recv().await {
+            if sink.send(msg).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    let res = relay_messages(&id, &mut stream, &peers).await;
+
+    // If the participant rejoined in the meantime, it's still in the room
+    let rejoined = {
+        let mut peers = peers.lock().unwrap();
+        let rejoined = !peers.get(&id).is_some_and(|tx| tx.same_channel(&own_tx));
+        if !rejoined {
+            peers.remove(&id);
+        }
+        rejoined
+    };
+    if !rejoined {
+        info!("{id} left");
+        broadcast(&peers, None, &ServerMessage::UserLeftNotification { id });
+    }
+    writer.abort();
+    res
+}
+
+/// Relays handshake messages from the participant `id` to everyone else until it leaves
+async fn relay_messages(
+    id: &str,
+    stream: &mut (impl StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin),
+    peers: &Peers,
+) -> Result<(), tungstenite::Error> {
+    while let Some(msg) = stream.next().await {
+        let Message::Text(text) = msg? else {
+            continue;
+        };
+        match serde_json::from_str::<ClientMessage>(&text) {
+            Ok(ClientMessage::E2eeMlsMessage { payload }) => {
+                broadcast(peers, Some(id), &ServerMessage::E2eeMlsMessage { payload })
+            }
+            Ok(ClientMessage::UserLeft) => break,
+            Ok(ClientMessage::Heartbeat | ClientMessage::Other) => {}
+            Err(e) => warn!("Ignoring malformed message from {id}: {e}"),
+        }
+    }
+    Ok(())
+}
+
+/// Sends the given message to everyone but `exclude`. The peers stay locked while the message is
+/// queued for everyone, so everyone gets messages in the same order
+fn broadcast(peers: &Peers, exclude: Option<&str>, msg: &ServerMessage) {
+    let text = serde_json::to_string(msg).expect("server messages always serialize");
+    let peers = peers.lock().unwrap();
+    for (id, tx) in peers.iter() {
+        if Some(id.as_str()) != exclude {
+            // A peer whose writer is gone is about to be removed anyway
+            let _ = tx.send(Message::text(text.clone()));
+        }
+    }
+}
@@ -0,0 +1,225 @@
+//! The messages passed over a room's websocket, as far as end-to-end encryption is concerned. These
+//! mirror `ServerMessage` and `ClientMessage` in `app/types/Messages.ts`. The handshake messages
+//! themselves travel as the `payload` of `e2eeMlsMessage`s, in the JSON encoding `e2ee.ts` gives
+//! the worker's events.
+
+use orange_mls_core::mls_ops::HandshakeMessage;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// A message from the room to this participant
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ServerMessage {
+    /// A handshake message another participant sent
+    E2eeMlsMessage { payload: String },
+    /// A participant left the room
+    UserLeftNotification { id: String },
+    /// Room state, chat, and everything else that isn't about encryption
+    #[serde(other)]
+    Other,
+}
+
+/// A message from this participant to the room
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum ClientMessage {
+    /// A handshake message, which the room relays to everyone else as is
+    E2eeMlsMessage { payload: String },
+    /// Tells the room this participant is still there. Participants who stop sending these are
+    /// eventually dropped from the room
+    Heartbeat,
+    /// Tells the room this participant is leaving
+    UserLeft,
+    /// Anything a browser sends that isn't about encryption
+    #[serde(other)]
+    Other,
+}
+
+/// The payload of an `e2eeMlsMessage`. This is the worker event that made the handshake message,
+/// with its byte strings encoded the way `e2ee.ts` encodes `ArrayBuffer`s. Any other fields of the
+/// event, like the sender's worker session, are ignored
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum MlsPayload {
+    #[serde(rename_all = "camelCase")]
+    ShareKeyPackage {
+        #[serde(with = "flagged_bytes_list")]
+        key_pkgs: Vec<Vec<u8>>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SendMlsWelcome {
+        sender_id: String,
+        #[serde(with = "flagged_bytes")]
+        welcome: Vec<u8>,
+        #[serde(with = "flagged_bytes")]
+        rtree: Vec<u8>,
+    },
+    #[serde(rename_all = "camelCase")]
+    SendMlsMessage {
+        sender_id: String,
+        #[serde(with = "flagged_bytes")]
+        msg: Vec<u8>,
+    },
+}
+
+impl MlsPayload {
+    /// Parses the payload of an `e2eeMlsMessage`
+    pub fn parse(payload: &str) -> serde_json::Result<MlsPayload> {
+        serde_json::from_str(payload)
+    }
+
+    /// Wraps this payload in an `e2eeMlsMessage` for the room to relay
+    pub fn to_message(&self) -> ClientMessage {
+        ClientMessage::E2eeMlsMessage {
+            payload: serde_json::to_string(self).expect("payloads always serialize"),
+        }
+    }
+}
+
+impl From<HandshakeMessage> for MlsPayload {
+    fn from(msg: HandshakeMessage) -> MlsPayload {
+        match msg {
+            HandshakeMessage::KeyPackages(key_pkgs) => MlsPayload::ShareKeyPackage { key_pkgs },
+            HandshakeMessage::Welcome {
+                sender_id,
+                welcome,
+                rtree,
+            } => MlsPayload::SendMlsWelcome {
+                sender_id,
+                welcome,
+                rtree,
+            },
+            HandshakeMessage::Commit { sender_id, msg } => {
+                MlsPayload::SendMlsMessage { sender_id, msg }
+            }
+        }
+    }
+}
+
+/// The encoding `e2ee.ts` gives byte strings when it puts worker events in JSON, i.e.,
+/// `{ "FLAG_ARRAY_BUFFER": true, "data": [1, 2, 3] }`. Typed arrays are flagged with
+/// `FLAG_TYPED_ARRAY` instead. Either is accepted, and byte strings are written as `ArrayBuffer`s,
+/// which is what the worker's events carry
+mod flagged_bytes {
+    use super::*;
+
+    #[derive(Serialize)]
+    struct FlaggedOut<'a> {
+        #[serde(rename = "FLAG_ARRAY_BUFFER")]
+        flag: bool,
+        data: &'a [u8],
+    }
+
+    // The flag only says which kind of JS object to revive the bytes as
+    #[derive(Deserialize)]
+    struct FlaggedIn {
+        data: Vec<u8>,
+    }
+
+    pub(super) fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        FlaggedOut {
+            flag: true,
+            data: bytes,
+        }
+        .serialize(s)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u8>, D::Error> {
+        FlaggedIn::deserialize(d).map(|f| f.data)
+    }
+}
+
+/// A list of byte strings, each encoded like [`flagged_bytes`] encodes them
+mod flagged_bytes_list {
+    use super::*;
+
+    struct FlaggedOut<'a>(&'a [u8]);
+
+    impl Serialize for FlaggedOut<'_> {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            flagged_bytes::serialize(self.0, s)
+        }
+    }
+
+    #[derive(Deserialize)]
+    struct FlaggedIn(#[serde(with = "super::flagged_bytes")] Vec<u8>);
+
+    pub(super) fn serialize<S: Serializer>(list: &[Vec<u8>], s: S) -> Result<S::Ok, S::Error> {
+        s.collect_seq(list.iter().map(|bytes| FlaggedOut(bytes)))
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Vec<u8>>, D::Error> {
+        let list = Vec::<FlaggedIn>::deserialize(d)?;
+        Ok(list.into_iter().map(|f| f.0).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn browser_payloads() {
+        // As `e2ee.ts` sends them, session and all
+        let payload = MlsPayload::parse(
+            r#"{"type":"sendMlsWelcome","sessionId":"main","senderId":"alice",
+                "welcome":{"FLAG_ARRAY_BUFFER":true,"data":[1,2]},
+                "rtree":{"FLAG_TYPED_ARRAY":true,"data":[3]}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            payload,
+            MlsPayload::SendMlsWelcome {
+                sender_id: "alice".to_string(),
+                welcome: vec![1, 2],
+                rtree: vec![3],
+            }
+        );
+
+        let ClientMessage::E2eeMlsMessage { payload } = payload.to_message() else {
+            panic!("expected an e2eeMlsMessage");
+        };
+        assert_eq!(
+            payload,
+            r#"{"type":"sendMlsWelcome","senderId":"alice","welcome":{"FLAG_ARRAY_BUFFER":true,"data":[1,2]},"rtree":{"FLAG_ARRAY_BUFFER":true,"data":[3]}}"#
+        );
+
+        let payload = MlsPayload::parse(
+            r#"{"type":"shareKeyPackage","sessionId":"main",
+                "keyPkgs":[{"FLAG_ARRAY_BUFFER":true,"data":[4]},{"FLAG_ARRAY_BUFFER":true,"data":[5,6]}]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            payload,
+            MlsPayload::ShareKeyPackage {
+                key_pkgs: vec![vec![4], vec![5, 6]],
+            }
+        );
+        let ClientMessage::E2eeMlsMessage { payload } = payload.to_message() else {
+            panic!("expected an e2eeMlsMessage");
+        };
+        assert_eq!(
+            payload,
+            r#"{"type":"shareKeyPackage","keyPkgs":[{"FLAG_ARRAY_BUFFER":true,"data":[4]},{"FLAG_ARRAY_BUFFER":true,"data":[5,6]}]}"#
+        );
+    }
+
+    #[test]
+    fn other_messages() {
+        let msg: ServerMessage =
+            serde_json::from_str(r#"{"type":"roomState","state":{"users":[]}}"#).unwrap();
+        assert_eq!(msg, ServerMessage::Other);
+        let msg: ServerMessage =
+            serde_json::from_str(r#"{"type":"userLeftNotification","id":"bob"}"#).unwrap();
+        assert_eq!(
+            msg,
+            ServerMessage::UserLeftNotification {
+                id: "bob".to_string()
+            }
+        );
+        assert_eq!(
+            serde_json::to_string(&ClientMessage::Heartbeat).unwrap(),
+            r#"{"type":"heartbeat"}"#
+        );
+    }
+}
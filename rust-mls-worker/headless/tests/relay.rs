@@ -0,0 +1,234 @@
+//! End-to-end tests of headless participants taking part in a room through the local relay
+
+use std::{sync::Arc, time::Duration};
+
+use orange_mls_core::{
+    diagnostics::DiagnosticEvent,
+    error::{ErrorCode, WorkerError},
+    framing::FrameKind,
+    group_config::{CiphersuiteName, GroupConfig},
+    mls_ops::{DecryptOutcome, EncryptOutcome, MediaConfig, MediaFormat, SafetyNumber},
+    padding::{Padding, PaddingPolicy},
+};
+use orange_mls_headless::{
+    client::{Client, Event},
+    participant::Participant,
+    relay,
+};
+use tokio::{net::TcpListener, sync::mpsc, task::JoinHandle, time};
+
+/// Starts a relay on a free port. Returns its URL
+async fn start_relay() -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}/parties/rooms/test", listener.local_addr().unwrap());
+    tokio::spawn(relay::serve(listener));
+    url
+}
+
+/// A participant connected to the relay
+struct Member {
+    participant: Arc<Participant>,
+    events: mpsc::UnboundedReceiver<Event>,
+    run: JoinHandle<()>,
+}
+
+impl Member {
+    async fn join(url: &str, id: &str, config: MediaConfig, create_group: bool) -> Member {
+        let (participant, joined) = Participant::join(id, config, create_group).unwrap();
+        let participant = Arc::new(participant);
+        let client = Client::connect(format!("{url}?_pk={id}"), participant.clone(), joined)
+            .await
+            .unwrap();
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let run = tokio::spawn(async move { client.run(events_tx).await.unwrap() });
+        Member {
+            participant,
+            events,
+            run,
+        }
+    }
+
+    /// Waits for the next event
+    async fn next_event(&mut self) -> Option<Event> {
+        time::timeout(Duration::from_secs(10), self.events.recv())
+            .await
+            .expect("timed out waiting for an event")
+    }
+
+    /// Waits for the next safety number. Fails on any error
+    async fn next_safety_number(&mut self) -> SafetyNumber {
+        match self.next_event().await {
+            Some(Event::SafetyNumber(sn)) => sn,
+            other => panic!("expected a safety number, got {other:?}"),
+        }
+    }
+
+    /// Leaves the room, and waits for the client to be done
+    async fn leave(self) {
+        drop(self.events);
+        self.run.await.unwrap();
+    }
+}
+
+/// Asserts that a video and an audio frame sent by `from` are received intact by `to`
+fn assert_frames_roundtrip(from: &Participant, to: &Participant) {
+    let frames: [(FrameKind, &str, &[u8]); 2] = [
+        (FrameKind::Video, "video/VP8", &[0x10; 100]),
+        (FrameKind::Audio, "audio/opus", &[0x78; 40]),
+    ];
+    for (kind, mime_type, frame) in frames {
+        let mut ct = Vec::new();
+        let outcome = from
+            .encrypt_frame(kind, mime_type, b"track", frame, &mut ct)
+            .unwrap();
+        assert!(matches!(outcome, EncryptOutcome::Encrypted(_)));
+        assert_ne!(ct, frame);
+
+        let mut pt = Vec::new();
+        let outcome = to
+            .decrypt_frame(kind, mime_type, b"track", &ct, &mut pt)
+            .unwrap();
+        assert_eq!(outcome, DecryptOutcome::Decrypted);
+        assert_eq!(pt, frame);
+    }
+}
+
+#[tokio::test]
+async fn join_and_exchange_frames() {
+    let url = start_relay().await;
+    for media_format in [MediaFormat::Mls, MediaFormat::SFrame] {
+        let config = MediaConfig {
+            media_format,
+            ..Default::default()
+        };
+        let mut alice = Member::join(&url, "alice", config.clone(), true).await;
+        let alone = alice.next_safety_number().await;
+
+        // Alice is the designated committer, so she adds Bob as soon as his key package arrives
+        let mut bob = Member::join(&url, "bob", config, false).await;
+        let together = alice.next_safety_number().await;
+        assert_ne!(together, alone);
+        assert_eq!(bob.next_safety_number().await, together);
+
+        assert_frames_roundtrip(&alice.participant, &bob.participant);
+        assert_frames_roundtrip(&bob.participant, &alice.participant);
+        assert_eq!(bob.participant.decrypt_stats().decrypted, 2);
+
+        bob.leave().await;
+        alice.leave().await;
+    }
+}
+
+#[tokio::test]
+async fn leaving_rekeys_the_group() {
+    let url = start_relay().await;
+    let config = MediaConfig::default();
+    let mut alice = Member::join(&url, "alice", config.clone(), true).await;
+    alice.next_safety_number().await;
+    let mut bob = Member::join(&url, "bob", config.clone(), false).await;
+    alice.next_safety_number().await;
+    bob.next_safety_number().await;
+    let mut carol = Member::join(&url, "carol", config, false).await;
+    let with_carol = alice.next_safety_number().await;
+    assert_eq!(bob.next_safety_number().await, with_carol);
+    assert_eq!(carol.next_safety_number().await, with_carol);
+
+    // Carol's old keys are no good once Alice removes her
+    let carol_participant = carol.participant.clone();
+    carol.leave().await;
+    let without_carol = alice.next_safety_number().await;
+    assert_ne!(without_carol, with_carol);
+    assert_eq!(bob.next_safety_number().await, without_carol);
+
+    assert_frames_roundtrip(&alice.participant, &bob.participant);
+    let mut ct = Vec::new();
+    alice
+        .participant
+        .encrypt_frame(
+            FrameKind::Video,
+            "video/VP8",
+            b"track",
+            &[0x10; 100],
+            &mut ct,
+        )
+        .unwrap();
+    let outcome = carol_participant
+        .decrypt_frame(
+            FrameKind::Video,
+            "video/VP8",
+            b"track",
+            &ct,
+            &mut Vec::new(),
+        )
+        .unwrap();
+    assert!(outcome.is_failure());
+}
+
+#[tokio::test]
+async fn mismatched_group_config_is_adopted() {
+    let url = start_relay().await;
+    let mut alice = Member::join(&url, "alice", MediaConfig::default(), true).await;
+    alice.next_safety_number().await;
+
+    // Bob pads his frames, which Alice's group doesn't. Alice adds him anyway, since she can't
+    // tell, and he joins with the group's parameters
+    let padded = MediaConfig {
+        group: GroupConfig {
+            padding: PaddingPolicy::uniform(Padding::Padme),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut bob = Member::join(&url, "bob", padded, false).await;
+    let together = alice.next_safety_number().await;
+    assert_eq!(bob.next_safety_number().await, together);
+
+    assert_frames_roundtrip(&alice.participant, &bob.participant);
+    assert_frames_roundtrip(&bob.participant, &alice.participant);
+
+    bob.leave().await;
+    alice.leave().await;
+}
+
+#[tokio::test]
+async fn incompatible_ciphersuite_is_reported() {
+    let url = start_relay().await;
+    let only = |ciphersuite| MediaConfig {
+        group: GroupConfig {
+            ciphersuites: vec![ciphersuite],
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    let mut alice = Member::join(
+        &url,
+        "alice",
+        only(CiphersuiteName::X25519Aes128GcmSha256Ed25519),
+        true,
+    )
+    .await;
+    alice.next_safety_number().await;
+
+    // Bob only accepts a ciphersuite the group isn't in, so Alice can't add him
+    let bob = Member::join(
+        &url,
+        "bob",
+        only(CiphersuiteName::P256Aes128GcmSha256P256),
+        false,
+    )
+    .await;
+    assert!(matches!(
+        alice.next_event().await,
+        Some(Event::Error(WorkerError::IncompatibleKeyPackage(_)))
+    ));
+    let last = alice.participant.diagnostics().pop().unwrap();
+    assert_eq!(
+        last.event,
+        DiagnosticEvent::Error {
+            code: ErrorCode::IncompatibleKeyPackage
+        }
+    );
+
+    bob.leave().await;
+    alice.leave().await;
+}
@@ -0,0 +1,466 @@
+use log::{info, warn};
+use orange_mls_core::{
+    error::WorkerError, framing::FrameKind, keyframes::KeyFrameTrigger, mls_ops::Session,
+};
+use protocol::{
+    InboundEvent, OutboundEvent, RecvMlsMessageEvent, RecvMlsWelcomeEvent, StreamEvent,
+    StreamOperation, UserJoinedEvent, UserLeftEvent,
+};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{
+    js_sys::{self, Array, ArrayBuffer, Object, Reflect::get as obj_get, Uint8Array},
+    DedicatedWorkerGlobalScope, ReadableStreamDefaultReader, RtcEncodedAudioFrame,
+    RtcEncodedVideoFrame, RtcRtpScriptTransformer, WritableStreamDefaultWriter,
+};
+use web_time::Instant;
+
+mod logging;
+mod protocol;
+mod sessions;
+
+/// Where getrandom 0.3 gets randomness from in the browser. `.cargo/config.toml` tells it to call
+/// this, and this calls getrandom 0.2, which asks `crypto.getRandomValues`
+#[cfg(target_arch = "wasm32")]
+#[no_mangle]
+unsafe extern "Rust" fn __getrandom_v03_custom(
+    dest: *mut u8,
+    len: usize,
+) -> Result<(), getrandom_03::Error> {
+    // Safety: getrandom 0.3 passes a buffer of `len` bytes that's ours to fill, though maybe not
+    // initialized
+    let dest = unsafe { std::slice::from_raw_parts_mut(dest.cast(), len) };
+    getrandom::getrandom_uninit(dest)
+        .map(|_| ())
+        .map_err(|_| getrandom_03::Error::UNEXPECTED)
+}
+
+/// Given an `RtcEncodedAudioFrame` or `RtcEncodedVideoFrame`, returns the kind of frame it is and
+/// the MIME type of its codec if the browser gives it in the frame's metadata. The frame's byte
+/// contents are copied into `buf`, replacing what was there. `buf` is only reallocated if it's too
+/// small.
+fn get_frame_data(
+    frame: &JsValue,
+    buf: &mut Vec<u8>,
+) -> Result<(FrameKind, Option<String>), WorkerError> {
+    let (kind, mime_type, data) = if RtcEncodedAudioFrame::instanceof(frame) {
+        let frame: &RtcEncodedAudioFrame = frame.dyn_ref().unwrap();
+        (
+            FrameKind::Audio,
+            frame.get_metadata().get_mime_type(),
+            frame.data(),
+        )
+    } else if RtcEncodedVideoFrame::instanceof(frame) {
+        let frame: &RtcEncodedVideoFrame = frame.dyn_ref().unwrap();
+        (
+            FrameKind::Video,
+            frame.get_metadata().get_mime_type(),
+            frame.data(),
+        )
+    } else {
+        return Err(unknown_frame_type());
+    };
+
+    // Copy straight from the frame's buffer into WASM memory
+    let view = Uint8Array::new(&data);
+    buf.resize(view.length() as usize, 0);
+    view.copy_to(buf);
+
+    Ok((kind, mime_type))
+}
+
+/// Given an `RtcEncodedAudioFrame` or `RtcEncodedVideoFrame` and a bytestring, sets frame's bytestring
+fn set_frame_data(frame: &JsValue, new_data: &[u8]) -> Result<(), WorkerError> {
+    // The frame needs an ArrayBuffer of its own, since it outlives any view into WASM memory. This
+    // is the only copy out of WASM memory, and the only allocation per frame outside it. The buffer
+    // can't come from a pool: once the frame is written, the packetizer or decoder reads it
+    // whenever it gets to it, and the frame's data is exactly the buffer, so it can't be reused or
+    // handed out with spare room
+    let buf = ArrayBuffer::new(new_data.len() as u32);
+    Uint8Array::new(&buf).copy_from(new_data);
+
+    if RtcEncodedAudioFrame::instanceof(frame) {
+        let frame: &RtcEncodedAudioFrame = frame.dyn_ref().unwrap();
+        frame.set_data(&buf);
+    } else if RtcEncodedVideoFrame::instanceof(frame) {
+        let frame: &RtcEncodedVideoFrame = frame.dyn_ref().unwrap();
+        frame.set_data(&buf);
+    } else {
+        return Err(unknown_frame_type());
+    }
+    Ok(())
+}
+
+/// The error for a stream chunk that's neither an `RtcEncodedAudioFrame` nor an
+/// `RtcEncodedVideoFrame`
+fn unknown_frame_type() -> WorkerError {
+    WorkerError::Stream("frame value of unknown type".to_string())
+}
+
+/// Wraps an exception thrown by a stream, for use with `map_err`
+fn stream_error(e: JsValue) -> WorkerError {
+    WorkerError::Stream(format!("{e:?}"))
+}
+
+/// Sets some logging globals. Until a session is initialized with log options, everything at the
+/// `info` level and above is logged
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+pub fn initLogging() {
+    logging::init();
+    console_error_panic_hook::set_once();
+}
+
+/// Returns the `workerReady` event, which tells the main thread which protocol versions the worker
+/// speaks. The worker script posts it once the WASM module is loaded
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+pub fn readyEvent() -> JsValue {
+    OutboundEvent::worker_ready().to_js().0
+}
+
+/// Processes an event and returns an array `[objects, buffers]`, where `objects` are the events to
+/// post to the main thread in response, and `buffers` are the lists of `ArrayBuffer`s to transfer
+/// along with each. See [`OutboundEvent`] for what the events are. If the event can't be
+/// processed, the response is a single `error` event instead, and the state is left as it was. If
+/// it fails after it changed the group, the `error` event comes after the events it did make.
+#[wasm_bindgen]
+#[allow(non_snake_case)]
+pub async fn processEvent(event: Object) -> JsValue {
+    let ty = obj_get(&event, &"type".into())
+        .ok()
+        .and_then(|ty| ty.as_string());
+    // The session is read ahead of parsing, so that even a malformed event's error can say it
+    let session_id = obj_get(&event, &"sessionId".into())
+        .ok()
+        .and_then(|id| id.as_string());
+    let ret = match ty.as_deref() {
+        Some(ty) => {
+            info!("Received event of type {ty} from main thread");
+            match InboundEvent::parse(ty, event.into()) {
+                Ok(event) => process_event(event).await,
+                Err(e) => Err(e),
+            }
+        }
+        None => Err(WorkerError::MalformedEvent(
+            "missing or non-string field `type`".to_string(),
+        )),
+    };
+
+    let events = ret.unwrap_or_else(|e| {
+        warn!("Failed to process event: {e}");
+        vec![OutboundEvent::Error {
+            code: e.code(),
+            message: e.to_string(),
+            session_id,
+            event_type: ty,
+        }]
+    });
+
+    // Make a list of objects to send to the main thread, and a list of the buffers in each object
+    // (we need these in order to properly transfer data between threads)
+    let obj_list = Array::new();
+    let buffers_list = Array::new();
+    for event in events {
+        let (o, buffers) = event.to_js();
+        obj_list.push(&o);
+        buffers_list.push(&buffers);
+    }
+
+    // Finally, return an array [objs, payloads] for the worker JS script to go through and post to
+    // the calling thread
+    let ret = Array::new();
+    ret.push(&obj_list);
+    ret.push(&buffers_list);
+    ret.dyn_into().unwrap()
+}
+
+/// Processes the given event in the session it's addressed to. Returns the events to post in
+/// response
+async fn process_event(event: InboundEvent) -> Result<Vec<OutboundEvent>, WorkerError> {
+    let session_id = event.session_id().to_string();
+    let resp = match event {
+        InboundEvent::EncryptStream(stream) => {
+            process_stream_event(StreamOperation::EncryptStream, stream).await?;
+            // No response necessary if we're just writing between two streams
+            None
+        }
+
+        InboundEvent::DecryptStream(stream) => {
+            process_stream_event(StreamOperation::DecryptStream, stream).await?;
+            None
+        }
+
+        InboundEvent::Initialize(init) => {
+            init.check_protocol_version()?;
+            if let Some(options) = &init.logging {
+                logging::configure(options);
+            }
+            Some(sessions::create(&session_id, || {
+                Session::new(&init.id, init.media_config())
+            })?)
+        }
+
+        InboundEvent::InitializeAndCreateGroup(init) => {
+            init.check_protocol_version()?;
+            if let Some(options) = &init.logging {
+                logging::configure(options);
+            }
+            Some(sessions::create(&session_id, || {
+                Session::new_with_group(&init.id, init.media_config())
+            })?)
+        }
+
+        InboundEvent::DestroySession(_) => {
+            sessions::destroy(&session_id)?;
+            None
+        }
+
+        InboundEvent::UserJoined(UserJoinedEvent { key_pkgs, .. }) => {
+            Some(sessions::get(&session_id)?.add_user(&key_pkgs)?)
+        }
+
+        InboundEvent::UserLeft(UserLeftEvent { id, .. }) => {
+            Some(sessions::get(&session_id)?.remove_user(&id)?)
+        }
+
+        // We don't really use the sender ID of a Welcome
+        InboundEvent::RecvMlsWelcome(RecvMlsWelcomeEvent { welcome, rtree, .. }) => {
+            Some(sessions::get(&session_id)?.join_group(&welcome, &rtree)?)
+        }
+
+        InboundEvent::RecvMlsMessage(RecvMlsMessageEvent { msg, sender_id, .. }) => {
+            Some(sessions::get(&session_id)?.handle_commit(&msg, &sender_id)?)
+        }
+
+        InboundEvent::GetDecryptStats(_) => Some(sessions::get(&session_id)?.decrypt_stats()),
+
+        InboundEvent::DumpDiagnostics(_) => Some(sessions::get(&session_id)?.diagnostics()),
+    };
+
+    match resp {
+        Some(resp) => OutboundEvent::from_response(&session_id, resp),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Encrypts or decrypts the frames of the given streams until the readable stream is done. Frames
+/// are only passed on while the stream's session exists
+async fn process_stream_event(
+    operation: StreamOperation,
+    stream: StreamEvent,
+) -> Result<(), WorkerError> {
+    let StreamEvent {
+        session_id,
+        input,
+        output,
+        codec,
+        track_id,
+        transformer,
+    } = stream;
+    // If the codec isn't given, assume VP8
+    let codec = codec.unwrap_or_default();
+    let track_id = track_id.unwrap_or_default().into_bytes();
+    let reader = ReadableStreamDefaultReader::new(&input).map_err(stream_error)?;
+    let writer = output.get_writer().map_err(stream_error)?;
+    let key_frames = KeyFrameRequester::new(operation, transformer, &session_id, &track_id);
+
+    // Only video streams need keyframes
+    let mut trigger = KeyFrameTrigger::default();
+    match operation {
+        StreamOperation::EncryptStream => {
+            // Tell the main thread once this stream's frames are actually encrypted, rather than
+            // held or sent in the clear under the pre-group policy
+            let mut started = false;
+            process_stream(reader, writer, |kind, mime_type, frame, out| {
+                // Without a session, there's not even a pre-group policy to follow
+                let Some(session) = sessions::find(&session_id) else {
+                    return Ok(false);
+                };
+                let outcome = session.encrypt_msg(kind, mime_type, codec, &track_id, frame, out)?;
+                let epoch = outcome.epoch();
+                // The frame is encrypted either way, so it's still sent if a post fails. The event
+                // is posted again with the next frame
+                if !started && epoch.is_some() {
+                    match post_encryption_started(&session_id, &track_id) {
+                        Ok(()) => started = true,
+                        Err(e) => warn!("Couldn't post encryptionStarted: {e}"),
+                    }
+                }
+                if kind == FrameKind::Video && trigger.encrypted(epoch, Instant::now()) {
+                    key_frames.request();
+                }
+                Ok(outcome.has_frame())
+            })
+            .await
+        }
+        StreamOperation::DecryptStream => {
+            process_stream(reader, writer, |kind, mime_type, frame, out| {
+                let Some(session) = sessions::find(&session_id) else {
+                    return Ok(false);
+                };
+                let outcome = session.decrypt_msg(kind, mime_type, codec, &track_id, frame, out)?;
+                let ok = !outcome.needs_key_frame();
+                if kind == FrameKind::Video && trigger.decrypted(ok, Instant::now()) {
+                    key_frames.request();
+                }
+                Ok(outcome.has_frame())
+            })
+            .await
+        }
+    }
+}
+
+/// Processes a posssibly infinite stream of `RtcEncodedAudio(/Video)Frame`s . Reads a frame from
+/// `reader`, applies `f` to the frame kind, codec MIME type, and data, then writes the output to
+/// `writer`. `f` writes the new frame data into the buffer it's given, and returns whether there's a
+/// frame to write at all. If `f` fails, the frame is dropped and the stream goes on, so one bad
+/// frame doesn't end the track. The codec is read from every frame, so a stream can change codecs
+/// midway, e.g., after renegotiation. Fails if reading or writing a frame fails.
+async fn process_stream<F>(
+    reader: ReadableStreamDefaultReader,
+    writer: WritableStreamDefaultWriter,
+    mut f: F,
+) -> Result<(), WorkerError>
+where
+    F: FnMut(FrameKind, Option<&str>, &[u8], &mut Vec<u8>) -> Result<bool, WorkerError>,
+{
+    // Frame data goes in and out of buffers that live as long as the stream, so once they've grown
+    // to the size of the biggest frame, processing a frame doesn't allocate in WASM memory
+    let mut frame_data = Vec::new();
+    let mut new_frame_data = Vec::new();
+
+    loop {
+        let promise = reader.read();
+
+        // Await the call. This will return an object { value, done }, where value is a view
+        // containing the new data, and done is a bool indicating that there is nothing left to read
+        let res: Object = JsFuture::from(promise)
+            .await
+            .map_err(stream_error)?
+            .dyn_into()
+            .map_err(|_| WorkerError::Stream("stream chunk must be an object".to_string()))?;
+        let done_reading = obj_get(&res, &"done".into())
+            .map_err(stream_error)?
+            .is_truthy();
+
+        // A finished stream has no frame in its last chunk
+        if done_reading {
+            return Ok(());
+        }
+
+        // Read a frame and get the underlying bytestring
+        let frame = obj_get(&res, &"value".into()).map_err(stream_error)?;
+
+        // Process the frame data
+        let (kind, mime_type) = get_frame_data(&frame, &mut frame_data)?;
+        let keep =
+            f(kind, mime_type.as_deref(), &frame_data, &mut new_frame_data).unwrap_or_else(|e| {
+                warn!("Dropping frame: {e}");
+                false
+            });
+
+        // Set the new frame data value and write the read chunk to the writable stream. A dropped
+        // frame is just never written. This promise returns nothing
+        if keep {
+            set_frame_data(&frame, &new_frame_data)?;
+            let promise = writer.write_with_chunk(&frame);
+            JsFuture::from(promise).await.map_err(stream_error)?;
+        }
+    }
+}
+
+/// Where a stream's keyframe requests go. An encrypting stream's request is for its own encoder to
+/// make a keyframe, and a decrypting stream's request is for the remote sender to make one
+enum KeyFrameRequester {
+    /// On the `RTCRtpScriptTransform` path, the transformer makes or asks for keyframes itself
+    Transformer {
+        transformer: RtcRtpScriptTransformer,
+        encrypting: bool,
+    },
+    /// On the `createEncodedStreams` path, the worker can't reach the encoder or the RTP session, so
+    /// requests are posted to the main thread as `keyFrameRequest` events
+    MainThread {
+        operation: StreamOperation,
+        session_id: String,
+        track_id: String,
+    },
+}
+
+impl KeyFrameRequester {
+    /// Makes the requester for a stream with the given operation, transformer, if any, session, and
+    /// track ID
+    fn new(
+        operation: StreamOperation,
+        transformer: Option<RtcRtpScriptTransformer>,
+        session_id: &str,
+        track_id: &[u8],
+    ) -> KeyFrameRequester {
+        match transformer {
+            Some(transformer) => KeyFrameRequester::Transformer {
+                transformer,
+                encrypting: operation == StreamOperation::EncryptStream,
+            },
+            None => KeyFrameRequester::MainThread {
+                operation,
+                session_id: session_id.to_string(),
+                track_id: String::from_utf8_lossy(track_id).into_owned(),
+            },
+        }
+    }
+
+    /// Requests a keyframe. This doesn't wait for the request to go through. A request that fails
+    /// is only logged, since the next one will be made soon enough
+    fn request(&self) {
+        match self {
+            KeyFrameRequester::Transformer {
+                transformer,
+                encrypting,
+            } => {
+                let promise = if *encrypting {
+                    transformer.generate_key_frame()
+                } else {
+                    transformer.send_key_frame_request()
+                };
+                // The request fails if, e.g., the transform isn't attached to a video track yet.
+                // There's nothing to do about that but wait for the next request
+                wasm_bindgen_futures::spawn_local(async move {
+                    if let Err(e) = JsFuture::from(promise).await {
+                        info!("Keyframe request failed: {e:?}");
+                    }
+                });
+            }
+            KeyFrameRequester::MainThread {
+                operation,
+                session_id,
+                track_id,
+            } => {
+                let res = post_to_main_thread(&OutboundEvent::KeyFrameRequest {
+                    session_id: session_id.clone(),
+                    operation: *operation,
+                    track_id: track_id.clone(),
+                });
+                if let Err(e) = res {
+                    warn!("Couldn't post keyframe request: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Posts an `encryptionStarted` event for the encrypting stream with the given session and track ID
+fn post_encryption_started(session_id: &str, track_id: &[u8]) -> Result<(), WorkerError> {
+    post_to_main_thread(&OutboundEvent::EncryptionStarted {
+        session_id: session_id.to_string(),
+        track_id: String::from_utf8_lossy(track_id).into_owned(),
+    })
+}
+
+/// Posts the given event to the main thread outside of any response to an event
+fn post_to_main_thread(event: &OutboundEvent) -> Result<(), WorkerError> {
+    let (o, buffers) = event.to_js();
+    js_sys::global()
+        .unchecked_into::<DedicatedWorkerGlobalScope>()
+        .post_message_with_transfer(&o, &buffers)
+        .map_err(stream_error)
+}
@@ -0,0 +1,135 @@
+//! The worker's logger. It writes to the console, like `console_log` does on its own, but what it
+//! writes is up to the main thread, which sets the log level, and the levels of individual modules,
+//! when it initializes a session. See [`LogOptions`].
+
+use std::sync::RwLock;
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+use crate::protocol::LogOptions;
+
+/// The levels records are logged at, by module
+#[derive(Debug, PartialEq, Eq)]
+struct LogFilter {
+    /// The level of the modules that aren't in `modules`
+    level: LevelFilter,
+    /// The levels of individual modules, and of the modules in them, by path
+    modules: Vec<(String, LevelFilter)>,
+}
+
+impl LogFilter {
+    /// The filter the worker starts out with
+    const DEFAULT: LogFilter = LogFilter {
+        level: LevelFilter::Info,
+        modules: Vec::new(),
+    };
+
+    /// Returns the filter the given options ask for. Whatever isn't given is the default
+    fn new(options: &LogOptions) -> LogFilter {
+        LogFilter {
+            level: options.level.map_or(Self::DEFAULT.level, LevelFilter::from),
+            modules: options
+                .modules
+                .iter()
+                .flatten()
+                .map(|(path, &level)| (path.clone(), level.into()))
+                .collect(),
+        }
+    }
+
+    /// Returns the level of the module with the given path, which is that of the longest path in
+    /// `modules` it's in
+    fn level_for(&self, target: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(path, _)| {
+                target
+                    .strip_prefix(path.as_str())
+                    .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+            })
+            .max_by_key(|(path, _)| path.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+
+    /// Returns the most verbose level any module is logged at
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, Ord::max)
+    }
+}
+
+/// Logs the records that pass its filter to the console
+struct WorkerLogger {
+    filter: RwLock<LogFilter>,
+}
+
+static LOGGER: WorkerLogger = WorkerLogger {
+    filter: RwLock::new(LogFilter::DEFAULT),
+};
+
+impl Log for WorkerLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // A poisoned filter is still a filter
+        let filter = self.filter.read().unwrap_or_else(|e| e.into_inner());
+        metadata.level() <= filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            console_log::log(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Installs the logger, which logs everything at the `info` level and above until it's configured
+pub fn init() {
+    log::set_logger(&LOGGER).expect("logger is only installed once");
+    log::set_max_level(LogFilter::DEFAULT.level);
+}
+
+/// Logs what the given options ask for from now on
+pub fn configure(options: &LogOptions) {
+    let filter = LogFilter::new(options);
+    log::set_max_level(filter.max_level());
+    *LOGGER.filter.write().unwrap_or_else(|e| e.into_inner()) = filter;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::LogLevel;
+
+    #[test]
+    fn module_levels() {
+        let filter = LogFilter::new(&LogOptions {
+            level: Some(LogLevel::Warn),
+            modules: Some(
+                [
+                    ("orange_mls_core".to_string(), LogLevel::Info),
+                    ("orange_mls_core::mls_ops".to_string(), LogLevel::Off),
+                    ("orange_mls_wasm".to_string(), LogLevel::Debug),
+                ]
+                .into(),
+            ),
+        });
+        assert_eq!(
+            filter.level_for("orange_mls_core::mls_ops"),
+            LevelFilter::Off
+        );
+        assert_eq!(
+            filter.level_for("orange_mls_core::keyframes"),
+            LevelFilter::Info
+        );
+        assert_eq!(filter.level_for("orange_mls_wasm"), LevelFilter::Debug);
+        // Paths only match whole modules
+        assert_eq!(filter.level_for("orange_mls_core_extra"), LevelFilter::Warn);
+        assert_eq!(filter.level_for("openmls::group"), LevelFilter::Warn);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert_eq!(LogFilter::new(&LogOptions::default()), LogFilter::DEFAULT);
+    }
+}
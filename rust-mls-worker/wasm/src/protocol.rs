@@ -0,0 +1,688 @@
+//! The events passed between the main thread and the worker. Inbound events are parsed from the
+//! objects the main thread posts, and outbound events are serialized into the objects posted back.
+//! The TypeScript definitions of both are generated into `app/types/E2eeProtocol.ts` by
+//! `cargo test`, so `e2ee.ts` can't drift from what the worker actually speaks. The types the
+//! events share with `orange-mls-core`, like the media policies, are exported along with them.
+//!
+//! The protocol is versioned. The worker says which versions it speaks in `workerReady`, and the
+//! main thread says which one it picked when it initializes a session.
+//!
+//! Every event but `workerReady` is about one session, and says which in its `sessionId`. See
+//! [`crate::sessions`].
+
+use std::collections::BTreeMap;
+
+use log::LevelFilter;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use ts_rs::TS;
+use wasm_bindgen::{JsCast, JsValue};
+use web_sys::{
+    js_sys::{Array, ArrayBuffer, Object, Uint8Array},
+    ReadableStream, RtcRtpScriptTransformer, WritableStream,
+};
+
+use orange_mls_core::{
+    diagnostics::Diagnostic,
+    error::{ErrorCode, WorkerError},
+    framing::{AudioPolicy, Codec},
+    group_config::{CiphersuiteName, GroupConfig},
+    mls_ops::{
+        DecryptCounts, DecryptFailurePolicy, HandshakeMessage, MediaConfig, MediaFormat,
+        PreGroupPolicy, WorkerResponse,
+    },
+    padding::PaddingPolicy,
+};
+
+/// The newest version of the protocol the worker speaks. This goes up whenever an event changes in a
+/// way that the other side can't ignore
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The oldest version of the protocol the worker still speaks
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+/// An event posted by the main thread
+#[derive(TS)]
+#[ts(
+    export,
+    export_to = "E2eeProtocol.ts",
+    tag = "type",
+    rename_all = "camelCase"
+)]
+pub enum InboundEvent {
+    /// Encrypt the frames of a stream. Only posted on the `createEncodedStreams` path. With
+    /// `RTCRtpScriptTransform`, the worker makes this event itself
+    EncryptStream(StreamEvent),
+    /// Decrypt the frames of a stream. See `encryptStream`
+    DecryptStream(StreamEvent),
+    /// Create a session with a new identity, and share its key package, to be added to an existing
+    /// group
+    Initialize(InitializeEvent),
+    /// Create a session with a new identity, and start a new group with it
+    InitializeAndCreateGroup(InitializeEvent),
+    /// Destroy a session. Its streams pass no more frames
+    DestroySession(SessionEvent),
+    /// Add the user with the given key package, if this user is the designated committer
+    UserJoined(UserJoinedEvent),
+    /// Remove the user with the given ID, if this user is the designated committer
+    UserLeft(UserLeftEvent),
+    /// Join a group from a peer's Welcome
+    RecvMlsWelcome(RecvMlsWelcomeEvent),
+    /// Process a peer's Commit
+    RecvMlsMessage(RecvMlsMessageEvent),
+    /// Post the session's `decryptStats` so far
+    GetDecryptStats(SessionEvent),
+    /// Post the session's recent `diagnostics`, e.g., to attach to a bug report. Since protocol
+    /// version 5
+    DumpDiagnostics(SessionEvent),
+}
+
+impl InboundEvent {
+    /// Parses an event posted by the main thread, whose `type` field is `ty`
+    pub fn parse(ty: &str, event: JsValue) -> Result<InboundEvent, WorkerError> {
+        let event = match ty {
+            "encryptStream" => InboundEvent::EncryptStream(parse_fields(event)?),
+            "decryptStream" => InboundEvent::DecryptStream(parse_fields(event)?),
+            "initialize" => InboundEvent::Initialize(parse_fields(event)?),
+            "initializeAndCreateGroup" => {
+                InboundEvent::InitializeAndCreateGroup(parse_fields(event)?)
+            }
+            "destroySession" => InboundEvent::DestroySession(parse_fields(event)?),
+            "userJoined" => InboundEvent::UserJoined(parse_fields(event)?),
+            "userLeft" => InboundEvent::UserLeft(parse_fields(event)?),
+            "recvMlsWelcome" => InboundEvent::RecvMlsWelcome(parse_fields(event)?),
+            "recvMlsMessage" => InboundEvent::RecvMlsMessage(parse_fields(event)?),
+            "getDecryptStats" => InboundEvent::GetDecryptStats(parse_fields(event)?),
+            "dumpDiagnostics" => InboundEvent::DumpDiagnostics(parse_fields(event)?),
+            _ => return Err(WorkerError::UnknownEvent(ty.to_string())),
+        };
+        Ok(event)
+    }
+}
+
+/// Parses the fields of an event. Fields that aren't part of the event, like `type`, are ignored
+fn parse_fields<T: DeserializeOwned>(event: JsValue) -> Result<T, WorkerError> {
+    serde_wasm_bindgen::from_value(event).map_err(|e| WorkerError::MalformedEvent(e.to_string()))
+}
+
+impl InboundEvent {
+    /// Returns the ID of the session this event is about
+    pub fn session_id(&self) -> &str {
+        match self {
+            InboundEvent::EncryptStream(e) | InboundEvent::DecryptStream(e) => &e.session_id,
+            InboundEvent::Initialize(e) | InboundEvent::InitializeAndCreateGroup(e) => {
+                &e.session_id
+            }
+            InboundEvent::DestroySession(e)
+            | InboundEvent::GetDecryptStats(e)
+            | InboundEvent::DumpDiagnostics(e) => &e.session_id,
+            InboundEvent::UserJoined(e) => &e.session_id,
+            InboundEvent::UserLeft(e) => &e.session_id,
+            InboundEvent::RecvMlsWelcome(e) => &e.session_id,
+            InboundEvent::RecvMlsMessage(e) => &e.session_id,
+        }
+    }
+}
+
+/// An event with nothing but the session it's about
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct SessionEvent {
+    pub session_id: String,
+}
+
+/// The streams of frames to encrypt or decrypt
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct StreamEvent {
+    /// The session whose keys protect the frames. Until the session is created, and after it's
+    /// destroyed, no frames are passed on
+    pub session_id: String,
+    #[serde(
+        rename = "in",
+        deserialize_with = "serde_wasm_bindgen::preserve::deserialize"
+    )]
+    #[ts(type = "ReadableStream")]
+    pub input: ReadableStream,
+    #[serde(
+        rename = "out",
+        deserialize_with = "serde_wasm_bindgen::preserve::deserialize"
+    )]
+    #[ts(type = "WritableStream")]
+    pub output: WritableStream,
+    /// The MIME type of the video codec, e.g., `video/H264`. This is only used for frames whose
+    /// metadata doesn't say what codec they're in. If it's not given, VP8 is assumed
+    #[serde(default, deserialize_with = "codec_from_mime_type")]
+    #[ts(optional, type = "string")]
+    pub codec: Option<Codec>,
+    /// Identifies the track, e.g., by its track name. Frames are bound to the track they're
+    /// encrypted for, so both sides of a track must use the same ID
+    #[ts(optional)]
+    pub track_id: Option<String>,
+    /// The `RTCRtpScriptTransformer` the streams belong to, if any. This is only set by the worker
+    /// itself, and lets it make and ask for keyframes
+    #[serde(default, deserialize_with = "optional_js_object")]
+    #[ts(skip)]
+    pub transformer: Option<RtcRtpScriptTransformer>,
+}
+
+/// Deserializes an optional codec from its MIME type
+fn codec_from_mime_type<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Codec>, D::Error> {
+    let Some(mime_type) = Option::<String>::deserialize(d)? else {
+        return Ok(None);
+    };
+    Codec::from_mime_type(&mime_type)
+        .map(Some)
+        .ok_or_else(|| serde::de::Error::custom(format!("unknown codec {mime_type}")))
+}
+
+/// Deserializes an optional JS object of type `T`, which is `None` if it's undefined or null
+fn optional_js_object<'de, D: Deserializer<'de>, T: JsCast>(d: D) -> Result<Option<T>, D::Error> {
+    let value: JsValue = serde_wasm_bindgen::preserve::deserialize(d)?;
+    if value.is_undefined() || value.is_null() {
+        return Ok(None);
+    }
+    value
+        .dyn_into()
+        .map(Some)
+        .map_err(|_| serde::de::Error::custom("JS object of the wrong type"))
+}
+
+/// This user's ID, the protocol version, and how media frames are encrypted. Everything but the ID
+/// and the protocol version is optional, and defaults to what the worker's media config defaults to
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct InitializeEvent {
+    /// The session to create. There must be no session with this ID yet
+    pub session_id: String,
+    pub id: String,
+    /// The protocol version the main thread speaks, which must be one of the ones the worker
+    /// announced in `workerReady`
+    pub protocol_version: u32,
+    #[ts(optional)]
+    pub audio_policy: Option<AudioPolicy>,
+    /// How the encrypted parts of frames are protected. Defaults to `sframe`, whose frames never
+    /// wait on handshake processing. Frames in the `mls` format wait on it every time
+    #[ts(optional)]
+    pub media_format: Option<MediaFormat>,
+    /// The parameters of the MLS group, which must be the same for everyone in the room
+    #[ts(optional)]
+    pub group: Option<GroupOptions>,
+    /// How long the worker keeps sending in the previous epoch after a membership change. Only used
+    /// with the `sframe` format, and only if past epochs are kept
+    #[ts(optional)]
+    pub sender_delay_ms: Option<u32>,
+    #[ts(optional)]
+    pub decrypt_failure_policy: Option<DecryptFailurePolicy>,
+    #[ts(optional)]
+    pub pre_group_policy: Option<PreGroupPolicy>,
+    /// How much the worker logs to the console. Unlike the rest of this event, this isn't specific
+    /// to the session: logging is worker-wide, and the last session initialized with this set
+    /// decides it
+    #[ts(optional)]
+    pub logging: Option<LogOptions>,
+}
+
+impl InitializeEvent {
+    /// Checks that the worker speaks the protocol version the main thread asked for
+    pub fn check_protocol_version(&self) -> Result<(), WorkerError> {
+        if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&self.protocol_version) {
+            Ok(())
+        } else {
+            Err(WorkerError::UnsupportedProtocolVersion(
+                self.protocol_version,
+            ))
+        }
+    }
+
+    /// Returns the media config this event asks for. Whatever isn't given is the default
+    pub fn media_config(&self) -> MediaConfig {
+        MediaConfig {
+            audio_policy: self.audio_policy.unwrap_or_default(),
+            media_format: self.media_format.unwrap_or_default(),
+            group: self
+                .group
+                .as_ref()
+                .map(GroupOptions::group_config)
+                .unwrap_or_default(),
+            sender_delay: std::time::Duration::from_millis(
+                self.sender_delay_ms.unwrap_or_default().into(),
+            ),
+            decrypt_failure_policy: self.decrypt_failure_policy.unwrap_or_default(),
+            pre_group_policy: self.pre_group_policy.unwrap_or_default(),
+        }
+    }
+}
+
+/// The parameters of the MLS group. Everything is optional, and defaults to what the worker's group
+/// config defaults to. The creator of the group pins them in the group, and a worker that joins a
+/// group with different parameters takes on the group's. It refuses to join a group whose
+/// ciphersuite it doesn't accept
+#[derive(Default, Deserialize, TS)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub struct GroupOptions {
+    /// The ciphersuites this user accepts, in order of preference. A group this user creates is in
+    /// the first. By default, every ciphersuite but the post-quantum one is accepted
+    #[ts(optional)]
+    pub ciphersuites: Option<Vec<CiphersuiteName>>,
+    /// How many frames older than the newest one from the same sender still decrypt. In the SFrame
+    /// format, each sender's replay window covers this many frames
+    #[ts(optional)]
+    pub out_of_order_tolerance: Option<u32>,
+    /// How many frames ahead of the newest one from the same sender still decrypt
+    #[ts(optional)]
+    pub max_message_seq_jump: Option<u32>,
+    /// How many past epochs the worker keeps keys for, so that frames in flight during a membership
+    /// change still decrypt
+    #[ts(optional)]
+    pub max_past_epochs: Option<usize>,
+    #[ts(optional)]
+    pub padding: Option<PaddingPolicy>,
+}
+
+impl GroupOptions {
+    /// Returns the group config these options ask for. Whatever isn't given is the default. The
+    /// worker validates it when it makes the session
+    pub fn group_config(&self) -> GroupConfig {
+        let default = GroupConfig::default();
+        GroupConfig {
+            ciphersuites: self.ciphersuites.clone().unwrap_or(default.ciphersuites),
+            out_of_order_tolerance: self
+                .out_of_order_tolerance
+                .unwrap_or(default.out_of_order_tolerance),
+            max_message_seq_jump: self
+                .max_message_seq_jump
+                .unwrap_or(default.max_message_seq_jump),
+            max_past_epochs: self.max_past_epochs.unwrap_or(default.max_past_epochs),
+            padding: self.padding.unwrap_or(default.padding),
+        }
+    }
+}
+
+/// How much the worker logs to the console. Everything is optional. By default, everything at the
+/// `info` level and above is logged
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, TS)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub struct LogOptions {
+    /// The level of the modules that aren't in `modules`
+    #[ts(optional)]
+    pub level: Option<LogLevel>,
+    /// The levels of individual modules, and of the modules in them, by path, e.g.,
+    /// `orange_mls_core::mls_ops`. A module's level is that of the longest path it's in
+    #[ts(optional)]
+    pub modules: Option<BTreeMap<String, LogLevel>>,
+}
+
+/// The least severe kind of log message that's logged. `off` logs nothing
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, TS)]
+#[serde(rename_all = "lowercase")]
+#[ts(export_to = "E2eeProtocol.ts")]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(level: LogLevel) -> LevelFilter {
+        match level {
+            LogLevel::Off => LevelFilter::Off,
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+/// The key packages shared by a user who wants to join, one per ciphersuite it accepts
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct UserJoinedEvent {
+    pub session_id: String,
+    #[serde(deserialize_with = "byte_strings")]
+    #[ts(type = "Array<ArrayBuffer | Uint8Array>")]
+    pub key_pkgs: Vec<Vec<u8>>,
+}
+
+/// Deserializes a list of byte strings, each of which is an `ArrayBuffer` or a typed array
+fn byte_strings<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<Vec<u8>>, D::Error> {
+    let bufs = Vec::<serde_bytes::ByteBuf>::deserialize(d)?;
+    Ok(bufs
+        .into_iter()
+        .map(serde_bytes::ByteBuf::into_vec)
+        .collect())
+}
+
+/// The ID of a user who left
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct UserLeftEvent {
+    pub session_id: String,
+    pub id: String,
+}
+
+/// A Welcome and ratchet tree relayed from the peer who added this user
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct RecvMlsWelcomeEvent {
+    pub session_id: String,
+    /// The peer who sent the Welcome. The worker doesn't use this
+    #[allow(dead_code)]
+    pub sender_id: String,
+    #[serde(with = "serde_bytes")]
+    #[ts(type = "ArrayBuffer | Uint8Array")]
+    pub welcome: Vec<u8>,
+    #[serde(with = "serde_bytes")]
+    #[ts(type = "ArrayBuffer | Uint8Array")]
+    pub rtree: Vec<u8>,
+}
+
+/// An MLS message relayed from a peer
+#[derive(Deserialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub struct RecvMlsMessageEvent {
+    pub session_id: String,
+    pub sender_id: String,
+    #[serde(with = "serde_bytes")]
+    #[ts(type = "ArrayBuffer | Uint8Array")]
+    pub msg: Vec<u8>,
+}
+
+/// Which kind of stream an event is about
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, TS)]
+#[serde(rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub enum StreamOperation {
+    EncryptStream,
+    DecryptStream,
+}
+
+/// An event posted to the main thread. Byte strings are posted as `ArrayBuffer`s of their own,
+/// which are transferred rather than copied. Every event but `workerReady` says which session it's
+/// from
+#[derive(Serialize, TS)]
+#[serde(tag = "type", rename_all = "camelCase")]
+#[ts(export, export_to = "E2eeProtocol.ts")]
+pub enum OutboundEvent {
+    /// Posted once the worker is loaded, with the range of protocol versions it speaks
+    #[serde(rename_all = "camelCase")]
+    WorkerReady {
+        protocol_version: u32,
+        min_protocol_version: u32,
+    },
+    /// This user's key packages, one per ciphersuite it accepts, to be relayed to the group's
+    /// designated committer
+    #[serde(rename_all = "camelCase")]
+    ShareKeyPackage {
+        session_id: String,
+        #[serde(serialize_with = "array_buffers")]
+        #[ts(type = "ArrayBuffer[]")]
+        key_pkgs: Vec<Vec<u8>>,
+    },
+    /// The group's safety number changed
+    #[serde(rename_all = "camelCase")]
+    NewSafetyNumber {
+        session_id: String,
+        #[serde(serialize_with = "array_buffer")]
+        #[ts(type = "ArrayBuffer")]
+        hash: Vec<u8>,
+    },
+    /// A Welcome for a user this user added, to be relayed to them
+    #[serde(rename_all = "camelCase")]
+    SendMlsWelcome {
+        session_id: String,
+        sender_id: String,
+        #[serde(serialize_with = "array_buffer")]
+        #[ts(type = "ArrayBuffer")]
+        welcome: Vec<u8>,
+        #[serde(serialize_with = "array_buffer")]
+        #[ts(type = "ArrayBuffer")]
+        rtree: Vec<u8>,
+    },
+    /// A Commit, to be relayed to the rest of the group
+    #[serde(rename_all = "camelCase")]
+    SendMlsMessage {
+        session_id: String,
+        sender_id: String,
+        #[serde(serialize_with = "array_buffer")]
+        #[ts(type = "ArrayBuffer")]
+        msg: Vec<u8>,
+    },
+    /// The response to `getDecryptStats`
+    #[serde(rename_all = "camelCase")]
+    DecryptStats {
+        session_id: String,
+        #[serde(flatten)]
+        counts: DecryptCounts,
+    },
+    /// The response to `dumpDiagnostics`: the session's recent group events, oldest first. They're
+    /// redacted, so they can be attached to bug reports as they are
+    #[serde(rename_all = "camelCase")]
+    Diagnostics {
+        session_id: String,
+        events: Vec<Diagnostic>,
+    },
+    /// Posted on the `createEncodedStreams` path, where the worker can't reach the encoder or the
+    /// RTP session itself. For an `encryptStream`, the sender should make a keyframe, e.g., after
+    /// the epoch changes. For a `decryptStream`, the remote sender should be asked for one, e.g.,
+    /// after frames failed to decrypt. Requests are already rate-limited by the worker
+    #[serde(rename_all = "camelCase")]
+    KeyFrameRequest {
+        session_id: String,
+        operation: StreamOperation,
+        track_id: String,
+    },
+    /// Frames on an `encryptStream` started being encrypted, i.e., this user is in a group. Until
+    /// then, frames are handled according to the pre-group policy
+    #[serde(rename_all = "camelCase")]
+    EncryptionStarted {
+        session_id: String,
+        track_id: String,
+    },
+    /// The response to an event the worker couldn't process. The session's state is left as it was
+    /// before the event, unless the error comes after the Commits the event did make, which are
+    /// posted first
+    #[serde(rename_all = "camelCase")]
+    Error {
+        code: ErrorCode,
+        message: String,
+        /// The session the event was addressed to, if it said
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[ts(optional)]
+        session_id: Option<String>,
+        /// The `type` of the event, if it had one
+        #[serde(skip_serializing_if = "Option::is_none")]
+        #[ts(optional)]
+        event_type: Option<String>,
+    },
+}
+
+impl OutboundEvent {
+    /// Returns the `workerReady` event
+    pub fn worker_ready() -> OutboundEvent {
+        OutboundEvent::WorkerReady {
+            protocol_version: PROTOCOL_VERSION,
+            min_protocol_version: MIN_PROTOCOL_VERSION,
+        }
+    }
+
+    /// Returns the events that carry the given response from the given session, in the order they
+    /// must be posted in: safety number, key packages, (Welcome, Add), (Welcome, Add), ..., Remove,
+    /// decrypt stats, diagnostics, and the error that stopped the operation partway, if any. Fails if
+    /// the handshake messages can't be serialized
+    pub(crate) fn from_response(
+        session_id: &str,
+        resp: WorkerResponse,
+    ) -> Result<Vec<OutboundEvent>, WorkerError> {
+        let session_id = || session_id.to_string();
+
+        let mut events = Vec::new();
+        if let Some(sn) = resp.new_safety_number {
+            events.push(OutboundEvent::NewSafetyNumber {
+                session_id: session_id(),
+                hash: sn.to_vec(),
+            });
+        }
+        events.extend(resp.handshake_messages()?.into_iter().map(|msg| match msg {
+            HandshakeMessage::KeyPackages(key_pkgs) => OutboundEvent::ShareKeyPackage {
+                session_id: session_id(),
+                key_pkgs,
+            },
+            HandshakeMessage::Welcome {
+                sender_id,
+                welcome,
+                rtree,
+            } => OutboundEvent::SendMlsWelcome {
+                session_id: session_id(),
+                sender_id,
+                welcome,
+                rtree,
+            },
+            HandshakeMessage::Commit { sender_id, msg } => OutboundEvent::SendMlsMessage {
+                session_id: session_id(),
+                sender_id,
+                msg,
+            },
+        }));
+        if let Some(counts) = resp.decrypt_counts {
+            events.push(OutboundEvent::DecryptStats {
+                session_id: session_id(),
+                counts,
+            });
+        }
+        if let Some(diagnostics) = resp.diagnostics {
+            events.push(OutboundEvent::Diagnostics {
+                session_id: session_id(),
+                events: diagnostics,
+            });
+        }
+        if let Some(e) = resp.error {
+            events.push(OutboundEvent::Error {
+                code: e.code(),
+                message: e.to_string(),
+                session_id: Some(session_id()),
+                event_type: None,
+            });
+        }
+        Ok(events)
+    }
+
+    /// Serializes this event into the object to post. Also returns the `ArrayBuffer`s in the
+    /// object and its lists, which are to be transferred along with it
+    pub fn to_js(&self) -> (JsValue, Array) {
+        // Flattened fields are serialized as maps, which must be objects too
+        let serializer = serde_wasm_bindgen::Serializer::new().serialize_maps_as_objects(true);
+        let o = self
+            .serialize(&serializer)
+            .expect("outbound events always serialize");
+        let buffers = Object::values(o.unchecked_ref())
+            .iter()
+            .flat_map(|v| match v.dyn_into::<Array>() {
+                Ok(list) => list.iter().collect(),
+                Err(v) => vec![v],
+            })
+            .filter(|v| v.is_instance_of::<ArrayBuffer>())
+            .collect();
+        (o, buffers)
+    }
+}
+
+/// Serializes a byte string as an `ArrayBuffer` of its own. It outlives any view into WASM memory,
+/// and can be transferred to the main thread
+fn array_buffer<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+    let buf = ArrayBuffer::new(bytes.len() as u32);
+    Uint8Array::new(&buf).copy_from(bytes);
+    serde_wasm_bindgen::preserve::serialize(&buf, s)
+}
+
+/// Serializes a list of byte strings as a list of `ArrayBuffer`s, like [`array_buffer`] does
+fn array_buffers<S: Serializer>(list: &[Vec<u8>], s: S) -> Result<S::Ok, S::Error> {
+    struct Buffer<'a>(&'a [u8]);
+
+    impl Serialize for Buffer<'_> {
+        fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            array_buffer(self.0, s)
+        }
+    }
+
+    s.collect_seq(list.iter().map(|bytes| Buffer(bytes)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn initialize_event(protocol_version: u32) -> InitializeEvent {
+        InitializeEvent {
+            session_id: "room".to_string(),
+            id: "alice".to_string(),
+            protocol_version,
+            audio_policy: None,
+            media_format: Some(MediaFormat::SFrame),
+            group: Some(GroupOptions {
+                max_past_epochs: Some(3),
+                ..Default::default()
+            }),
+            sender_delay_ms: Some(250),
+            decrypt_failure_policy: None,
+            pre_group_policy: None,
+            logging: None,
+        }
+    }
+
+    #[test]
+    fn protocol_versions() {
+        assert_eq!(
+            initialize_event(PROTOCOL_VERSION).check_protocol_version(),
+            Ok(())
+        );
+        assert_eq!(
+            initialize_event(MIN_PROTOCOL_VERSION - 1).check_protocol_version(),
+            Err(WorkerError::UnsupportedProtocolVersion(
+                MIN_PROTOCOL_VERSION - 1
+            ))
+        );
+        assert_eq!(
+            initialize_event(PROTOCOL_VERSION + 1).check_protocol_version(),
+            Err(WorkerError::UnsupportedProtocolVersion(
+                PROTOCOL_VERSION + 1
+            ))
+        );
+    }
+
+    #[test]
+    fn media_config_defaults() {
+        let config = initialize_event(PROTOCOL_VERSION).media_config();
+        assert_eq!(config.media_format, MediaFormat::SFrame);
+        assert_eq!(config.group.max_past_epochs, 3);
+        assert_eq!(config.group.out_of_order_tolerance, 500);
+        assert_eq!(config.sender_delay, std::time::Duration::from_millis(250));
+        assert_eq!(config.audio_policy, AudioPolicy::default());
+        assert_eq!(config.group.padding, PaddingPolicy::default());
+        assert_eq!(config.pre_group_policy, PreGroupPolicy::default());
+    }
+
+    /// Core types are exported along with the events that use them, except for the ones the
+    /// export can't see: `Padding` is only named in `PaddingPolicy`'s type override, and
+    /// `DecryptCounts` and `DiagnosticEvent` are flattened into `DecryptStats` and `Diagnostic`
+    #[test]
+    fn export_bindings_core() {
+        let cfg = ts_rs::Config::from_env();
+        orange_mls_core::padding::Padding::export_all(&cfg).unwrap();
+        DecryptCounts::export_all(&cfg).unwrap();
+        orange_mls_core::diagnostics::DiagnosticEvent::export_all(&cfg).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! The MLS sessions of this worker, by session ID. Every event about a group is addressed to a
+//! session, so one worker can serve several rooms, or a room and a side group, at once. Streams are
+//! addressed to a session too, but look it up for every frame, so they can be set up before the
+//! session is created and outlive it.
+
+use std::{cell::RefCell, collections::BTreeMap, sync::Arc};
+
+use orange_mls_core::{
+    error::WorkerError,
+    mls_ops::{Session, WorkerResponse},
+};
+
+thread_local! {
+    static SESSIONS: RefCell<BTreeMap<String, Arc<Session>>> = RefCell::default();
+}
+
+/// Runs `f` on the sessions
+fn with_sessions<T>(
+    f: impl FnOnce(&mut BTreeMap<String, Arc<Session>>) -> Result<T, WorkerError>,
+) -> Result<T, WorkerError> {
+    SESSIONS
+        .try_with(|sessions| f(&mut sessions.borrow_mut()))
+        .map_err(|_| WorkerError::StateUnavailable)?
+}
+
+/// Adds the session made by `make` under the given ID, and returns `make`'s response. Fails without
+/// calling `make` if there's already a session with this ID, rather than replacing it
+pub fn create(
+    id: &str,
+    make: impl FnOnce() -> Result<(Session, WorkerResponse), WorkerError>,
+) -> Result<WorkerResponse, WorkerError> {
+    with_sessions(|sessions| {
+        if sessions.contains_key(id) {
+            return Err(WorkerError::SessionExists(id.to_string()));
+        }
+        let (session, resp) = make()?;
+        sessions.insert(id.to_string(), Arc::new(session));
+        Ok(resp)
+    })
+}
+
+/// Returns the session with the given ID
+pub fn get(id: &str) -> Result<Arc<Session>, WorkerError> {
+    find(id).ok_or_else(|| WorkerError::UnknownSession(id.to_string()))
+}
+
+/// Returns the session with the given ID, or `None` if there's no such session (yet, or anymore)
+pub fn find(id: &str) -> Option<Arc<Session>> {
+    SESSIONS
+        .try_with(|sessions| sessions.borrow().get(id).cloned())
+        .ok()
+        .flatten()
+}
+
+/// Removes the session with the given ID. Its streams pass no more frames until a session with the
+/// same ID is created
+pub fn destroy(id: &str) -> Result<(), WorkerError> {
+    with_sessions(|sessions| {
+        sessions
+            .remove(id)
+            .map(|_| ())
+            .ok_or_else(|| WorkerError::UnknownSession(id.to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use orange_mls_core::mls_ops::MediaConfig;
+
+    #[test]
+    fn lifecycle() {
+        let make = || Session::new_with_group("Alice", MediaConfig::default());
+        assert!(create("room", make).unwrap().new_safety_number.is_some());
+        assert!(find("room").is_some());
+
+        // Creating the session again doesn't wipe it
+        let room = get("room").unwrap();
+        assert_eq!(
+            create("room", || panic!("made a session twice")).err(),
+            Some(WorkerError::SessionExists("room".to_string()))
+        );
+        assert!(Arc::ptr_eq(&room, &get("room").unwrap()));
+
+        // Sessions are independent
+        create("side", make).unwrap();
+        destroy("room").unwrap();
+        assert!(find("room").is_none());
+        assert!(find("side").is_some());
+        assert_eq!(
+            get("room").err(),
+            Some(WorkerError::UnknownSession("room".to_string()))
+        );
+        assert_eq!(
+            destroy("room"),
+            Err(WorkerError::UnknownSession("room".to_string()))
+        );
+    }
+}